@@ -10,6 +10,14 @@ use clap::Parser;
 pub struct Cli {
     #[clap(subcommand)]
     pub commands: Commands,
+
+    /// 只打印将要执行的写操作和索引变更，不修改目标数据库
+    #[clap(long, global = true)]
+    pub dry_run: bool,
+
+    /// 确认目标是 protected 集群，跳过写操作和开启 profile 2 前的确认
+    #[clap(long = "i-know-this-is-prod", global = true)]
+    pub i_know_this_is_prod: bool,
 }
 
 #[derive(Parser)]
@@ -32,11 +40,14 @@ pub enum Commands {
     /// 压测完成后恢复命令
     OPResume(OPReplay),
 
+    /// 校验恢复后的数据是否和压测前的快照一致
+    OPVerify(OPVerify),
+
     /// 构建压测恢复的 oplogs
     OPBuildResume(OPReplay),
 
     /// 导出相关的数据到 op 文件内
-    OPExport(OPReplay),
+    OPExport(OPExport),
 
     /// 将上门的导出的 op 文件导出到数据指定的数据库
     OPImport(OPImport),
@@ -53,7 +64,7 @@ pub enum Commands {
     /// diff 迁移索引，全程 background
     IndexMigrate(IndexMigrate),
 
-    /// 一些高效的辅助命令，包括文件的的行正则筛选、分析阿里云的审计日志、转换阿里云的审计日志为压测 oplogs.op等
+    /// 一些高效的辅助命令，包括文件的的行正则筛选、分析云厂商的审计日志、转换云厂商的审计日志为压测 oplogs.op等
     #[clap(subcommand)]
     Tool(Tool),
 
@@ -63,14 +74,50 @@ pub enum Commands {
 
 #[derive(clap::Parser, Debug, Clone)]
 pub enum Tool {
-    /// 分析阿里云的审计日志
+    /// 分析云厂商的审计日志
     Ana(Analyzer),
 
-    /// 转换阿里云的审计日志为压测 oplogs.op
+    /// 转换云厂商的审计日志为压测 oplogs.op
     Cov(Convert),
 
     /// 通过正则过滤文件的行
     Filter(Filter),
+
+    /// 将 .op 转换为 bson 格式的 .opb，加载时不需要从 json 转换
+    Pack(Pack),
+
+    /// 将 bson 格式的 .opb 转换为 .op
+    Unpack(Pack),
+
+    /// 构建 op 文件的 .idx 索引，用于快速计数和随机访问
+    Index(Index),
+
+    /// 检查 op 文件中的每一条记录是否可以执行
+    Lint(Lint),
+
+    /// 按比例或者固定条数抽样
+    Sample(Sample),
+
+    /// 按时间窗口、集合或者操作类型拆分为多个文件
+    Split(Split),
+
+    /// 按照 ts 合并多个文件
+    Merge(Merge),
+
+    /// 平移记录的 ts
+    ShiftTime(ShiftTime),
+
+    /// 按照 id 或者 key 去重
+    Dedup(Dedup),
+
+    /// 取开头一段时间内的记录
+    Head(HeadTail),
+
+    /// 取结尾一段时间内的记录
+    Tail(HeadTail),
+
+    /// 按照查询形状分组，把录制的值替换为按出现频率生成的 `$gen` 占位符
+    Templatize(Templatize),
 }
 
 #[derive(clap::Parser, Debug, Clone)]
@@ -114,11 +161,21 @@ pub struct OPPull {
     #[clap(short, long)]
     pub time_range: String,
 
+    /// 拉取的来源，profile: db.system.profile，oplog: local.oplog.rs（只有写操作）
+    #[clap(short, long, value_enum, default_value_t = PullSource::Profile)]
+    pub source: PullSource,
+
     /// force to clean
     #[clap(short, long)]
     pub force: bool,
 }
 
+#[derive(clap::ValueEnum, Debug, Clone, PartialEq)]
+pub enum PullSource {
+    Profile,
+    Oplog,
+}
+
 #[derive(clap::Parser, Debug, Clone)]
 pub struct OPStress {
     /// eg: qxg
@@ -128,7 +185,7 @@ pub struct OPStress {
     #[clap(long)]
     pub update: Option<bool>,
 
-    /// regex filter oplog
+    /// 过滤表达式，eg: `op in (Find, Aggregate) and coll = "orders"`，`re:` 开头时按正则匹配原始的行（不带 `re:` 的老正则写法已废弃）
     #[clap(short, long)]
     pub filter: Option<String>,
 
@@ -151,6 +208,18 @@ pub struct OPStress {
     /// 插入的时候去除某个字段
     #[clap(short, long)]
     pub ignore_field: Vec<String>,
+
+    /// 命名空间映射，回放到其他库时使用，可以多次指定，eg: `prod_app.*=perf_app.*`、`prod_app.orders=perf_app.orders_v2`
+    #[clap(long)]
+    pub map_ns: Vec<String>,
+
+    /// 库映射，等同于 `--map-ns 'a.*=b.*'`，eg: `prod_app=perf_app`
+    #[clap(long)]
+    pub map_db: Vec<String>,
+
+    /// 随机数种子，指定后 `$gen` 生成的值、行分配到的线程和退避时间都由种子决定，可以复现同一次执行，默认随机（记录在报告中）
+    #[clap(long)]
+    pub seed: Option<u64>,
 }
 
 #[derive(clap::Parser, Debug, Clone)]
@@ -173,6 +242,82 @@ pub struct OPReplay {
     /// 线程数量
     #[clap(short, long)]
     pub thread_count: Option<usize>,
+
+    /// 命名空间映射，回放到其他库时使用，可以多次指定，eg: `prod_app.*=perf_app.*`、`prod_app.orders=perf_app.orders_v2`
+    #[clap(long)]
+    pub map_ns: Vec<String>,
+
+    /// 库映射，等同于 `--map-ns 'a.*=b.*'`，eg: `prod_app=perf_app`
+    #[clap(long)]
+    pub map_db: Vec<String>,
+
+    /// 随机数种子，指定后 `$gen` 生成的值、行分配到的线程和退避时间都由种子决定，可以复现同一次执行，默认随机（记录在报告中）
+    #[clap(long)]
+    pub seed: Option<u64>,
+
+    /// 从上次中断时保存的断点继续执行，断点每 10 秒保存一次
+    #[clap(long = "continue")]
+    pub continue_run: bool,
+
+    /// 按照因果关系分配行：同一个文档（ns + _id 或者分片键）的写操作在同一个线程中按照录制的顺序执行，无法确定文档的写操作（批量修改）等待之前的行执行完
+    #[clap(long)]
+    pub causal: bool,
+
+    /// --causal 时没有 _id 的条件使用的分片键，可以多次指定，eg: `app.orders=uid` 、`app.logs=uid,day`
+    #[clap(long)]
+    pub shard_key: Vec<String>,
+}
+
+#[derive(clap::Parser, Debug, Clone)]
+pub struct OPVerify {
+    /// eg: qxg
+    pub target: String,
+
+    /// 是否强制更新
+    #[clap(long)]
+    pub update: Option<bool>,
+
+    ///覆盖配置的 uri
+    #[clap(short, long)]
+    pub uri: Option<String>,
+}
+
+#[derive(clap::Parser, Debug, Clone)]
+pub struct OPExport {
+    /// eg: qxg
+    pub target: String,
+
+    /// 是否强制更新
+    #[clap(long)]
+    pub update: Option<bool>,
+
+    ///覆盖配置的 uri
+    #[clap(short, long)]
+    pub uri: Option<String>,
+
+    /// 并发查询的行数，默认使用配置的 thread_count
+    #[clap(short, long)]
+    pub thread_count: Option<usize>,
+
+    /// 忽略上次中断的进度，重新导出
+    #[clap(short, long)]
+    pub rebuild: Option<bool>,
+
+    /// 导出格式
+    #[clap(short, long, value_enum, default_value = "op")]
+    pub format: ExportFormat,
+
+    /// bson-dump 的输出目录，默认 `./mongobar/<target>/dump`
+    #[clap(short, long)]
+    pub out: Option<String>,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    /// 导出为 data.op，每个文档一条 Insert
+    Op,
+    /// 导出为 mongodump 格式的目录 `<db>/<coll>.bson` + `<coll>.metadata.json`，可以用 mongorestore 恢复
+    BsonDump,
 }
 
 #[derive(clap::Parser, Debug, Clone)]
@@ -186,6 +331,18 @@ pub struct OPImport {
     /// 强制重新构建恢复恢复 oplogs
     #[clap(short, long)]
     pub rebuild: Option<bool>,
+
+    /// mongodump 格式的目录（包括 mongodump --gzip），默认 data.op 不存在时使用 `./mongobar/<target>/dump`
+    #[clap(short, long)]
+    pub dump: Option<String>,
+
+    /// 导入 dump 时的并发批次数量
+    #[clap(short, long, default_value_t = 8)]
+    pub thread_count: usize,
+
+    /// 导入 dump 时每个批次的文档数量
+    #[clap(short, long, default_value_t = 1000)]
+    pub batch_size: usize,
 }
 
 #[derive(clap::Parser, Debug, Clone)]
@@ -199,6 +356,20 @@ pub struct SaveAs {
     /// 如果文件存在强制保存
     #[clap(short, long)]
     pub force: bool,
+
+    /// 压缩保存，默认保持和原文件相同的格式
+    #[clap(short, long)]
+    pub compress: Option<Compress>,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum Compress {
+    /// 不压缩，保存为 .op
+    None,
+    /// 保存为 .op.zst
+    Zstd,
+    /// 保存为 .op.gz
+    Gzip,
 }
 
 #[derive(clap::Parser, Debug, Clone)]
@@ -206,7 +377,7 @@ pub struct UI {
     /// eg: qxg
     pub target: String,
 
-    /// regex filter oplog
+    /// 过滤表达式，eg: `op in (Find, Aggregate) and coll = "orders"`，`re:` 开头时按正则匹配原始的行（不带 `re:` 的老正则写法已废弃）
     #[clap(long)]
     pub filter: Option<String>,
 
@@ -239,10 +410,29 @@ pub struct UI {
     pub ignore_field: Vec<String>,
 }
 
+/// 审计日志的格式，不指定时通过文件首行自动识别
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum AuditFormat {
+    /// 阿里云审计日志 csv
+    Aliyun,
+    /// MongoDB Atlas 审计日志/数据库访问日志 json
+    Atlas,
+    /// AWS DocumentDB profiler/审计日志导出 json
+    Documentdb,
+    /// 腾讯云审计日志 csv
+    Tencent,
+    /// 华为云审计日志 csv
+    Huawei,
+}
+
 #[derive(clap::Parser, Debug, Clone)]
 pub struct Analyzer {
     pub target: String,
 
+    /// 审计日志格式，默认自动识别
+    #[clap(long, value_enum)]
+    pub format: Option<AuditFormat>,
+
     /// regex filter oplog
     #[clap(short, long)]
     pub filter: Option<String>,
@@ -264,6 +454,10 @@ pub struct Analyzer {
 pub struct Convert {
     pub target: String,
 
+    /// 审计日志格式，默认自动识别
+    #[clap(long, value_enum)]
+    pub format: Option<AuditFormat>,
+
     /// regex filter oplog
     #[clap(short, long)]
     pub filter: Option<String>,
@@ -277,13 +471,165 @@ pub struct Convert {
     pub rebuild: Option<bool>,
 }
 
+#[derive(clap::Parser, Debug, Clone)]
+pub struct Pack {
+    /// 目标文件，eg: qxg.op/qxg.op.zst
+    pub target: String,
+
+    /// 输出文件，默认为替换后缀后的同名文件，eg: qxg.opb/qxg.opb.zst
+    #[clap(short, long)]
+    pub out: Option<String>,
+}
+
+#[derive(clap::Parser, Debug, Clone)]
+pub struct Index {
+    /// 目标文件，eg: qxg.op/qxg.opb.zst
+    pub target: String,
+}
+
+#[derive(clap::Parser, Debug, Clone)]
+pub struct Lint {
+    /// 目标文件，eg: qxg.op/qxg.opb.zst
+    pub target: String,
+
+    /// 修复能修复的记录，丢弃其他的错误记录
+    #[clap(long)]
+    pub fix: bool,
+}
+
+#[derive(clap::Parser, Debug, Clone)]
+pub struct Sample {
+    /// 目标文件，eg: qxg.op/qxg.opb.zst
+    pub target: String,
+
+    /// 输出文件，默认为同目录下的 `{name}.sample{suffix}`
+    #[clap(short, long)]
+    pub out: Option<String>,
+
+    /// 抽样比例，eg: 0.1
+    #[clap(long, conflicts_with = "n")]
+    pub ratio: Option<f64>,
+
+    /// 抽样条数
+    #[clap(short, long)]
+    pub n: Option<usize>,
+
+    /// 随机数种子，相同的种子抽样结果相同
+    #[clap(long, default_value_t = 0)]
+    pub seed: u64,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+pub enum SplitBy {
+    Time,
+    Coll,
+    Op,
+}
+
+#[derive(clap::Parser, Debug, Clone)]
+pub struct Split {
+    /// 目标文件，eg: qxg.op/qxg.opb.zst
+    pub target: String,
+
+    /// 输出目录，默认为目标文件所在目录
+    #[clap(short, long)]
+    pub out: Option<String>,
+
+    #[clap(long, value_enum, default_value_t = SplitBy::Time)]
+    pub by: SplitBy,
+
+    /// 按时间拆分时的窗口，eg: 30m/1h/1d
+    #[clap(long)]
+    pub window: Option<String>,
+}
+
+#[derive(clap::Parser, Debug, Clone)]
+pub struct Merge {
+    /// 需要合并的文件，每个文件内部需要按 ts 排序
+    #[clap(required = true, num_args = 1..)]
+    pub targets: Vec<String>,
+
+    /// 输出文件，eg: merged.op
+    #[clap(short, long)]
+    pub out: String,
+}
+
+#[derive(clap::Parser, Debug, Clone)]
+pub struct ShiftTime {
+    /// 目标文件，eg: qxg.op/qxg.opb.zst
+    pub target: String,
+
+    /// 输出文件，默认为同目录下的 `{name}.shift{suffix}`
+    #[clap(short, long)]
+    pub out: Option<String>,
+
+    /// 第一条记录的新时间，eg: 2024-07-08T00:00:00Z
+    #[clap(long, conflicts_with = "offset")]
+    pub to: Option<String>,
+
+    /// 偏移的毫秒数，可以为负数
+    #[clap(long, allow_hyphen_values = true)]
+    pub offset: Option<i64>,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+pub enum DedupBy {
+    Id,
+    Key,
+}
+
+#[derive(clap::Parser, Debug, Clone)]
+pub struct Dedup {
+    /// 目标文件，eg: qxg.op/qxg.opb.zst
+    pub target: String,
+
+    /// 输出文件，默认为同目录下的 `{name}.dedup{suffix}`
+    #[clap(short, long)]
+    pub out: Option<String>,
+
+    #[clap(long, value_enum, default_value_t = DedupBy::Id)]
+    pub by: DedupBy,
+}
+
+#[derive(clap::Parser, Debug, Clone)]
+pub struct HeadTail {
+    /// 目标文件，eg: qxg.op/qxg.opb.zst
+    pub target: String,
+
+    /// 输出文件，默认为同目录下的 `{name}.head{suffix}`/`{name}.tail{suffix}`
+    #[clap(short, long)]
+    pub out: Option<String>,
+
+    /// 时间长度，eg: 500ms/30s/10m/1h/1d
+    #[clap(short, long)]
+    pub duration: String,
+}
+
+#[derive(clap::Parser, Debug, Clone)]
+pub struct Templatize {
+    /// 目标文件，eg: qxg.op/qxg.opb.zst
+    pub target: String,
+
+    /// 输出文件，默认为同目录下的 `{name}.tpl{suffix}`
+    #[clap(short, long)]
+    pub out: Option<String>,
+
+    /// 每个字段最多记录的不同值的数量，超过后整数按照最小/最大值的范围生成
+    #[clap(long, default_value_t = 1000)]
+    pub max_values: usize,
+
+    /// 按照录制时的占比重复写入模板，总数约为 rows，默认每个模板写入一次
+    #[clap(long, default_value_t = 0)]
+    pub rows: usize,
+}
+
 #[derive(clap::Parser, Debug, Clone)]
 
 pub struct Filter {
     /// 目标文件或者是 oplogs 的目录名称
     pub target: String,
 
-    /// 匹配字符串，默认是通过正则匹配，如果传入 -m 则是通过模式匹配
+    /// 过滤表达式，eg: `op = Find and cmd.filter.status exists`，`re:` 开头时按正则匹配（不带 `re:` 的老正则写法已废弃），如果传入 -m 则是通过模式匹配
     #[clap(long)]
     pub filter: String,
 
@@ -82,6 +82,13 @@ impl Metric {
         self.logs.lock().unwrap().push(log);
     }
 
+    /// 把缓冲中的日志写入 print_file
+    pub fn flush(&self) {
+        if let Some(print_file) = self.print_file.lock().unwrap().as_mut() {
+            let _ = print_file.flush();
+        }
+    }
+
     pub fn update(&self, index: usize, new_log: String) {
         let mut logs = self.logs.lock().unwrap();
         if let Some(log) = logs.get_mut(index) {
@@ -100,12 +107,14 @@ impl Metric {
         self.logs.lock().unwrap().clone()
     }
 
-    pub fn map_add(&self, key: &str, value: usize, eg: &Value) {
+    /// 累加 key 的耗时，key 首次出现时返回 true
+    pub fn map_add(&self, key: &str, value: usize, eg: &Value) -> bool {
         let mut map_count = self.map_count.lock().unwrap();
         if let Some(v) = map_count.get_mut(key) {
             v.count.fetch_add(1, self.ordering);
             v.sum.fetch_add(value, self.ordering);
             v.middle.add(value);
+            false
         } else {
             map_count.insert(
                 key.to_string(),
@@ -114,8 +123,31 @@ impl Metric {
                     sum: AtomicUsize::new(value),
                     middle: StreamingMedian::new(),
                     egs: vec![serde_json::to_string(eg).unwrap()],
+                    ..Default::default()
                 },
             );
+            true
+        }
+    }
+
+    /// 记录录制时的耗时和执行计划，需要在 map_add 之后调用
+    pub fn map_add_recorded(&self, key: &str, millis: Option<i64>, plan: Option<&String>) {
+        let mut map_count = self.map_count.lock().unwrap();
+        if let Some(v) = map_count.get_mut(key) {
+            if let Some(millis) = millis {
+                v.recorded_count.fetch_add(1, self.ordering);
+                v.recorded_sum.fetch_add(millis.max(0) as usize, self.ordering);
+            }
+            if v.recorded_plan.is_none() {
+                v.recorded_plan = plan.cloned();
+            }
+        }
+    }
+
+    pub fn map_set_plan(&self, key: &str, plan: String) {
+        let mut map_count = self.map_count.lock().unwrap();
+        if let Some(v) = map_count.get_mut(key) {
+            v.replayed_plan = Some(plan);
         }
     }
 
@@ -135,12 +167,17 @@ impl Metric {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Count {
     pub count: AtomicUsize,
     pub sum: AtomicUsize,
     pub middle: StreamingMedian,
     pub egs: Vec<String>,
+
+    pub recorded_count: AtomicUsize,
+    pub recorded_sum: AtomicUsize,
+    pub recorded_plan: Option<String>,
+    pub replayed_plan: Option<String>,
 }
 
 impl Clone for Count {
@@ -150,6 +187,15 @@ impl Clone for Count {
             sum: AtomicUsize::new(self.sum.load(std::sync::atomic::Ordering::Relaxed)),
             middle: self.middle.clone(),
             egs: self.egs.clone(),
+            recorded_count: AtomicUsize::new(
+                self.recorded_count
+                    .load(std::sync::atomic::Ordering::Relaxed),
+            ),
+            recorded_sum: AtomicUsize::new(
+                self.recorded_sum.load(std::sync::atomic::Ordering::Relaxed),
+            ),
+            recorded_plan: self.recorded_plan.clone(),
+            replayed_plan: self.replayed_plan.clone(),
         }
     }
 }
@@ -188,6 +234,12 @@ impl Indicator {
         self
     }
 
+    pub fn flush(&self) {
+        for metric in self.metric.values() {
+            metric.flush();
+        }
+    }
+
     pub fn take(&self, name: &str) -> Option<Arc<Metric>> {
         if let Some(v) = self.metric.get(name).map(|m| Arc::clone(m)) {
             Some(v)
@@ -204,7 +256,7 @@ impl Indicator {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct StreamingMedian {
     lower_half: BinaryHeap<usize>,          // 大顶堆
     upper_half: BinaryHeap<Reverse<usize>>, // 小顶堆
@@ -348,4 +400,21 @@ mod tests {
         sm.add(10);
         assert_eq!(sm.median(), 5);
     }
+
+    #[test]
+    fn test_map_add_first_seen() {
+        let metric = Arc::new(Metric::default());
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let metric = metric.clone();
+                thread::spawn(move || metric.map_add("k", 1, &Value::Null))
+            })
+            .collect();
+        let first: usize = handles
+            .into_iter()
+            .map(|h| h.join().unwrap() as usize)
+            .sum();
+        assert_eq!(first, 1);
+        assert_eq!(metric.map_get("k").unwrap().count.into_inner(), 8);
+    }
 }
@@ -2,10 +2,10 @@ use std::{env, path::PathBuf, sync::Arc};
 
 use bson::DateTime;
 use clap::Parser;
-use commands::{Cli, Commands, Tool};
+use commands::{Cli, Commands, ExportFormat, PullSource, Tool};
 use futures::Future;
 use indicator::print_indicator;
-use mongobar::Mongobar;
+use mongobar::{op_file, op_ns_map::NsMap, Mongobar};
 use signal::Signal;
 use tokio::runtime::Builder;
 
@@ -33,26 +33,29 @@ pub fn ind_keys() -> Vec<String> {
         "querying".to_string(),
         "dyn_threads".to_string(),
         "dyn_cc_limit".to_string(),
+        "skip_count".to_string(),
     ]
 }
 
 fn boot() -> Result<(), Box<dyn std::error::Error>> {
     let cli = Cli::parse();
+    let dry_run = cli.dry_run;
+    let allow_prod = cli.i_know_this_is_prod;
 
     match cli.commands {
         Commands::OPRecord(args) => {
             exec_tokio(move || async move {
-                if args.force {
-                    mongobar::Mongobar::new(&args.target)
-                        .clean()
-                        .op_record()
-                        .await?;
+                let signal = Arc::new(Signal::new());
+                signal::listen(Arc::clone(&signal));
+                let m = if args.force {
+                    mongobar::Mongobar::new(&args.target).clean()
                 } else {
-                    mongobar::Mongobar::new(&args.target)
-                        .init()
-                        .op_record()
-                        .await?;
-                }
+                    mongobar::Mongobar::new(&args.target).init()
+                };
+                m.set_allow_prod(allow_prod)
+                    .set_signal(signal)
+                    .op_record()
+                    .await?;
 
                 Ok(())
             });
@@ -66,16 +69,14 @@ fn boot() -> Result<(), Box<dyn std::error::Error>> {
                     .collect();
                 let start = time_range[0];
                 let end = time_range[1];
-                if args.force {
-                    mongobar::Mongobar::new(&args.target)
-                        .clean()
-                        .op_pull((start, end))
-                        .await?;
+                let mut m = if args.force {
+                    mongobar::Mongobar::new(&args.target).clean()
                 } else {
-                    mongobar::Mongobar::new(&args.target)
-                        .init()
-                        .op_pull((start, end))
-                        .await?;
+                    mongobar::Mongobar::new(&args.target).init()
+                };
+                match args.source {
+                    PullSource::Profile => m.op_pull((start, end)).await?,
+                    PullSource::Oplog => m.op_pull_oplog((start, end)).await?,
                 }
 
                 println!("OPRecord done output to `./mongobar/{}/*`.", args.target);
@@ -88,9 +89,16 @@ fn boot() -> Result<(), Box<dyn std::error::Error>> {
             exec_tokio(move || async move {
                 let indic = indicator::Indicator::new().init(ind_keys(), op_stress.target.clone());
                 print_indicator(&indic);
+                let signal = Arc::new(Signal::new());
+                signal::listen(Arc::clone(&signal));
                 let m = mongobar::Mongobar::new(&op_stress.target)
                     .set_indicator(indic)
+                    .set_signal(signal)
                     .set_ignore_field(op_stress.ignore_field)
+                    .set_dry_run(dry_run)
+                    .set_allow_prod(allow_prod)
+                    .set_ns_map(NsMap::new(&op_stress.map_ns, &op_stress.map_db)?)
+                    .set_seed(op_stress.seed)
                     .merge_config_uri(op_stress.uri)
                     .merge_config_loop_count(op_stress.loop_count)
                     .merge_config_thread_count(op_stress.thread_count)
@@ -108,8 +116,17 @@ fn boot() -> Result<(), Box<dyn std::error::Error>> {
             exec_tokio(move || async move {
                 let indic = indicator::Indicator::new().init(ind_keys(), op_replay.target.clone());
                 print_indicator(&indic);
+                let signal = Arc::new(Signal::new());
+                signal::listen(Arc::clone(&signal));
                 let m = mongobar::Mongobar::new(&op_replay.target)
                     .set_indicator(indic)
+                    .set_signal(signal)
+                    .set_ns_map(NsMap::new(&op_replay.map_ns, &op_replay.map_db)?)
+                    .set_seed(op_replay.seed)
+                    .set_continue(op_replay.continue_run)
+                    .set_causal(op_replay.causal, op_replay.shard_key)
+                    .set_dry_run(dry_run)
+                    .set_allow_prod(allow_prod)
                     .merge_config_rebuild(op_replay.rebuild)
                     .merge_config_uri(op_replay.uri)
                     .merge_config_thread_count(op_replay.thread_count)
@@ -127,8 +144,16 @@ fn boot() -> Result<(), Box<dyn std::error::Error>> {
             exec_tokio(move || async move {
                 let indic = indicator::Indicator::new().init(ind_keys(), args.target.clone());
                 print_indicator(&indic);
+                let signal = Arc::new(Signal::new());
+                signal::listen(Arc::clone(&signal));
                 let m = mongobar::Mongobar::new(&args.target)
                     .set_indicator(indic)
+                    .set_signal(signal)
+                    .set_ns_map(NsMap::new(&args.map_ns, &args.map_db)?)
+                    .set_continue(args.continue_run)
+                    .set_causal(args.causal, args.shard_key)
+                    .set_dry_run(dry_run)
+                    .set_allow_prod(allow_prod)
                     .merge_config_rebuild(args.rebuild)
                     .merge_config_uri(args.uri)
                     .init();
@@ -144,8 +169,16 @@ fn boot() -> Result<(), Box<dyn std::error::Error>> {
             exec_tokio(move || async move {
                 let indic = indicator::Indicator::new().init(ind_keys(), args.target.clone());
                 print_indicator(&indic);
+                let signal = Arc::new(Signal::new());
+                signal::listen(Arc::clone(&signal));
                 let m = mongobar::Mongobar::new(&args.target)
                     .set_indicator(indic)
+                    .set_signal(signal)
+                    .set_ns_map(NsMap::new(&args.map_ns, &args.map_db)?)
+                    .set_continue(args.continue_run)
+                    .set_causal(args.causal, args.shard_key)
+                    .set_dry_run(dry_run)
+                    .set_allow_prod(allow_prod)
                     .merge_config_rebuild(args.rebuild)
                     .merge_config_uri(args.uri)
                     .init();
@@ -156,12 +189,43 @@ fn boot() -> Result<(), Box<dyn std::error::Error>> {
                 Ok(())
             });
         }
+        Commands::OPVerify(mut args) => {
+            target_parse(&mut args.target, args.update);
+            exec_tokio(move || async move {
+                let reports = mongobar::Mongobar::new(&args.target)
+                    .merge_config_uri(args.uri)
+                    .init()
+                    .op_verify()
+                    .await?;
+                let mut ok = true;
+                for report in reports.iter() {
+                    println!(
+                        "{} count: {}/{} diffs: {} {}",
+                        report.ns,
+                        report.live_count,
+                        report.expected_count,
+                        report.diffs.len(),
+                        if report.is_ok() { "ok" } else { "mismatch" }
+                    );
+                    for (id, diff) in report.diffs.iter() {
+                        println!("  {} {}", diff.name(), id);
+                    }
+                    ok &= report.is_ok();
+                }
+                if !ok {
+                    std::process::exit(1);
+                }
+                println!("OPVerify done.");
+                Ok(())
+            });
+        }
         Commands::OPBuildResume(args) => {
             exec_tokio(move || async move {
                 let indic = indicator::Indicator::new().init(ind_keys(), args.target.clone());
                 print_indicator(&indic);
 
                 mongobar::Mongobar::new(&args.target)
+                    .set_ns_map(NsMap::new(&args.map_ns, &args.map_db)?)
                     .merge_config_rebuild(args.rebuild)
                     .merge_config_uri(args.uri)
                     .init()
@@ -178,57 +242,176 @@ fn boot() -> Result<(), Box<dyn std::error::Error>> {
         }
         Commands::UI(mut ui) => {
             target_parse(&mut ui.target, ui.update);
-            let _ = ui::boot(ui);
+            ui::boot(ui, allow_prod)?;
         }
-        Commands::OPExport(args) => exec_tokio(move || async move {
-            mongobar::Mongobar::new(&args.target)
-                .init()
-                .op_export()
-                .await?;
+        Commands::OPExport(mut args) => {
+            target_parse(&mut args.target, args.update);
+            exec_tokio(move || async move {
+                let mut m = mongobar::Mongobar::new(&args.target)
+                    .merge_config_uri(args.uri)
+                    .merge_config_thread_count(args.thread_count)
+                    .merge_config_rebuild(args.rebuild)
+                    .init();
+                let dump = match args.format {
+                    ExportFormat::Op => None,
+                    ExportFormat::BsonDump => Some(
+                        args.out
+                            .map(PathBuf::from)
+                            .unwrap_or_else(|| m.op_dir_dump.clone()),
+                    ),
+                };
+                m.op_export(dump.clone()).await?;
 
-            println!(
-                "OPExport done output to `./mongobar/{}/data.op`.",
-                args.target
-            );
+                match dump {
+                    Some(dir) => println!("OPExport done output to `{}`.", dir.to_str().unwrap()),
+                    None => println!(
+                        "OPExport done output to `./mongobar/{}/data.op`.",
+                        args.target
+                    ),
+                }
 
-            Ok(())
-        }),
+                Ok(())
+            });
+        }
         Commands::OPImport(args) => {
             exec_tokio(move || async move {
                 let indic = indicator::Indicator::new().init(ind_keys(), args.target.clone());
                 print_indicator(&indic);
+                let signal = Arc::new(Signal::new());
+                signal::listen(Arc::clone(&signal));
                 mongobar::Mongobar::new(&args.target)
                     .merge_config_uri(Some(args.uri))
                     .set_indicator(indic)
+                    .set_signal(signal)
+                    .set_dry_run(dry_run)
+                    .set_allow_prod(allow_prod)
                     .init()
-                    .op_import()
+                    .op_import(args.dump.map(PathBuf::from), args.thread_count, args.batch_size)
                     .await?;
 
-                println!("OPImport done by `./mongobar/{}/data.op`.", args.target);
+                println!("OPImport [{}] Done", args.target);
                 Ok(())
             });
         }
         Commands::Tool(tool) => match tool {
             Tool::Ana(args) => {
-                tool::analyze::analysis_alilog_csv(&args.target).unwrap();
+                tool::analyze::analysis_audit_log(&args.target, args.format).unwrap();
             }
             Tool::Cov(args) => {
-                tool::convert::convert_alilog_csv(&args.target, args.filter_db.unwrap_or_default())
-                    .unwrap();
+                tool::convert::convert_audit_log(
+                    &args.target,
+                    args.filter_db.unwrap_or_default(),
+                    args.format,
+                )
+                .unwrap();
+            }
+            Tool::Pack(args) => {
+                let (outfile, n) = tool::pack::pack(&args.target, args.out).unwrap();
+                println!("# Pack {} rows to {}.", n, outfile.display());
+            }
+            Tool::Unpack(args) => {
+                let (outfile, n) = tool::pack::unpack(&args.target, args.out).unwrap();
+                println!("# Unpack {} rows to {}.", n, outfile.display());
+            }
+            Tool::Index(args) => {
+                let path = PathBuf::from(&args.target);
+                let index = mongobar::op_index::OpIndex::build(&path).unwrap();
+                let idx_path = index.save(&path).unwrap();
+                println!("# Index {} rows to {}.", index.len(), idx_path.display());
+            }
+            Tool::Lint(args) => {
+                let report = tool::lint::lint(&args.target, args.fix).unwrap();
+                println!(
+                    "# Lint {} rows, {} bad, {} fixed, {} dropped.",
+                    report.rows, report.bad, report.fixed, report.dropped
+                );
+                if !args.fix && report.bad > 0 {
+                    std::process::exit(1);
+                }
+            }
+            Tool::Sample(args) => {
+                let (outfile, n) = tool::transform::sample(
+                    &args.target,
+                    args.out,
+                    args.ratio,
+                    args.n,
+                    args.seed,
+                )
+                .unwrap();
+                println!("# Sample {} rows to {}.", n, outfile.display());
+            }
+            Tool::Split(args) => {
+                let outs =
+                    tool::transform::split(&args.target, args.out, args.by, args.window).unwrap();
+                for (outfile, n) in outs.iter() {
+                    println!("# Split {} rows to {}.", n, outfile.display());
+                }
+            }
+            Tool::Merge(args) => {
+                let (outfile, n) = tool::transform::merge(&args.targets, &args.out).unwrap();
+                println!("# Merge {} rows to {}.", n, outfile.display());
+            }
+            Tool::ShiftTime(args) => {
+                let to = args.to.map(|to| {
+                    DateTime::parse_rfc3339_str(&to)
+                        .unwrap()
+                        .timestamp_millis()
+                });
+                let (outfile, n) =
+                    tool::transform::shift_time(&args.target, args.out, to, args.offset).unwrap();
+                println!("# Shift {} rows to {}.", n, outfile.display());
+            }
+            Tool::Dedup(args) => {
+                let (outfile, n) = tool::transform::dedup(&args.target, args.out, args.by).unwrap();
+                println!("# Dedup {} rows to {}.", n, outfile.display());
+            }
+            Tool::Head(args) => {
+                let (outfile, n) =
+                    tool::transform::head_tail(&args.target, args.out, &args.duration, false)
+                        .unwrap();
+                println!("# Head {} rows to {}.", n, outfile.display());
+            }
+            Tool::Tail(args) => {
+                let (outfile, n) =
+                    tool::transform::head_tail(&args.target, args.out, &args.duration, true)
+                        .unwrap();
+                println!("# Tail {} rows to {}.", n, outfile.display());
+            }
+            Tool::Templatize(args) => {
+                let (outfile, report) = tool::templatize::templatize(
+                    &args.target,
+                    args.out,
+                    args.max_values,
+                    args.rows,
+                )
+                .unwrap();
+                println!(
+                    "# Templatize {} rows to {} templates, write {} rows to {}.",
+                    report.rows,
+                    report.templates,
+                    report.written,
+                    outfile.display()
+                );
             }
             Tool::Filter(args) => {
                 if args.mode {
                     let n = tool::filter::mode_filter_line(&args.target, &args.filter);
                     println!("# Filter {} lines.", n);
                 } else {
-                    let n = tool::filter::reg_filter_line(&args.target, &args.filter);
+                    let n = tool::filter::reg_filter_line(&args.target, &args.filter)?;
                     println!("# Filter {} lines.", n);
                 }
             }
         },
         Commands::SaveAs(args) => {
             let m = mongobar::Mongobar::new(&args.target);
-            m.save_as(&args.outdir, args.force).unwrap();
+            let compress = args.compress.map(|c| match c {
+                commands::Compress::None => op_file::OpCompression::None,
+                commands::Compress::Zstd => op_file::OpCompression::Zstd,
+                commands::Compress::Gzip => op_file::OpCompression::Gzip,
+            });
+            let outfile = m.save_as(&args.outdir, args.force, compress).unwrap();
+            println!("Saved to {}", outfile);
         }
         Commands::Stats(args) => {
             let mongobar = mongobar::Mongobar::new("stats")
@@ -258,7 +441,7 @@ fn boot() -> Result<(), Box<dyn std::error::Error>> {
             let _ = ind.join();
         }
         Commands::IndexMigrate(args) => {
-            if let Err(err) = mongo_stats::index_migrate(args).join() {
+            if let Err(err) = mongo_stats::index_migrate(args, dry_run).join() {
                 eprintln!("Error occurred during index migration: {:?}", err);
             }
         }
@@ -272,23 +455,29 @@ fn target_parse(target: &mut String, update: Option<bool>) {
     if path.exists() {
         let ext = path.extension().unwrap();
         match ext.to_str().unwrap() {
-            "op" => {
-                let name = path.file_stem().unwrap().to_str().unwrap();
+            "op" | "opb" | "zst" | "gz" if op_file::is_op_file(&path) => {
+                let name = &op_file::op_name(&path);
                 *target = name.to_string();
-                // 复制文件到 .mongobar/{name}/oplogs.op
+                // 复制文件到 .mongobar/{name}/oplogs.op，保留格式和压缩的后缀 oplogs.opb/oplogs.op.zst
+                let suffix = op_file::op_suffix(&path);
                 let m = Mongobar::new(name);
                 if m.exists() {
                     if update.unwrap_or_default() {
                         m.clean();
-                        let _ =
-                            std::fs::copy(path.clone(), format!("./.mongobar/{}/oplogs.op", name));
+                        let _ = std::fs::copy(
+                            path.clone(),
+                            format!("./.mongobar/{}/oplogs{}", name, suffix),
+                        );
                     }
                 } else {
                     m.init();
-                    let _ = std::fs::copy(path.clone(), format!("./.mongobar/{}/oplogs.op", name));
+                    let _ = std::fs::copy(
+                        path.clone(),
+                        format!("./.mongobar/{}/oplogs{}", name, suffix),
+                    );
                 }
             }
-            "csv" => {
+            "csv" | "json" => {
                 let name = path.file_stem().unwrap().to_str().unwrap();
                 *target = name.to_string();
                 let m = Mongobar::new(name);
@@ -296,21 +485,23 @@ fn target_parse(target: &mut String, update: Option<bool>) {
                 // 复制文件到 .mongobar/{name}/oplogs.csv
                 if m.exists() {
                     if update.unwrap_or_default() {
-                        let oplogs_path = tool::convert::convert_alilog_csv(
+                        let oplogs_path = tool::convert::convert_audit_log(
                             path.to_str().unwrap(),
                             m.config.db.clone(),
+                            None,
                         )
-                        .expect("convert_alilog_csv failed, please check the csv file format.");
+                        .expect("convert_audit_log failed, please check the audit log format.");
                         m.clean();
                         let _ =
                             std::fs::rename(oplogs_path, format!("./.mongobar/{}/oplogs.op", name));
                     }
                 } else {
-                    let oplogs_path = tool::convert::convert_alilog_csv(
+                    let oplogs_path = tool::convert::convert_audit_log(
                         path.to_str().unwrap(),
                         m.config.db.clone(),
+                        None,
                     )
-                    .expect("convert_alilog_csv failed, please check the csv file format.");
+                    .expect("convert_audit_log failed, please check the audit log format.");
                     m.init();
                     let _ = std::fs::rename(oplogs_path, format!("./.mongobar/{}/oplogs.op", name));
                 }
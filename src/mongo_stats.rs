@@ -115,8 +115,9 @@ pub fn index_status(uri: String, db: String, coll: String) -> JoinHandle<()> {
     handle
 }
 
-pub fn index_migrate(index_migrate: IndexMigrate) -> JoinHandle<()> {
-    let handle = thread::spawn(|| {
+/// dry_run 时只打印将要创建和删除的索引
+pub fn index_migrate(index_migrate: IndexMigrate, dry_run: bool) -> JoinHandle<()> {
+    let handle = thread::spawn(move || {
         let rt = Builder::new_current_thread().enable_all().build().unwrap();
         rt.block_on(async move {
             let mut source_indexes = std::fs::read(index_migrate.index_path)
@@ -169,7 +170,9 @@ pub fn index_migrate(index_migrate: IndexMigrate) -> JoinHandle<()> {
                 let keys = index.remove("key").unwrap();
                 let name = index.get("name").unwrap().as_str().unwrap();
                 
-                if !drop_indexes.contains(&name.to_string()) {
+                if !drop_indexes.contains(&name.to_string()) && dry_run {
+                    println!("DryRun IndexMigrate [{}.{}] Create index: {} {}", db, coll, name, keys);
+                } else if !drop_indexes.contains(&name.to_string()) {
                     println!("IndexMigrate [{}.{}] Create index: {}", db, coll, name);
 
                     let create_index: Result<IndexOptions, serde_json::Error> = serde::Deserialize::deserialize(Value::Object(index.clone()));                    
@@ -187,6 +190,10 @@ pub fn index_migrate(index_migrate: IndexMigrate) -> JoinHandle<()> {
                 drop_indexes.retain(|x| x != name);
             }
             for index in drop_indexes {
+                if dry_run {
+                    println!("DryRun IndexMigrate [{}.{}] Drop   index: {}", db, coll, index);
+                    continue;
+                }
                 println!("IndexMigrate [{}.{}] Drop   index: {}", db, coll, index);
                 target_coll.drop_index(index).await.unwrap();
             }
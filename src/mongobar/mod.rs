@@ -1,14 +1,24 @@
 use std::{
+    collections::BTreeSet,
     fs::{self},
     path::PathBuf,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     thread, vec,
 };
 
-use bson::{doc, DateTime};
+use bson::{doc, Bson, DateTime, Timestamp};
 
 use hashbrown::{HashMap, HashSet};
-use mongodb::{bson::Document, options::ClientOptions, Client, Collection, Cursor};
+use mongodb::{
+    bson::Document,
+    error::{ErrorKind, InsertManyError},
+    options::ClientOptions,
+    Client, Collection, Cursor,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use serde::Deserialize;
 use serde_json::{json, Value};
 
@@ -17,15 +27,35 @@ use crate::{
     utils::{get_db_coll, to_sha3},
 };
 use futures::TryStreamExt;
-use op_logs::{reverse_file, OpLogs, OpReadMode};
-use tokio::{fs::OpenOptions, io::AsyncWriteExt, time::Instant};
+use op_logs::{reverse_file, OpReadMode};
+use tokio::time::Instant;
 
 mod mongobar_config;
 
 mod op_state;
 
+mod op_export;
+
+mod op_causal;
+mod op_checkpoint;
+
+pub mod op_dry_run;
+pub mod op_dump;
+pub mod op_file;
+pub mod op_filter;
+#[cfg(test)]
+pub(crate) mod op_fixture;
+pub mod op_gen;
+pub mod op_guard;
+pub mod op_header;
+pub mod op_index;
 pub mod op_logs;
+pub mod op_ns_map;
+pub mod op_oplog;
+pub mod op_plan;
 pub mod op_row;
+pub mod op_snapshot;
+pub mod op_verify;
 
 #[derive(Debug, Clone)]
 pub enum OpRunMode {
@@ -43,6 +73,9 @@ pub(crate) struct Mongobar {
     pub(crate) op_file_revert: PathBuf,
     pub(crate) op_file_resume: PathBuf,
     pub(crate) op_file_data: PathBuf,
+    /// op-export --format bson-dump 的默认目录
+    pub(crate) op_dir_dump: PathBuf,
+    pub(crate) op_file_baseline: PathBuf,
 
     pub(crate) op_state_file: PathBuf,
     pub(crate) op_state: op_state::OpState,
@@ -52,6 +85,21 @@ pub(crate) struct Mongobar {
     pub(crate) indicator: Indicator,
     pub(crate) signal: Arc<crate::signal::Signal>,
     pub(crate) ignore_field: Vec<String>,
+    pub(crate) ns_map: op_ns_map::NsMap,
+    /// 模板占位符 `$gen`、分配线程和退避的随机数种子
+    pub(crate) seed: u64,
+    /// 指定了 `--seed`，由一个任务按照 seed 给线程分配行，相同的 seed 每一行分配到的线程和退避时间相同，
+    /// 线程之间实际的交错顺序仍然取决于执行的耗时
+    pub(crate) seeded: bool,
+    /// 只统计将要执行的写操作，不修改目标数据库
+    pub(crate) dry_run: bool,
+    /// 允许在 protected 集群上执行写操作，`--i-know-this-is-prod`
+    pub(crate) allow_prod: bool,
+    /// `--continue`，从上次中断的断点继续执行
+    pub(crate) continue_run: bool,
+    /// 按照因果关系（ns + _id 或者分片键）分配行，同一个文档的写操作按照录制的顺序执行
+    pub(crate) causal: bool,
+    pub(crate) shard_keys: Vec<String>,
 }
 
 impl Mongobar {
@@ -59,7 +107,7 @@ impl Mongobar {
         let cur_cwd: PathBuf = std::env::current_dir().unwrap();
         let dir: PathBuf = cur_cwd.join(".mongobar");
         let workdir: PathBuf = dir.join(name);
-        let op_file_oplogs = workdir.join(PathBuf::from("oplogs.op"));
+        let op_file_oplogs = op_file::resolve(workdir.join(PathBuf::from("oplogs.op")));
         Self {
             name: name.to_string(),
             op_workdir: workdir.clone(),
@@ -67,6 +115,8 @@ impl Mongobar {
             op_file_revert: workdir.join(PathBuf::from("revert.op")),
             op_file_resume: workdir.join(PathBuf::from("resume.op")),
             op_file_data: workdir.join(PathBuf::from("data.op")),
+            op_dir_dump: workdir.join(PathBuf::from("dump")),
+            op_file_baseline: workdir.join(PathBuf::from("baseline.json")),
             config: mongobar_config::MongobarConfig::new(
                 cur_cwd.join(PathBuf::from("mongobar.json")),
             ),
@@ -78,6 +128,14 @@ impl Mongobar {
 
             signal: Arc::new(crate::signal::Signal::new()),
             ignore_field: vec![],
+            ns_map: op_ns_map::NsMap::default(),
+            seed: rand::random(),
+            seeded: false,
+            dry_run: false,
+            allow_prod: false,
+            continue_run: false,
+            causal: false,
+            shard_keys: vec![],
         }
     }
 
@@ -118,6 +176,40 @@ impl Mongobar {
         self
     }
 
+    pub fn set_ns_map(mut self, ns_map: op_ns_map::NsMap) -> Self {
+        self.ns_map = ns_map;
+        self
+    }
+
+    pub fn set_seed(mut self, seed: Option<u64>) -> Self {
+        if let Some(seed) = seed {
+            self.seed = seed;
+            self.seeded = true;
+        }
+        self
+    }
+
+    pub fn set_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    pub fn set_allow_prod(mut self, allow_prod: bool) -> Self {
+        self.allow_prod = allow_prod;
+        self
+    }
+
+    pub fn set_continue(mut self, continue_run: bool) -> Self {
+        self.continue_run = continue_run;
+        self
+    }
+
+    pub fn set_causal(mut self, causal: bool, shard_keys: Vec<String>) -> Self {
+        self.causal = causal;
+        self.shard_keys = shard_keys;
+        self
+    }
+
     pub fn merge_config_rebuild(mut self, rebuild: Option<bool>) -> Self {
         self.config.rebuild = rebuild;
         self
@@ -197,7 +289,18 @@ impl Mongobar {
             "OPRecord [{}] Start collecting logs, please operate...",
             chrono::Local::now().timestamp()
         );
-        let client = Client::with_uri_str(&self.config.uri).await?;
+        let options = ClientOptions::parse(&self.config.uri).await?;
+        let seeds = options.hosts.iter().map(|h| h.to_string()).collect();
+        let client = Client::with_options(options)?;
+
+        op_guard::guard(
+            &client,
+            seeds,
+            &self.config.protected,
+            self.allow_prod,
+            "set profile 2",
+        )
+        .await?;
 
         let db = client.database(&self.config.db);
 
@@ -218,8 +321,17 @@ impl Mongobar {
             chrono::Local::now().timestamp()
         );
 
-        let mut input = String::new();
-        std::io::stdin().read_line(&mut input).expect("READ ERROR");
+        // 中断时也需要恢复 profile，在单独的线程中读取输入
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        thread::spawn(move || {
+            let mut input = String::new();
+            std::io::stdin().read_line(&mut input).expect("READ ERROR");
+            let _ = tx.send(input);
+        });
+        let input = tokio::select! {
+            input = rx => input.unwrap_or_default(),
+            _ = self.signal.wait() => String::new(),
+        };
 
         if let Ok(was) = cur_profile.get_i32("was") {
             println!(
@@ -280,6 +392,7 @@ impl Mongobar {
         // let doc_as_json = serde_json::to_string(&query)?;
         // println!("{}", doc_as_json);
         let mut cursor: Cursor<Document> = c.find(query).await?;
+        let mut writer = op_file::OpWriter::append(&self.op_file_oplogs)?;
 
         while cursor.advance().await? {
             let doc = cursor.deserialize_current().unwrap();
@@ -345,42 +458,21 @@ impl Mongobar {
                     row.coll = nsp.1;
                     row.cmd = json!(cmd);
                 }
-                "command" => {
+                "command" | "aggregate" => {
                     if let Ok(_) = cmd.get_str("aggregate") {
                         if let Err(_) = cmd.get_array("pipeline") {
                             continue;
                         }
-                        row.id = to_sha3(&cmd.to_string());
-                        let nsp = get_db_coll(&ns);
-                        row.ns = ns;
-                        row.ts = doc.get_datetime("ts").unwrap().timestamp_millis() as i64;
-                        row.op = op_row::Op::Aggregate;
-                        row.db = nsp.0;
-                        row.coll = nsp.1;
-                        row.cmd = json!(cmd);
-                    } else {
-                        row.id = to_sha3(&cmd.to_string());
-                        let nsp = get_db_coll(&ns);
-                        row.ns = ns;
-                        row.ts = doc.get_datetime("ts").unwrap().timestamp_millis() as i64;
-                        row.op = op_row::Op::Command;
-                        row.db = nsp.0;
-                        row.coll = nsp.1;
-                        row.cmd = json!(cmd);
-                    }
-                }
-                "aggregate" => {
-                    if let Err(_) = cmd.get_array("pipeline") {
-                        continue;
                     }
                     row.id = to_sha3(&cmd.to_string());
                     let nsp = get_db_coll(&ns);
                     row.ns = ns;
                     row.ts = doc.get_datetime("ts").unwrap().timestamp_millis() as i64;
-                    row.op = op_row::Op::Aggregate;
+                    row.cmd = json!(cmd);
+                    // count/distinct/countDocuments/geoNear/$search 等单独区分
+                    row.op = op_row::Op::from_cmd(&row.cmd);
                     row.db = nsp.0;
                     row.coll = nsp.1;
-                    row.cmd = json!(cmd);
                 }
                 "getmore" => {
                     row.id = to_sha3(&cmd.to_string());
@@ -405,13 +497,125 @@ impl Mongobar {
                 _ => {}
             }
 
+            row.profile = Some(op_row::OpProfile::from_profile_doc(&doc));
+
             // println!("{:?}", row);
-            op_logs::OpLogs::push_line(self.op_file_oplogs.clone(), row);
+            writer.write(&row)?;
         }
+        writer.finish()?;
+
+        self.stamp_header("profile", time_range)?;
 
         Ok(())
     }
 
+    /// 拉取完成后写入头信息，记录来源和录制的时间范围
+    fn stamp_header(
+        &self,
+        source: &str,
+        time_range: (DateTime, DateTime),
+    ) -> Result<(), anyhow::Error> {
+        let header = op_header::OpHeader::new(source, &self.config.db, &self.config.uri)
+            .with_time_range(
+                time_range.0.timestamp_millis(),
+                time_range.1.timestamp_millis(),
+            );
+        let header = op_header::stamp(&self.op_file_oplogs, header)?;
+        println!(
+            "OPPull [{}] header: {}",
+            chrono::Local::now().timestamp(),
+            header.summary()
+        );
+        Ok(())
+    }
+
+    /// 从 local.oplog.rs 拉取写操作（insert/update/delete，包括 applyOps 批量写入）
+    /// 不需要提前开启 profile，只要时间范围还在 oplog 窗口内就可以重建写流量
+    pub async fn op_pull_oplog(
+        &mut self,
+        time_range: (DateTime, DateTime),
+    ) -> Result<(), anyhow::Error> {
+        let start_time = time_range.0;
+        let end_time = time_range.1;
+
+        println!(
+            "OPPull [{}] source: oplog start_time: {} end_time: {}",
+            chrono::Local::now().timestamp(),
+            start_time,
+            end_time
+        );
+
+        let client = Client::with_uri_str(&self.config.uri).await?;
+
+        let c: Collection<Document> = client.database("local").collection("oplog.rs");
+
+        let query = doc! {
+            "ts": {
+                "$gte": Timestamp { time: (start_time.timestamp_millis() / 1000) as u32, increment: 0 },
+                "$lt": Timestamp { time: (end_time.timestamp_millis() / 1000) as u32, increment: 0 },
+            },
+            "$or": [
+                { "ns": { "$regex": format!("^{}\\.", regex::escape(&self.config.db)) } },
+                { "op": "c", "o.applyOps": { "$exists": true } },
+            ]
+        };
+        let mut cursor: Cursor<Document> = c.find(query).sort(doc! { "$natural": 1 }).await?;
+
+        let mut count = 0;
+        let mut writer = op_file::OpWriter::append(&self.op_file_oplogs)?;
+        while cursor.advance().await? {
+            let doc = cursor.deserialize_current()?;
+            for row in op_oplog::oplog_to_rows(&doc, &self.config.db) {
+                writer.write(&row)?;
+                count += 1;
+            }
+        }
+        writer.finish()?;
+
+        println!(
+            "OPPull [{}] source: oplog rows: {}",
+            chrono::Local::now().timestamp(),
+            count
+        );
+
+        self.stamp_header("oplog", time_range)?;
+
+        Ok(())
+    }
+
+    /// `--dry-run`：完整地加载 OpLogs（包括命名空间映射和 `$gen`），只统计写操作并打印样例
+    fn op_dry_run(
+        &self,
+        exec_file: PathBuf,
+        loop_count: usize,
+        mode: op_logs::OpReadMode,
+        op_run_mode: OpRunMode,
+    ) -> Result<(), anyhow::Error> {
+        let op_logs = op_logs::OpLogs::new(exec_file.clone(), mode, self.ignore_field.clone())
+            .set_ns_map(self.ns_map.clone())
+            .init()?;
+        let mut op_gen = op_gen::OpGen::new(self.seed);
+        let mut dry_run = op_dry_run::DryRun::default();
+        while let Some(mut row) = op_logs.read(0, 0) {
+            if let OpRunMode::Readonly = op_run_mode {
+                if !row.is_readonly() {
+                    continue;
+                }
+            }
+            op_gen.resolve(&mut row)?;
+            dry_run.add(&row);
+        }
+        let title = format!(
+            "{} x{}",
+            exec_file.file_name().unwrap().to_str().unwrap(),
+            loop_count
+        );
+        for line in dry_run.summary(&title) {
+            println!("{}", line);
+        }
+        Ok(())
+    }
+
     pub async fn op_exec(
         &self,
         exec_file: PathBuf,
@@ -420,25 +624,76 @@ impl Mongobar {
         mode: op_logs::OpReadMode,
         op_run_mode: OpRunMode,
     ) -> Result<(), anyhow::Error> {
+        if self.dry_run {
+            return self.op_dry_run(exec_file, loop_count, mode, op_run_mode);
+        }
+
         // let record_start_time = DateTime::from_millis(self.op_state.record_start_ts);
         // let record_end_time = DateTime::from_millis(self.op_state.record_end_ts);
 
         let mongo_uri: String = self.config.uri.clone();
+        // 执行期间关闭了 profile，结束（包括中断）后恢复
+        let mut restore_profile = None;
         {
             let options = ClientOptions::parse(&mongo_uri).await.unwrap();
+            let seeds = options.hosts.iter().map(|h| h.to_string()).collect();
             let client = Client::with_options(options).unwrap();
             let db = client.database(&self.config.db);
 
+            if let OpRunMode::ReadWrite = op_run_mode {
+                op_guard::guard(
+                    &client,
+                    seeds,
+                    &self.config.protected,
+                    self.allow_prod,
+                    "write",
+                )
+                .await?;
+            }
+
             let cur_profile = db.run_command(doc! {  "profile": -1 }).await?;
 
             if let Ok(was) = cur_profile.get_i32("was") {
                 if was == 2 {
                     db.run_command(doc! { "profile": 0 }).await?;
+                    restore_profile = Some(was);
                 }
             }
             client.shutdown().await;
         }
 
+        let mut res = self
+            .op_exec_workers(exec_file, thread_count, loop_count, mode, op_run_mode)
+            .await;
+
+        // 执行失败时也需要恢复 profile
+        if let Some(was) = restore_profile {
+            let restored = async {
+                let client = Client::with_uri_str(&mongo_uri).await?;
+                client
+                    .database(&self.config.db)
+                    .run_command(doc! { "profile": was })
+                    .await?;
+                client.shutdown().await;
+                anyhow::Ok(())
+            }
+            .await;
+            res = res.and(restored);
+        }
+        self.indicator.flush();
+
+        res
+    }
+
+    /// 启动线程执行 oplogs，等待全部完成（或者中断）并保存断点
+    async fn op_exec_workers(
+        &self,
+        exec_file: PathBuf,
+        thread_count: u32,
+        loop_count: usize,
+        mode: op_logs::OpReadMode,
+        op_run_mode: OpRunMode,
+    ) -> Result<(), anyhow::Error> {
         // println!(
         //     "OPExec [{}] loop_count: {} thread_count: {}",
         //     chrono::Local::now().timestamp(),
@@ -464,6 +719,7 @@ impl Mongobar {
         let progress_total = self.indicator.take("progress_total").unwrap();
         let logs = self.indicator.take("logs").unwrap();
         let query_stats = self.indicator.take("query_stats").unwrap();
+        let skip_count = self.indicator.take("skip_count").unwrap();
         let signal = Arc::clone(&self.signal);
         let stack: HashMap<String, Instant> = HashMap::new();
         let stack = Arc::new(std::sync::Mutex::new(stack));
@@ -473,10 +729,116 @@ impl Mongobar {
             .unwrap()
             .set(thread_count as usize);
         let mut client_pool = ClientPool::new(&self.config.uri, thread_count * 100);
+        let exec_path = exec_file.to_str().unwrap().to_string();
         let op_logs = Arc::new(
-            op_logs::OpLogs::new(exec_file, mode.clone(), self.ignore_field.clone()).init(),
+            op_logs::OpLogs::new(exec_file, mode.clone(), self.ignore_field.clone())
+                .set_ns_map(self.ns_map.clone())
+                .init()?,
         );
 
+        // 只执行一遍时（回放、还原、导入）每隔 CHECKPOINT_SECS 秒保存断点，`--continue` 时从断点继续
+        let mut checkpoint_state = None;
+        if loop_count == 1 {
+            let mut state = self.op_state.clone();
+            let start = match self.continue_run && state.stress_file == exec_path {
+                true => state.stress_index as usize,
+                false => 0,
+            };
+            if start > 0 {
+                op_logs.skip(start);
+                progress.set(start);
+                query_count.set(state.stress_query_count);
+                cost_ms.set(state.stress_cost_ms);
+                skip_count.set(state.stress_skip_count);
+                logs.push(format!(
+                    "OPExec [{}] continue from row {}",
+                    chrono::Local::now().timestamp(),
+                    start
+                ));
+            } else {
+                state.stress_start_ts = chrono::Local::now().timestamp();
+            }
+            state.stress_file = exec_path;
+            checkpoint_state = Some((op_checkpoint::Checkpoint::new(start), state));
+        }
+        let checkpoint = checkpoint_state.as_ref().map(|(c, _)| Arc::clone(c));
+
+        // 因果顺序或者指定了 seed：由一个任务按顺序读取并分配，相同 key 的行发送给同一个线程
+        let mut causal_receivers = vec![];
+        let causal = self.causal && loop_count == 1;
+        if causal || self.seeded {
+            let scheduler = match causal {
+                true => op_causal::CausalScheduler::new(
+                    thread_count as usize,
+                    &self.shard_keys,
+                    self.seed,
+                )?,
+                false => op_causal::CausalScheduler::seeded(thread_count as usize, self.seed),
+            };
+            let mut senders = vec![];
+            for _ in 0..thread_count {
+                let (tx, rx) = tokio::sync::mpsc::channel(op_causal::QUEUE_SIZE);
+                senders.push(tx);
+                causal_receivers.push(Some(rx));
+            }
+            // FullLine 时每个线程都执行 loop_count 遍，和 progress_total 一致
+            let passes = match mode {
+                op_logs::OpReadMode::FullLine(_) => loop_count * thread_count as usize,
+                _ => loop_count,
+            };
+            let op_logs = Arc::clone(&op_logs);
+            let checkpoint = checkpoint.clone();
+            let signal = Arc::clone(&signal);
+            let mut dispatcher = op_causal::Dispatcher::new(scheduler, senders);
+            tokio::spawn(async move {
+                let mut pass = 0;
+                'dispatch: while passes == 0 || pass < passes {
+                    pass += 1;
+                    let mut count = 0;
+                    while let Some((done, row)) =
+                        op_checkpoint::read_row(&checkpoint, || op_logs.read(0, 0))
+                    {
+                        count += 1;
+                        if let Err(tokio::sync::mpsc::error::SendError((_, done, _))) =
+                            dispatcher.send(done, row).await
+                        {
+                            if let Some(done) = done {
+                                done.cancel();
+                            }
+                            break 'dispatch;
+                        }
+                    }
+                    if count == 0 || signal.get() != 0 {
+                        break;
+                    }
+                }
+            });
+        }
+        let dispatched = !causal_receivers.is_empty();
+        let checkpoint_saver = checkpoint_state.as_ref().map(|(checkpoint, state)| {
+            let checkpoint = Arc::clone(checkpoint);
+            let mut state = state.clone();
+            let state_file = self.op_state_file.clone();
+            let (query_count, cost_ms, skip_count) =
+                (query_count.clone(), cost_ms.clone(), skip_count.clone());
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(tokio::time::Duration::from_secs(
+                        op_checkpoint::CHECKPOINT_SECS,
+                    ))
+                    .await;
+                    let metrics = (query_count.get(), cost_ms.get(), skip_count.get());
+                    let _ = op_checkpoint::save(
+                        &mut state,
+                        &state_file,
+                        checkpoint.index(),
+                        metrics,
+                        false,
+                    );
+                }
+            })
+        });
+
         thread::spawn({
             let stack = Arc::clone(&stack);
             let logs = Arc::clone(&logs);
@@ -510,6 +872,30 @@ impl Mongobar {
             }
         });
 
+        // 对比执行计划的 explain 使用单独的连接，和压测的连接池分开
+        let (explain_tx, mut explain_rx) =
+            tokio::sync::mpsc::unbounded_channel::<(String, String, Document)>();
+        let explainer = {
+            let client = Client::with_uri_str(&self.config.uri).await?;
+            let query_stats = query_stats.clone();
+            tokio::spawn(async move {
+                while let Some((key, db, explain)) = explain_rx.recv().await {
+                    if let Ok(res) = client.database(&db).run_command(explain).await {
+                        if let Some(plan) = op_plan::summarize(&res) {
+                            query_stats.map_set_plan(&key, plan);
+                        }
+                    }
+                }
+                client.shutdown().await;
+            })
+        };
+
+        logs.push(format!(
+            "OPExec [{}] seed: {}",
+            chrono::Local::now().timestamp(),
+            self.seed
+        ));
+
         let mut created_thread_count = 0;
         loop {
             let dyn_threads_num = dyn_threads.get();
@@ -539,6 +925,7 @@ impl Mongobar {
             let boot_worker = boot_worker.clone();
             let logs = logs.clone();
             let query_stats = query_stats.clone();
+            let skip_count = skip_count.clone();
             let signal = Arc::clone(&signal);
             let done_worker = done_worker.clone();
             let dyn_cc_limit = dyn_cc_limit.clone();
@@ -549,6 +936,14 @@ impl Mongobar {
             let mode = mode.clone();
             let op_run_mode = op_run_mode.clone();
             let client = client_pool.get().await?;
+            let mut op_gen = op_gen::OpGen::new(self.seed.wrapping_add(thread_index as u64));
+            let mut backoff_rng =
+                StdRng::seed_from_u64(self.seed.rotate_left(32).wrapping_add(thread_index as u64));
+            let checkpoint = checkpoint.clone();
+            let explain_tx = explain_tx.clone();
+            let mut causal_rx = causal_receivers
+                .get_mut(thread_index)
+                .and_then(Option::take);
 
             handles.push(tokio::spawn(async move {
                 // println!("Thread[{}] [{}]\twait", i, chrono::Local::now().timestamp());
@@ -579,19 +974,62 @@ impl Mongobar {
                     }
                     let dyn_cc_limit_n = dyn_cc_limit.get();
                     if dyn_cc_limit_n > 0 && querying.get() >= dyn_cc_limit_n {
-                        let rand = rand::random::<u64>() % 100;
+                        let rand = backoff_rng.gen_range(0..100);
                         tokio::time::sleep(tokio::time::Duration::from_millis(rand)).await;
                         continue;
                     }
                     let mut row_index = 0;
-                    while let Some(row) = op_rows.read(thread_index, row_index) {
+                    loop {
+                        // 分配的行执行完之前一直持有，drop 后通知分配的任务
+                        let mut _in_flight = None;
+                        let next = match &mut causal_rx {
+                            Some(rx) => rx.recv().await.map(|(in_flight, done, row)| {
+                                _in_flight = Some(in_flight);
+                                (done, row)
+                            }),
+                            None if dispatched => None,
+                            None => op_checkpoint::read_row(&checkpoint, || {
+                                op_rows.read(thread_index, row_index)
+                            }),
+                        };
+                        let Some((done, mut row)) = next else {
+                            break;
+                        };
                         if signal.get() != 0 {
+                            // 没有执行，不计入断点，队列中剩余的行也一样
+                            if let Some(done) = done {
+                                done.cancel();
+                            }
+                            if let Some(rx) = &mut causal_rx {
+                                rx.close();
+                                while let Ok((_, Some(done), _)) = rx.try_recv() {
+                                    done.cancel();
+                                }
+                            }
                             break;
                         }
                         // if progress.get() >= progress_total.get() {
                         //     break;
                         // }
                         progress.increment();
+                        if let OpRunMode::Readonly = op_run_mode {
+                            if !row.is_readonly() {
+                                skip_count.increment();
+                                row_index += 1;
+                                continue;
+                            }
+                        }
+                        if let Err(e) = op_gen.resolve(&mut row) {
+                            logs.push(format!(
+                                "OPExec [{}] [{}] gen err {}",
+                                chrono::Local::now().timestamp(),
+                                row.id,
+                                e
+                            ));
+                            skip_count.increment();
+                            row_index += 1;
+                            continue;
+                        }
                         querying.increment();
                         {
                             stack.lock().unwrap().insert(row.id.clone(), Instant::now());
@@ -636,7 +1074,7 @@ impl Mongobar {
                                 //     in_size.fetch_add(sum, Ordering::Relaxed);
                                 // }
                             }
-                            op_row::Op::Count => {
+                            op_row::Op::Count | op_row::Op::Distinct => {
                                 let db = client.database(&row.db);
 
                                 // println!("after cmd {:?}", cmd);
@@ -654,23 +1092,40 @@ impl Mongobar {
                                     ));
                                 }
                             }
-                            op_row::Op::Aggregate => {
+                            op_row::Op::Aggregate
+                            | op_row::Op::CountDocuments
+                            | op_row::Op::GeoNear
+                            | op_row::Op::Search => {
                                 let db = client.database(&row.db);
                                 // out_size.fetch_add(row.cmd.len(), Ordering::Relaxed);
-                                let get_document: Vec<Document> = row
-                                    .cmd
-                                    .get("pipeline")
-                                    .unwrap()
-                                    .as_array()
-                                    .unwrap()
-                                    .iter()
-                                    .map(|v| Document::deserialize(v).unwrap())
-                                    .collect();
+                                // .opb 中读取的 pipeline 已经是原生的 BSON
+                                let get_document: Option<Vec<Document>> = match row
+                                    .args
+                                    .get_array("pipeline")
+                                {
+                                    Ok(v) => Some(
+                                        v.iter().filter_map(|v| v.as_document().cloned()).collect(),
+                                    ),
+                                    Err(_) => {
+                                        row.cmd.get("pipeline").and_then(|v| v.as_array()).map(
+                                            |v| {
+                                                v.iter()
+                                                    .map(|v| Document::deserialize(v).unwrap())
+                                                    .collect()
+                                            },
+                                        )
+                                    }
+                                };
                                 let start = Instant::now();
-                                let res = db
-                                    .collection::<Document>(&row.coll)
-                                    .aggregate(get_document)
-                                    .await;
+                                let res = if let Some(get_document) = get_document {
+                                    db.collection::<Document>(&row.coll)
+                                        .aggregate(get_document)
+                                        .await
+                                        .map(|_| ())
+                                } else {
+                                    // 4.2 之前的 geoNear 命令
+                                    db.run_command(row.args).await.map(|_| ())
+                                };
                                 let end = start.elapsed();
                                 cost_ms.add(end.as_millis() as usize);
                                 query_count.increment();
@@ -737,6 +1192,7 @@ impl Mongobar {
                                                             let res = db
                                                                 .collection::<Document>(&row.coll)
                                                                 .update_many(q.clone(), u.clone())
+                                                                .upsert(upsert)
                                                                 .await;
                                                             if let Err(e) = &res {
                                                                 logs.push(format!(
@@ -747,10 +1203,21 @@ impl Mongobar {
                                                             ));
                                                             }
                                                         } else {
-                                                            let res = db
-                                                                .collection::<Document>(&row.coll)
-                                                                .update_one(q.clone(), u.clone())
-                                                                .await;
+                                                            let coll = db.collection::<Document>(&row.coll);
+                                                            let res = if u
+                                                                .keys()
+                                                                .next()
+                                                                .is_some_and(|k| k.starts_with('$'))
+                                                            {
+                                                                coll.update_one(q.clone(), u.clone())
+                                                                    .upsert(upsert)
+                                                                    .await
+                                                            } else {
+                                                                // 没有更新操作符时替换整个文档，还原快照时使用
+                                                                coll.replace_one(q.clone(), u.clone())
+                                                                    .upsert(upsert)
+                                                                    .await
+                                                            };
                                                             if let Err(e) = &res {
                                                                 logs.push(format!(
                                                                 "OPExec [{}] [{}] Update Err {}",
@@ -779,6 +1246,7 @@ impl Mongobar {
                                                     let res = db
                                                         .collection::<Document>(&row.coll)
                                                         .update_many(q.clone(), u.clone())
+                                                        .upsert(upsert)
                                                         .await;
                                                     if let Err(e) = &res {
                                                         logs.push(format!(
@@ -789,10 +1257,21 @@ impl Mongobar {
                                                         ));
                                                     }
                                                 } else {
-                                                    let res = db
-                                                        .collection::<Document>(&row.coll)
-                                                        .update_one(q.clone(), u.clone())
-                                                        .await;
+                                                    let coll = db.collection::<Document>(&row.coll);
+                                                    let res = if u
+                                                        .keys()
+                                                        .next()
+                                                        .is_some_and(|k| k.starts_with('$'))
+                                                    {
+                                                        coll.update_one(q.clone(), u.clone())
+                                                            .upsert(upsert)
+                                                            .await
+                                                    } else {
+                                                        // 没有更新操作符时替换整个文档，还原快照时使用
+                                                        coll.replace_one(q.clone(), u.clone())
+                                                            .upsert(upsert)
+                                                            .await
+                                                    };
                                                     if let Err(e) = &res {
                                                         logs.push(format!(
                                                             "OPExec [{}] [{}] Update Err {}",
@@ -914,17 +1393,37 @@ impl Mongobar {
                             op_row::Op::None => (),
                         }
 
-                        query_stats.map_add(
+                        let recorded_plan = row
+                            .profile
+                            .as_ref()
+                            .and_then(|p| p.plan_summary.as_ref());
+                        let first_seen = query_stats.map_add(
                             &row.key,
                             query_start.elapsed().as_millis() as usize,
                             &row.cmd,
                         );
+                        let explain = if first_seen && recorded_plan.is_some() {
+                            op_plan::explain_cmd(&row.op, &row.cmd)
+                        } else {
+                            None
+                        };
+                        if let Some(profile) = &row.profile {
+                            query_stats.map_add_recorded(&row.key, profile.millis, recorded_plan);
+                        }
+                        // 每个 key 首次出现时 explain 一次，在单独的任务中执行，不影响压测的耗时
+                        if let Some(explain) = explain {
+                            let _ = explain_tx.send((row.key.clone(), row.db.clone(), explain));
+                        }
                         querying.decrement();
                         {
                             stack.lock().unwrap().remove(&row.id);
                         }
                         row_index += 1;
                     }
+                    // 分配的行由分配任务负责循环，队列关闭即结束
+                    if dispatched {
+                        break;
+                    }
                 }
 
                 // println!("Thread[{}] [{}]\tend", i, chrono::Local::now().timestamp());
@@ -954,8 +1453,55 @@ impl Mongobar {
         // self.op_state.stress_start_ts = stress_start_time;
         // self.save_state();
 
-        for handle in handles {
-            handle.await?;
+        // 中断后等待正在执行的操作完成，超时的直接取消
+        let aborted =
+            crate::signal::join_all(handles, &signal, crate::signal::DRAIN_TIMEOUT, || {
+                if let Some(checkpoint) = &checkpoint {
+                    checkpoint.freeze();
+                }
+            })
+            .await?;
+        if aborted > 0 {
+            logs.push(format!(
+                "OPExec [{}] aborted {} workers after {}s",
+                chrono::Local::now().timestamp(),
+                aborted,
+                crate::signal::DRAIN_TIMEOUT.as_secs()
+            ));
+        }
+
+        // 等待排队的 explain 完成，中断时直接取消
+        drop(explain_tx);
+        if signal.get() != 0 {
+            explainer.abort();
+        }
+        let _ = explainer.await;
+
+        if let (Some(saver), Some((checkpoint, mut state))) = (checkpoint_saver, checkpoint_state) {
+            saver.abort();
+            let interrupted = signal.get() != 0;
+            op_checkpoint::save(
+                &mut state,
+                &self.op_state_file,
+                checkpoint.index(),
+                (query_count.get(), cost_ms.get(), skip_count.get()),
+                !interrupted,
+            )?;
+            if interrupted {
+                logs.push(format!(
+                    "OPExec [{}] interrupted at row {}, use --continue to resume",
+                    chrono::Local::now().timestamp(),
+                    checkpoint.index()
+                ));
+            }
+        }
+
+        if skip_count.get() > 0 {
+            logs.push(format!(
+                "OPExec [{}] readonly skipped {} write ops",
+                chrono::Local::now().timestamp(),
+                skip_count.get()
+            ));
         }
 
         // let stress_end_time = chrono::Local::now().timestamp();
@@ -1021,7 +1567,9 @@ impl Mongobar {
             OpReadMode::StreamLine,
             self.ignore_field.clone(),
         )
-        .init();
+        .set_ns_map(self.ns_map.clone())
+        .init()?;
+        let mut writer = op_file::OpWriter::append(&self.op_file_revert)?;
 
         while let Some(op_row) = op_logs.read(0, 0) {
             match op_row.op {
@@ -1030,6 +1578,10 @@ impl Mongobar {
                 op_row::Op::Aggregate => (),
                 op_row::Op::Find => (),
                 op_row::Op::Count => (),
+                op_row::Op::Distinct => (),
+                op_row::Op::CountDocuments => (),
+                op_row::Op::GeoNear => (),
+                op_row::Op::Search => (),
                 op_row::Op::Command => (),
                 op_row::Op::Insert => {
                     let cmd = op_row.cmd.clone();
@@ -1056,6 +1608,7 @@ impl Mongobar {
                         id: op_row.id.clone(),
                         ns: op_row.ns.clone(),
                         ts: op_row.ts,
+                        profile: None,
                         op: op_row::Op::Delete,
                         db: op_row.db.clone(),
                         coll: op_row.coll.clone(),
@@ -1064,7 +1617,7 @@ impl Mongobar {
                         key: String::new(),
                         hash: String::new(),
                     };
-                    OpLogs::push_line(self.op_file_revert.clone(), re_row);
+                    writer.write(&re_row)?;
                 }
                 op_row::Op::Update => {
                     //     let cmd = op_row.cmd.clone();
@@ -1216,28 +1769,37 @@ impl Mongobar {
             }
         }
 
+        writer.finish()?;
+
         reverse_file(self.op_file_revert.to_str().unwrap()).unwrap();
 
         Ok(())
     }
 
+    /// 压测前的快照：收集 oplogs 中所有写操作可能修改的文档（按 _id），生成还原到当前状态的 resume.op
+    ///
+    /// 还原逻辑：
+    ///   insert => 当前不存在的 _id 还原时删除，已经存在的还原为当前的文档
+    ///   update/delete/findAndModify => 把 q 当作 find 执行，还原时整个替换为当前的文档（upsert，被删除的会重新插入）
+    ///   upsert => q 中直接指定了 _id 并且当前不存在的，还原时删除
+    ///
+    /// 同一个文档只记录第一次的快照，upsert 创建的没有指定 _id 的文档无法追踪
     pub async fn op_resume(&self) -> Result<(), anyhow::Error> {
-        // self.op_exec(1, OpReadMode::StreamLine, OpRunMode::ReadWrite)
-        //     .await?;
         let client: Client = Client::with_uri_str(self.config.uri.clone()).await?;
+        let logs = self.indicator.take("logs").unwrap();
 
         let op_logs = op_logs::OpLogs::new(
             self.op_file_oplogs.clone(),
             OpReadMode::StreamLine,
             self.ignore_field.clone(),
         )
-        .init();
+        .set_ns_map(self.ns_map.clone())
+        .init()?;
 
         if self.op_file_resume.exists() {
             if self.config.rebuild.unwrap_or_default() {
                 tokio::fs::remove_file(self.op_file_resume.clone()).await?;
             } else {
-                let logs = self.indicator.take("logs").unwrap();
                 logs.push(format!(
                     "OPResume [{}] [{}] file exists",
                     chrono::Local::now().timestamp(),
@@ -1247,215 +1809,144 @@ impl Mongobar {
             }
         }
 
-        let mut file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(self.op_file_resume.clone())
-            .await?;
-
+        let mut writer = op_file::OpWriter::append(&self.op_file_resume)?;
+        let mut touched = op_snapshot::Touched::default();
+        let mut baseline = op_verify::Baseline {
+            created_at: chrono::Local::now().timestamp_millis(),
+            ..Default::default()
+        };
+        // 每个匹配到的文档直接写入文件，不在内存中缓存
+        let mut snapshot =
+            |op_row: &op_row::OpRow, id: Bson, pre_image: Option<Document>| -> anyhow::Result<()> {
+                if touched.insert(&op_row.ns, &id) {
+                    baseline.add(&op_row.db, &op_row.coll, &id, pre_image.as_ref());
+                    writer.write(&op_snapshot::restore_row(op_row, &id, pre_image))?;
+                }
+                Ok(())
+            };
+        // 所有写过的集合都记录文档数量，没有匹配到文档的 upsert、没有 _id 的 insert 也算
+        let mut written = BTreeSet::new();
         while let Some(op_row) = op_logs.read(0, 0) {
+            if !op_row.is_readonly() {
+                written.insert((op_row.db.clone(), op_row.coll.clone()));
+            }
+            let coll = client
+                .database(&op_row.db)
+                .collection::<Document>(&op_row.coll);
             match op_row.op {
-                op_row::Op::None => (),
-                op_row::Op::GetMore => (),
-                op_row::Op::Aggregate => (),
-                op_row::Op::Find => (),
-                op_row::Op::Count => (),
-                op_row::Op::Command => (),
                 op_row::Op::Insert => {
-                    let cmd = op_row.cmd.clone();
-                    let values = cmd
-                        .get("documents")
-                        .map(|v| v.as_array().unwrap())
-                        .unwrap()
-                        .iter()
-                        .collect::<Vec<&Value>>();
-
-                    for val in values {
-                        let _id = val.get("_id").unwrap();
-                        let doc = Document::deserialize(val).unwrap();
-                        let mut res = client
-                            .database(&op_row.db)
-                            .collection::<Document>(&op_row.coll)
-                            .find(doc! {
-                                "_id": doc.get_object_id("_id").unwrap()
-                            })
-                            .await?;
-
-                        if let Some(doc) = res.try_next().await? {
-                            // 如果当前有则更新一下
-                            let re_row = op_row::OpRow {
-                                id: op_row.id.clone(),
-                                ns: op_row.ns.clone(),
-                                ts: op_row.ts,
-                                op: op_row::Op::Update,
-                                db: op_row.db.clone(),
-                                coll: op_row.coll.clone(),
-                                cmd: json!({
-                                    "updates": [
-                                        {
-                                            "q": {
-                                                "_id": _id
-                                            },
-                                            "u": {
-                                                "$set": doc
-                                            },
-                                            "multi": false,
-                                            "upsert": false,
-                                        }
-                                    ],
-                                }),
-                                args: doc! {},
-                                key: String::new(),
-                                hash: String::new(),
-                            };
-
-                            let content = serde_json::to_string(&re_row).unwrap();
-                            file.write_all(content.as_bytes()).await?;
-                            file.write_all(b"\n").await?;
-                        } else {
-                            // 如果查到当前没有就删除掉
-                            let re_row = op_row::OpRow {
-                                id: op_row.id.clone(),
-                                ns: op_row.ns.clone(),
-                                ts: op_row.ts,
-                                op: op_row::Op::Delete,
-                                db: op_row.db.clone(),
-                                coll: op_row.coll.clone(),
-                                cmd: json!({
-                                    "deletes": [
-                                        {
-                                            "q": {
-                                                "_id": _id
-                                            },
-                                            "limit": 0
-                                        }
-                                    ],
-                                }),
-                                args: doc! {},
-                                key: String::new(),
-                                hash: String::new(),
-                            };
-                            let content = serde_json::to_string(&re_row).unwrap();
-                            file.write_all(content.as_bytes()).await?;
-                            file.write_all(b"\n").await?;
-                        }
+                    let ids = op_snapshot::insert_ids(&op_row);
+                    if ids.is_empty() {
+                        continue;
+                    }
+                    let mut res = coll.find(doc! { "_id": { "$in": ids.clone() } }).await?;
+                    let mut exists = vec![];
+                    while let Some(doc) = res.try_next().await? {
+                        exists.push(doc);
+                    }
+                    for id in ids {
+                        let pre_image = exists.iter().find(|d| d.get("_id") == Some(&id)).cloned();
+                        snapshot(&op_row, id, pre_image)?;
                     }
                 }
-                op_row::Op::Update => {
-                    let cmd = op_row.cmd.clone();
-                    let qs: Vec<Document> = cmd
-                        .get("updates")
-                        .unwrap_or(&Value::Array(vec![cmd.clone()]))
-                        .as_array()
-                        .unwrap()
-                        .iter()
-                        .map(|v| {
-                            let q = v.get("q").unwrap();
-                            Document::deserialize(q).unwrap()
-                        })
-                        .collect();
-
-                    for q in qs {
-                        let mut res = client
-                            .database(&op_row.db)
-                            .collection::<Document>(&op_row.coll)
-                            .find(q.clone())
-                            .await?;
-
+                op_row::Op::Update | op_row::Op::Delete | op_row::Op::FindAndModify => {
+                    for (q, upsert) in op_snapshot::write_filters(&op_row) {
+                        let mut found = false;
+                        let mut res = coll.find(q.clone()).await?;
                         while let Some(doc) = res.try_next().await? {
-                            let doc = doc.clone();
-                            let re_row = op_row::OpRow {
-                                id: op_row.id.clone(),
-                                ns: op_row.ns.clone(),
-                                ts: op_row.ts,
-                                op: op_row::Op::Update,
-                                db: op_row.db.clone(),
-                                coll: op_row.coll.clone(),
-                                cmd: json!({
-                                    "updates": [
-                                        {
-                                            "q": {
-                                                "_id": doc.get_object_id("_id").unwrap()
-                                            },
-                                            "u": {
-                                                "$set": doc
-                                            },
-                                            "multi": q.get_bool("multi").unwrap_or_default(),
-                                            "upsert": q.get_bool("upsert").unwrap_or_default()
-                                        }
-                                    ],
-                                }),
-                                args: doc! {},
-                                key: String::new(),
-                                hash: String::new(),
-                            };
-
-                            let content = serde_json::to_string(&re_row).unwrap();
-                            file.write_all(content.as_bytes()).await?;
-                            file.write_all(b"\n").await?;
+                            found = true;
+                            if let Some(id) = doc.get("_id").cloned() {
+                                snapshot(&op_row, id, Some(doc))?;
+                            }
+                        }
+                        if !found && upsert {
+                            if let Some(id) = op_snapshot::upsert_id(&q) {
+                                snapshot(&op_row, id, None)?;
+                            }
                         }
                     }
                 }
-                op_row::Op::Delete => {}
-                op_row::Op::FindAndModify => {
-                    // println!("{:?}", op_row);
+                _ => {}
+            }
+        }
+        writer.finish()?;
 
-                    let remove = op_row
-                        .cmd
-                        .get("remove")
-                        .unwrap()
-                        .as_bool()
-                        .unwrap_or_default();
+        for (db, coll) in written.iter() {
+            baseline.touch(db, coll);
+        }
+        for ns in baseline.namespaces.values_mut() {
+            ns.count = client
+                .database(&ns.db)
+                .collection::<Document>(&ns.coll)
+                .count_documents(doc! {})
+                .await?;
+        }
+        baseline.save(&self.op_file_baseline)?;
 
-                    if !remove {
-                        // 更新的情况
-                        let query = op_row.cmd.get("query").unwrap();
-                        let query = Document::deserialize(query).unwrap();
+        op_header::stamp(
+            &self.op_file_resume,
+            op_header::OpHeader::new("snapshot", &self.config.db, &self.config.uri),
+        )?;
+        logs.push(format!(
+            "OPResume [{}] snapshot {} documents",
+            chrono::Local::now().timestamp(),
+            touched.count()
+        ));
 
-                        let mut res = client
-                            .database(&op_row.db)
-                            .collection::<Document>(&op_row.coll)
-                            .find(query.clone())
-                            .await?;
+        Ok(())
+    }
 
-                        while let Some(doc) = res.try_next().await? {
-                            let re_row = op_row::OpRow {
-                                id: op_row.id.clone(),
-                                ns: op_row.ns.clone(),
-                                ts: op_row.ts,
-                                op: op_row::Op::Update,
-                                db: op_row.db.clone(),
-                                coll: op_row.coll.clone(),
-                                cmd: json!({
-                                    "updates": [
-                                        {
-                                            "q": {
-                                                "_id": doc.get("_id")
-                                            },
-                                            "u": {
-                                                "$set": doc
-                                            },
-                                            "multi": false,
-                                            "upsert": false
-                                        }
-                                    ],
-                                }),
-                                args: doc! {},
-                                key: String::new(),
-                                hash: String::new(),
-                            };
-
-                            let content = serde_json::to_string(&re_row).unwrap();
-                            file.write_all(content.as_bytes()).await?;
-                            file.write_all(b"\n").await?;
-                        }
+    /// 对比 baseline.json 和当前数据库，校验恢复后是否和压测前一致
+    ///
+    /// 每个集合比较文档数量，快照中的每个文档比较内容的 hash
+    pub async fn op_verify(&self) -> Result<Vec<op_verify::NsReport>, anyhow::Error> {
+        if !self.op_file_baseline.exists() {
+            return Err(anyhow::anyhow!(
+                "{} not exists, please run op-build-resume before the stress test first",
+                self.op_file_baseline.display()
+            ));
+        }
+        let baseline = op_verify::Baseline::load(&self.op_file_baseline)?;
+        let client: Client = Client::with_uri_str(self.config.uri.clone()).await?;
+
+        let mut reports = vec![];
+        for (ns, expected) in baseline.namespaces.iter() {
+            let coll = client
+                .database(&expected.db)
+                .collection::<Document>(&expected.coll);
+            let mut report = op_verify::NsReport {
+                ns: ns.clone(),
+                expected_count: expected.count,
+                live_count: coll.count_documents(doc! {}).await?,
+                diffs: vec![],
+            };
+
+            let keys: Vec<&String> = expected.docs.keys().collect();
+            for chunk in keys.chunks(1000) {
+                let ids = chunk
+                    .iter()
+                    .map(|key| op_verify::id_from_key(key))
+                    .collect::<anyhow::Result<Vec<Bson>>>()?;
+                let mut live: HashMap<String, Document> = HashMap::new();
+                let mut res = coll.find(doc! { "_id": { "$in": ids } }).await?;
+                while let Some(doc) = res.try_next().await? {
+                    if let Some(id) = doc.get("_id") {
+                        live.insert(op_verify::id_key(id), doc);
+                    }
+                }
+                for key in chunk.iter() {
+                    let diff = op_verify::DocDiff::compare(
+                        expected.docs.get(*key).unwrap().as_ref(),
+                        live.get(*key),
+                    );
+                    if let Some(diff) = diff {
+                        report.diffs.push(((*key).clone(), diff));
                     }
                 }
             }
+            reports.push(report);
         }
-
-        Ok(())
+        Ok(reports)
     }
 
     /// 回放压测文件
@@ -1566,173 +2057,173 @@ impl Mongobar {
     }
 
     /// 将线上相关的数据拉取到本地文件
-    pub async fn op_export(&self) -> Result<(), anyhow::Error> {
+    ///
+    /// 导出 oplogs 中写操作涉及的文档：insert 按照 _id，update/delete/findAndModify 按照查询条件，
+    /// 同一个文档（ns + _id）只导出一次，最多 thread_count 行并发查询。
+    /// dump 为 None 时导出为 data.op，否则导出为 mongodump 格式的目录（包含索引）。
+    /// 进度保存在 state.json 中，中断后再次导出到相同的位置会从上次完成的行继续，rebuild 时重新开始
+    pub async fn op_export(&mut self, dump: Option<PathBuf>) -> Result<(), anyhow::Error> {
         let instant = Instant::now();
-        let _ = fs::remove_file(&self.op_file_data);
-        let client = Arc::new(Client::with_uri_str(self.config.uri.clone()).await?);
-
-        let op_logs = Arc::new(
-            op_logs::OpLogs::new(
-                self.op_file_oplogs.clone(),
-                OpReadMode::StreamLine,
-                self.ignore_field.clone(),
-            )
-            .init(),
-        );
-        let mut tasks = vec![];
+        let client = Client::with_uri_str(self.config.uri.clone()).await?;
 
-        let op_file = OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(self.op_file_data.clone())
-            .await
-            .unwrap();
+        let export_target = dump
+            .as_ref()
+            .unwrap_or(&self.op_file_data)
+            .to_str()
+            .unwrap()
+            .to_string();
+        if self.op_state.export_target != export_target || self.config.rebuild.unwrap_or_default()
+        {
+            self.op_state.export_target = export_target;
+            self.op_state.export_index = 0;
+        }
+        let start = self.op_state.export_index;
+        if start > 0 {
+            println!(
+                "OPExport [{}] continue from row {}",
+                chrono::Local::now().timestamp(),
+                start
+            );
+        }
+        let exporter = Arc::new(std::sync::Mutex::new(op_export::Exporter::open(
+            &self.op_file_data,
+            dump.as_deref(),
+            self.op_state.clone(),
+            self.op_state_file.clone(),
+        )?));
 
-        let op_file = Arc::new(tokio::sync::Mutex::new(op_file));
+        let op_logs = op_logs::OpLogs::new(
+            self.op_file_oplogs.clone(),
+            OpReadMode::StreamLine,
+            self.ignore_field.clone(),
+        )
+        .set_ns_map(self.ns_map.clone())
+        .init()?;
+        for _ in 0..start {
+            op_logs.read(0, 0);
+        }
+        // 读取和行号需要一起加锁，保证行号和文件中的顺序一致
+        let source = Arc::new(std::sync::Mutex::new((op_logs, start)));
+        let failed = Arc::new(std::sync::atomic::AtomicBool::new(false));
 
-        for _ in 0..1000 {
-            let client = Arc::clone(&client);
-            let op_logs = Arc::clone(&op_logs);
-            let op_file = Arc::clone(&op_file);
-            // let op_file_data = self.op_file_data.clone();
+        let mut tasks = vec![];
+        for _ in 0..self.config.thread_count.max(1) {
+            let client = client.clone();
+            let source = Arc::clone(&source);
+            let exporter = Arc::clone(&exporter);
+            let failed = Arc::clone(&failed);
             let task = tokio::spawn(async move {
-                while let Some(op_row) = op_logs.read(0, 0) {
-                    match op_row.op {
-                        op_row::Op::None => (),
-                        op_row::Op::GetMore => (),
-                        op_row::Op::Aggregate => (),
-                        op_row::Op::Find => {}
-                        op_row::Op::Count => {}
-                        op_row::Op::Insert => {}
-                        op_row::Op::Delete => {}
-                        op_row::Op::Command => {}
-                        op_row::Op::Update => {
-                            let cmd = op_row.cmd.clone();
-                            let qs: Vec<Document> = cmd
-                                .get("updates")
-                                .unwrap()
-                                .as_array()
-                                .unwrap()
-                                .iter()
-                                .map(|v| {
-                                    let q = v.get("q").unwrap();
-                                    Document::deserialize(q).unwrap()
-                                })
-                                .collect();
-
-                            for q in qs {
-                                let res = client
-                                    .database(&op_row.db)
-                                    .collection::<Document>(&op_row.coll)
-                                    .find(q.clone())
-                                    .await;
-                                if let Ok(mut res) = res {
-                                    while let Some(doc) = res.try_next().await.unwrap() {
-                                        let doc = doc.clone();
-                                        let re_row = op_row::OpRow {
-                                            id: op_row.id.clone(),
-                                            ns: op_row.ns.clone(),
-                                            ts: op_row.ts,
-                                            op: op_row::Op::Insert,
-                                            db: op_row.db.clone(),
-                                            coll: op_row.coll.clone(),
-                                            cmd: json!({
-                                                "documents": [doc]
-                                            }),
-                                            args: doc! {},
-                                            key: String::new(),
-                                            hash: String::new(),
-                                        };
-
-                                        // OpLogs::push_line(op_file_data.clone(), re_row);
-                                        let mut op_file = op_file.lock().await;
-
-                                        op_file
-                                            .write_all(
-                                                format!(
-                                                    "{}\n",
-                                                    serde_json::to_string(&re_row).unwrap()
-                                                )
-                                                .as_bytes(),
-                                            )
-                                            .await
-                                            .unwrap();
-                                    }
-                                }
+                while !failed.load(Ordering::Relaxed) {
+                    let (index, op_row) = {
+                        let mut source = source.lock().unwrap();
+                        match source.0.read(0, 0) {
+                            Some(op_row) => {
+                                source.1 += 1;
+                                (source.1 - 1, op_row)
                             }
+                            None => break,
                         }
-                        op_row::Op::FindAndModify => {
-                            let remove = op_row
-                                .cmd
-                                .get("remove")
-                                .unwrap()
-                                .as_bool()
-                                .unwrap_or_default();
-
-                            if !remove {
-                                // 更新的情况
-                                let query = op_row.cmd.get("query").unwrap();
-                                let query = Document::deserialize(query).unwrap();
-
-                                let res = client
-                                    .database(&op_row.db)
-                                    .collection::<Document>(&op_row.coll)
-                                    .find(query.clone())
-                                    .await;
-
-                                if let Ok(mut res) = res {
-                                    while let Some(doc) = res.try_next().await.unwrap() {
-                                        let re_row = op_row::OpRow {
-                                            id: op_row.id.clone(),
-                                            ns: op_row.ns.clone(),
-                                            ts: op_row.ts,
-                                            op: op_row::Op::Insert,
-                                            db: op_row.db.clone(),
-                                            coll: op_row.coll.clone(),
-                                            cmd: json!({
-                                                "documents": [doc]
-                                            }),
-                                            args: doc! {},
-                                            key: String::new(),
-                                            hash: String::new(),
-                                        };
-
-                                        // OpLogs::push_line(op_file_data.clone(), re_row);
-                                        let mut op_file = op_file.lock().await;
-                                        op_file
-                                            .write_all(
-                                                format!(
-                                                    "{}\n",
-                                                    serde_json::to_string(&re_row).unwrap()
-                                                )
-                                                .as_bytes(),
-                                            )
-                                            .await
-                                            .unwrap();
-                                    }
-                                }
+                    };
+                    let res: Result<(), anyhow::Error> = async {
+                        let coll = client
+                            .database(&op_row.db)
+                            .collection::<Document>(&op_row.coll);
+                        for q in op_export::export_filters(&op_row) {
+                            let mut cursor = coll.find(q).await?;
+                            while let Some(doc) = cursor.try_next().await? {
+                                exporter.lock().unwrap().write(&op_row, doc)?;
                             }
                         }
+                        exporter.lock().unwrap().done(index)
+                    }
+                    .await;
+                    if let Err(err) = res {
+                        failed.store(true, Ordering::Relaxed);
+                        return Err(anyhow::anyhow!(
+                            "OPExport [{}] row {} error: {}",
+                            op_row.id,
+                            index,
+                            err
+                        ));
                     }
                 }
+                Ok(())
             });
             tasks.push(task);
         }
 
+        let mut error = None;
         for task in tasks {
-            task.await?;
+            if let Err(err) = task.await? {
+                error.get_or_insert(err);
+            }
+        }
+
+        let exporter = Arc::into_inner(exporter).unwrap().into_inner().unwrap();
+        if let Some(err) = error {
+            self.op_state = exporter.abort()?;
+            println!(
+                "OPExport [{}] interrupted at row {}, run again to continue",
+                chrono::Local::now().timestamp(),
+                self.op_state.export_index
+            );
+            return Err(err);
         }
+        let docs = exporter.docs;
+        let (state, counts) = exporter.finish()?;
+        self.op_state = state;
+        self.save_state();
 
-        println!("cost {:?}", instant.elapsed());
+        if let Some(dir) = dump {
+            for dump_coll in op_dump::scan(&dir)? {
+                let res = client
+                    .database(&dump_coll.db)
+                    .run_command(doc! { "listIndexes": &dump_coll.coll })
+                    .await?;
+                let indexes: Vec<Document> = res
+                    .get_document("cursor")?
+                    .get_array("firstBatch")?
+                    .iter()
+                    .filter_map(|index| index.as_document().cloned())
+                    .collect();
+                let ns = dump_coll.ns();
+                println!(
+                    "OPExport [{}] [{}] docs: {}, indexes: {}",
+                    chrono::Local::now().timestamp(),
+                    ns,
+                    counts
+                        .iter()
+                        .find(|(n, _)| *n == ns)
+                        .map(|(_, count)| *count)
+                        .unwrap_or_default(),
+                    indexes.len()
+                );
+                op_dump::write_metadata(&dir, &dump_coll.db, &dump_coll.coll, indexes)?;
+            }
+        }
 
-        // println!("waiting...");
-        // reverse_file(self.op_file_data.to_str().unwrap()).unwrap();
+        println!("docs {} cost {:?}", docs, instant.elapsed());
 
         Ok(())
     }
 
     /// 将本地文件导入到连接的数据库
-    pub async fn op_import(&self) -> Result<(), anyhow::Error> {
+    ///
+    /// dump 为 mongodump 格式的目录，不指定时 data.op 不存在并且有 op_dir_dump 则使用 op_dir_dump
+    pub async fn op_import(
+        &self,
+        dump: Option<PathBuf>,
+        thread_count: usize,
+        batch_size: usize,
+    ) -> Result<(), anyhow::Error> {
+        let dump = dump.or_else(|| {
+            (!self.op_file_data.exists() && self.op_dir_dump.exists())
+                .then(|| self.op_dir_dump.clone())
+        });
+        if let Some(dir) = dump {
+            return self.op_import_dump(dir, thread_count, batch_size).await;
+        }
+
         self.op_exec(
             self.op_file_data.clone(),
             1,
@@ -1745,8 +2236,127 @@ impl Mongobar {
         Ok(())
     }
 
-    pub fn save_as(&self, outdir: &String, force: bool) -> Result<String, anyhow::Error> {
-        let outfile = PathBuf::from(outdir).join(self.name.clone() + ".op");
+    /// 导入 mongodump 格式的目录：每个批次一个无序的 insert_many，最多 thread_count 个批次并发，
+    /// 已经存在的文档（duplicate key）跳过，最后按照 metadata 创建索引
+    async fn op_import_dump(
+        &self,
+        dir: PathBuf,
+        thread_count: usize,
+        batch_size: usize,
+    ) -> Result<(), anyhow::Error> {
+        let colls = op_dump::scan(&dir)?;
+        if self.dry_run {
+            for dump_coll in colls.iter() {
+                let names: Vec<String> = dump_coll
+                    .indexes()?
+                    .iter()
+                    .map(|index| index.get_str("name").unwrap_or_default().to_string())
+                    .collect();
+                println!(
+                    "DryRun [{}] [{}] Insert: {} Create index: [{}]",
+                    dir.to_str().unwrap(),
+                    dump_coll.ns(),
+                    dump_coll.docs()?.count(),
+                    names.join(", ")
+                );
+            }
+            return Ok(());
+        }
+
+        let options = ClientOptions::parse(&self.config.uri).await?;
+        let seeds = options.hosts.iter().map(|h| h.to_string()).collect();
+        let client = Client::with_options(options)?;
+        op_guard::guard(
+            &client,
+            seeds,
+            &self.config.protected,
+            self.allow_prod,
+            "write",
+        )
+        .await?;
+
+        let semaphore = Arc::new(tokio::sync::Semaphore::new(thread_count.max(1)));
+        let mut pending = vec![];
+        for dump_coll in colls {
+            let coll = client
+                .database(&dump_coll.db)
+                .collection::<Document>(&dump_coll.coll);
+            let inserted = Arc::new(AtomicUsize::new(0));
+            let duplicated = Arc::new(AtomicUsize::new(0));
+            let mut tasks = vec![];
+            let mut docs = dump_coll.docs()?;
+            loop {
+                let batch = docs
+                    .by_ref()
+                    .take(batch_size.max(1))
+                    .collect::<Result<Vec<Document>, _>>()?;
+                if batch.is_empty() {
+                    break;
+                }
+                let permit = Arc::clone(&semaphore).acquire_owned().await?;
+                let coll = coll.clone();
+                let inserted = Arc::clone(&inserted);
+                let duplicated = Arc::clone(&duplicated);
+                tasks.push(tokio::spawn(async move {
+                    let _permit = permit;
+                    let n = batch.len();
+                    match coll.insert_many(batch).ordered(false).await {
+                        Ok(_) => inserted.fetch_add(n, Ordering::Relaxed),
+                        Err(err) => match *err.kind {
+                            ErrorKind::InsertMany(InsertManyError {
+                                write_errors: Some(ref errors),
+                                write_concern_error: None,
+                                ..
+                            }) if errors.iter().all(|e| e.code == 11000) => {
+                                duplicated.fetch_add(errors.len(), Ordering::Relaxed);
+                                inserted.fetch_add(n - errors.len(), Ordering::Relaxed)
+                            }
+                            _ => return Err(anyhow::Error::from(err)),
+                        },
+                    };
+                    Ok(())
+                }));
+            }
+            pending.push((dump_coll, inserted, duplicated, tasks));
+        }
+
+        for (dump_coll, inserted, duplicated, tasks) in pending {
+            for task in tasks {
+                task.await??;
+            }
+            let indexes = dump_coll.indexes()?;
+            if !indexes.is_empty() {
+                client
+                    .database(&dump_coll.db)
+                    .run_command(doc! { "createIndexes": &dump_coll.coll, "indexes": indexes.clone() })
+                    .await?;
+            }
+            println!(
+                "OPImport [{}] [{}] inserted: {}, duplicate: {}, indexes: {}",
+                chrono::Local::now().timestamp(),
+                dump_coll.ns(),
+                inserted.load(Ordering::Relaxed),
+                duplicated.load(Ordering::Relaxed),
+                indexes.len()
+            );
+        }
+
+        Ok(())
+    }
+
+    pub fn save_as(
+        &self,
+        outdir: &String,
+        force: bool,
+        compress: Option<op_file::OpCompression>,
+    ) -> Result<String, anyhow::Error> {
+        let source_compression = op_file::OpCompression::from_path(&self.op_file_oplogs);
+        let compression = compress.unwrap_or(source_compression);
+        let outfile = PathBuf::from(outdir).join(
+            self.name.clone()
+                + op_file::OpFormat::from_path(&self.op_file_oplogs).ext()
+                + compression.suffix(),
+        );
 
         if force {
             let _ = fs::remove_file(&outfile);
@@ -1759,10 +2369,14 @@ impl Mongobar {
             ));
         }
 
-        std::fs::copy(
-            self.op_file_oplogs.to_str().unwrap(),
-            outfile.to_str().unwrap(),
-        )?;
+        if compression == source_compression {
+            std::fs::copy(
+                self.op_file_oplogs.to_str().unwrap(),
+                outfile.to_str().unwrap(),
+            )?;
+        } else {
+            op_file::transcode(&self.op_file_oplogs, &outfile)?;
+        }
 
         return Ok(outfile.to_str().unwrap().to_string());
     }
@@ -1773,15 +2387,34 @@ impl Mongobar {
 
     pub fn report(&self) -> Result<PathBuf, anyhow::Error> {
         let m = self.indicator.take("query_stats").unwrap();
-        let csv_file = self.op_workdir.join("query_stats.csv");
+        // 中断时只有部分结果，单独保存，避免和完整的报告混淆
+        let interrupted = self.signal.get() == crate::signal::INTERRUPTED;
+        let csv_file = self.op_workdir.join(if interrupted {
+            "query_stats.interrupted.csv"
+        } else {
+            "query_stats.csv"
+        });
         if csv_file.exists() {
             let _ = fs::remove_file(&csv_file);
         }
         let mut wtr = csv::Writer::from_path(&csv_file).unwrap();
-        wtr.write_record(&["Key", "AvgCost(ms)", "MidCost(ms)", "Count", "Eg"])
-            .unwrap();
+        wtr.write_record(&[
+            "Key",
+            "AvgCost(ms)",
+            "MidCost(ms)",
+            "Count",
+            "RecordedAvgCost(ms)",
+            "RecordedPlan",
+            "ReplayedPlan",
+            "PlanChanged",
+            "Eg",
+        ])
+        .unwrap();
         for k in m.map_keys().iter() {
             let v = m.map_get(k).unwrap();
+            let recorded_count = v.recorded_count.load(std::sync::atomic::Ordering::Relaxed);
+            let recorded_plan = v.recorded_plan.clone().unwrap_or_default();
+            let replayed_plan = v.replayed_plan.clone().unwrap_or_default();
             wtr.write_record(&[
                 k,
                 &format!(
@@ -1791,6 +2424,23 @@ impl Mongobar {
                 ),
                 &format!("{:.2}", v.middle.median()),
                 &format!("{}", v.count.load(std::sync::atomic::Ordering::Relaxed)),
+                &if recorded_count > 0 {
+                    format!(
+                        "{:.2}",
+                        v.recorded_sum.load(std::sync::atomic::Ordering::Relaxed) as f64
+                            / recorded_count as f64
+                    )
+                } else {
+                    String::new()
+                },
+                &recorded_plan,
+                &replayed_plan,
+                &format!(
+                    "{}",
+                    !recorded_plan.is_empty()
+                        && !replayed_plan.is_empty()
+                        && op_plan::plan_changed(&recorded_plan, &replayed_plan)
+                ),
                 &format!("{}", v.egs.join("|")),
             ])
             .unwrap();
@@ -1798,10 +2448,41 @@ impl Mongobar {
 
         wtr.flush().unwrap();
 
-        self.indicator
-            .take("logs")
-            .unwrap()
-            .push(format!("Build Report {:?}.", csv_file.to_str().unwrap()));
+        // 记录回放的 op 文件来源，测试结果可以追溯到对应的录制
+        if let Some(header) = op_header::read(&self.op_file_oplogs) {
+            let sidecar = op_header::write_sidecar(&header, &csv_file)?;
+            self.indicator.take("logs").unwrap().push(format!(
+                "Report source {} ({}).",
+                header.summary(),
+                sidecar.to_str().unwrap()
+            ));
+        }
+
+        // 记录随机数种子和执行的参数，`--seed` 可以重新执行相同的分配
+        let run_file = csv_file.with_extension("run.json");
+        fs::write(
+            &run_file,
+            serde_json::to_string_pretty(&json!({
+                "seed": self.seed,
+                "seeded": self.seeded,
+                "thread_count": self.config.thread_count,
+                "loop_count": self.config.loop_count,
+                "causal": self.causal,
+                "shard_keys": self.shard_keys,
+            }))?,
+        )?;
+        self.indicator.take("logs").unwrap().push(format!(
+            "Report seed {} ({}).",
+            self.seed,
+            run_file.to_str().unwrap()
+        ));
+
+        self.indicator.take("logs").unwrap().push(format!(
+            "Build Report {:?}{}.",
+            csv_file.to_str().unwrap(),
+            if interrupted { " (interrupted)" } else { "" }
+        ));
+        self.indicator.flush();
         Ok(csv_file)
     }
 }
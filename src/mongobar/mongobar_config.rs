@@ -15,6 +15,11 @@ pub(crate) struct MongobarConfig {
     pub loop_count: usize,
 
     pub rebuild: Option<bool>,
+
+    /// 受保护的集群，host（支持 `*` 通配符，可以省略端口）或者副本集名称
+    /// 匹配时写操作和开启 profile 2 需要确认或者 `--i-know-this-is-prod`
+    #[serde(default)]
+    pub protected: Vec<String>,
 }
 
 impl MongobarConfig {
@@ -0,0 +1,351 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use bson::{Bson, Document};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::sync::mpsc::{error::SendError, Sender};
+use tokio::sync::Notify;
+
+use super::op_row::{Op, OpRow};
+use super::op_snapshot;
+
+/// 每个线程的队列长度
+pub static QUEUE_SIZE: usize = 1024;
+
+/// 回放时按照因果关系给线程分配行：同一个 key 的写操作分配给同一个线程，按照录制的顺序执行，不同的 key 并发执行
+///
+/// key 从写操作的条件中获取：ns + `_id`，没有 `_id` 时使用 `--shard-key` 指定的字段；
+/// 无法确定唯一文档的（多个 _id、按照其他字段批量修改）使用 ns，作为屏障由 [`Dispatcher`] 处理，
+/// 读操作按照 seed 随机分配
+pub struct CausalScheduler {
+    workers: usize,
+    /// 为 false 时只按照 seed 分配（`--seed` 没有 `--causal`）
+    causal: bool,
+    /// ns => 分片键字段
+    shard_keys: HashMap<String, Vec<String>>,
+    rng: StdRng,
+}
+
+impl CausalScheduler {
+    /// shard_keys 的格式为 `db.coll=field1,field2`
+    pub fn new(workers: usize, shard_keys: &[String], seed: u64) -> anyhow::Result<Self> {
+        let mut keys = HashMap::new();
+        for shard_key in shard_keys {
+            let (ns, fields) = shard_key
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("invalid shard key: {}", shard_key))?;
+            let fields: Vec<String> = fields
+                .split(',')
+                .map(|f| f.trim().to_string())
+                .filter(|f| !f.is_empty())
+                .collect();
+            if ns.is_empty() || fields.is_empty() {
+                return Err(anyhow::anyhow!("invalid shard key: {}", shard_key));
+            }
+            keys.insert(ns.to_string(), fields);
+        }
+        Ok(Self {
+            causal: true,
+            shard_keys: keys,
+            ..Self::seeded(workers, seed)
+        })
+    }
+
+    /// 不考虑因果关系，每一行按照 seed 随机分配，相同的 seed 分配的结果相同
+    pub fn seeded(workers: usize, seed: u64) -> Self {
+        Self {
+            workers: workers.max(1),
+            causal: false,
+            shard_keys: HashMap::new(),
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    /// 因果关系的 key，读操作返回 None
+    pub fn key(&self, row: &OpRow) -> Option<String> {
+        if row.is_readonly() {
+            return None;
+        }
+        let docs: Vec<Document> = match row.op {
+            Op::Insert => row
+                .cmd
+                .get("documents")
+                .and_then(Value::as_array)
+                .map(|documents| {
+                    documents
+                        .iter()
+                        .filter_map(|doc| Document::deserialize(doc).ok())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            _ => op_snapshot::write_filters(row)
+                .into_iter()
+                .map(|(q, _)| q)
+                .collect(),
+        };
+        let keys: BTreeSet<Option<String>> =
+            docs.iter().map(|doc| self.doc_key(row, doc)).collect();
+        match (keys.len(), keys.into_iter().next()) {
+            (1, Some(Some(key))) => Some(format!("{}:{}", row.ns, key)),
+            _ => Some(row.ns.clone()),
+        }
+    }
+
+    fn doc_key(&self, row: &OpRow, doc: &Document) -> Option<String> {
+        if let Some(id) = op_snapshot::upsert_id(doc) {
+            return Some(id.into_relaxed_extjson().to_string());
+        }
+        let fields = self.shard_keys.get(&row.ns)?;
+        let values = fields
+            .iter()
+            .map(|field| match doc.get(field)? {
+                Bson::Document(d) if d.keys().any(|k| k.starts_with('$')) => None,
+                v => Some(v.clone().into_relaxed_extjson().to_string()),
+            })
+            .collect::<Option<Vec<String>>>()?;
+        Some(values.join(","))
+    }
+
+    /// 分配的线程，相同的 key 总是分配给同一个线程
+    pub fn partition(&mut self, row: &OpRow) -> (usize, Order) {
+        let key = match self.causal {
+            true => self.key(row),
+            false => None,
+        };
+        match key {
+            Some(key) => {
+                let order = match key == row.ns {
+                    true => Order::Ns,
+                    false => Order::Doc,
+                };
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                ((hasher.finish() % self.workers as u64) as usize, order)
+            }
+            None => (self.rng.gen_range(0..self.workers), Order::Any),
+        }
+    }
+}
+
+/// 一行需要保证的顺序
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Order {
+    /// 读操作或者只按照 seed 分配
+    Any,
+    /// 同一个文档的写操作在同一个线程中按顺序执行
+    Doc,
+    /// 无法确定文档的写操作：等待之前的行全部执行完再分配，这个 ns 之后的写操作等待它执行完
+    Ns,
+}
+
+/// 已经分配还没有执行完的行数
+#[derive(Default)]
+struct Pending {
+    rows: AtomicUsize,
+    notify: Notify,
+}
+
+impl Pending {
+    /// 等待所有的行执行完
+    async fn idle(&self) {
+        loop {
+            let notified = self.notify.notified();
+            if self.rows.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// 和行一起发送给线程，执行完（drop）后通知 [`Dispatcher`]
+pub struct InFlight(Vec<Arc<Pending>>);
+
+impl InFlight {
+    fn new(pending: Vec<Arc<Pending>>) -> Self {
+        for p in pending.iter() {
+            p.rows.fetch_add(1, Ordering::SeqCst);
+        }
+        Self(pending)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        for p in self.0.iter() {
+            if p.rows.fetch_sub(1, Ordering::SeqCst) == 1 {
+                p.notify.notify_waiters();
+            }
+        }
+    }
+}
+
+/// 按顺序把行分配给线程的队列，处理 [`Order::Ns`] 的屏障
+pub struct Dispatcher<T> {
+    scheduler: CausalScheduler,
+    senders: Vec<Sender<(InFlight, T, OpRow)>>,
+    /// 所有已经分配的行
+    pending: Arc<Pending>,
+    /// 最近一个屏障的 ns，和它是否执行完
+    barrier: Option<(String, Arc<Pending>)>,
+}
+
+impl<T> Dispatcher<T> {
+    pub fn new(scheduler: CausalScheduler, senders: Vec<Sender<(InFlight, T, OpRow)>>) -> Self {
+        Self {
+            scheduler,
+            senders,
+            pending: Arc::default(),
+            barrier: None,
+        }
+    }
+
+    /// 分配一行，线程已经结束时返回 Err
+    pub async fn send(
+        &mut self,
+        extra: T,
+        row: OpRow,
+    ) -> Result<(), SendError<(InFlight, T, OpRow)>> {
+        let (worker, order) = self.scheduler.partition(&row);
+        let mut pending = vec![Arc::clone(&self.pending)];
+        match order {
+            Order::Any => {}
+            Order::Doc => {
+                if let Some((_, barrier)) = self.barrier.as_ref().filter(|(ns, _)| *ns == row.ns) {
+                    barrier.idle().await;
+                }
+            }
+            Order::Ns => {
+                self.pending.idle().await;
+                let barrier = Arc::new(Pending::default());
+                pending.push(Arc::clone(&barrier));
+                self.barrier = Some((row.ns.clone(), barrier));
+            }
+        }
+        self.senders[worker]
+            .send((InFlight::new(pending), extra, row))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn row(op: &str, cmd: Value) -> OpRow {
+        serde_json::from_value(json!({
+            "id": "1", "op": op, "db": "xgj", "coll": "c", "ns": "xgj.c", "ts": 0, "cmd": cmd
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_causal_key() {
+        let mut scheduler = CausalScheduler::new(8, &["xgj.c=uid".to_string()], 0).unwrap();
+        let insert = row(
+            "Insert",
+            json!({ "insert": "c", "documents": [{ "_id": 1, "n": 1 }] }),
+        );
+        let update = row(
+            "Update",
+            json!({ "update": "c", "updates": [{ "q": { "_id": 1 }, "u": { "$inc": { "n": 1 } } }] }),
+        );
+        let delete = row(
+            "Delete",
+            json!({ "delete": "c", "deletes": [{ "q": { "uid": 7, "status": 1 }, "limit": 1 }] }),
+        );
+        let multi = row(
+            "Update",
+            json!({ "update": "c", "updates": [{ "q": { "status": 1 }, "u": {}, "multi": true }] }),
+        );
+        let find = row("Find", json!({ "find": "c", "filter": { "_id": 1 } }));
+
+        assert_eq!(scheduler.key(&insert), Some("xgj.c:1".to_string()));
+        assert_eq!(scheduler.key(&insert), scheduler.key(&update));
+        assert_eq!(scheduler.key(&delete), Some("xgj.c:7".to_string()));
+        assert_eq!(scheduler.key(&multi), Some("xgj.c".to_string()));
+        assert_eq!(scheduler.key(&find), None);
+        assert_eq!(scheduler.partition(&insert), scheduler.partition(&update));
+        assert_eq!(scheduler.partition(&multi).1, Order::Ns);
+        assert_eq!(scheduler.partition(&find).1, Order::Any);
+
+        assert!(CausalScheduler::new(8, &["xgj.c".to_string()], 0).is_err());
+    }
+
+    #[test]
+    fn test_seeded_partition() {
+        let find = row("Find", json!({ "find": "c", "filter": { "_id": 1 } }));
+        let partitions = |seed| {
+            let mut scheduler = CausalScheduler::seeded(8, seed);
+            (0..100)
+                .map(|_| scheduler.partition(&find).0)
+                .collect::<Vec<usize>>()
+        };
+        assert_eq!(partitions(7), partitions(7));
+        assert_ne!(partitions(7), partitions(8));
+    }
+
+    async fn recv<T>(receivers: &mut [tokio::sync::mpsc::Receiver<T>]) -> T {
+        loop {
+            for rx in receivers.iter_mut() {
+                if let Ok(item) = rx.try_recv() {
+                    return item;
+                }
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_barrier() {
+        use std::time::Duration;
+        use tokio::sync::mpsc::channel;
+        use tokio::time::timeout;
+
+        let update = |id: i32| {
+            row(
+                "Update",
+                json!({ "update": "c", "updates": [{ "q": { "_id": id }, "u": { "$inc": { "n": 1 } } }] }),
+            )
+        };
+        let multi = row(
+            "Update",
+            json!({ "update": "c", "updates": [{ "q": { "status": 1 }, "u": {}, "multi": true }] }),
+        );
+        let mut other = update(3);
+        other.coll = "d".to_string();
+        other.ns = "xgj.d".to_string();
+
+        let scheduler = CausalScheduler::new(2, &[], 0).unwrap();
+        let (senders, mut receivers): (Vec<_>, Vec<_>) =
+            (0..2).map(|_| channel::<(InFlight, (), OpRow)>(16)).unzip();
+        let mut dispatcher = Dispatcher::new(scheduler, senders);
+        let wait = Duration::from_millis(50);
+
+        dispatcher.send((), update(1)).await.unwrap();
+        let first = recv(&mut receivers).await;
+        // 屏障需要等待之前的行执行完
+        assert!(timeout(wait, dispatcher.send((), multi.clone()))
+            .await
+            .is_err());
+        drop(first);
+        dispatcher.send((), multi).await.unwrap();
+        let barrier = recv(&mut receivers).await;
+        assert_eq!(barrier.2.ns, "xgj.c");
+        // 其他 ns 不受影响，同一个 ns 之后的写操作等待屏障执行完
+        dispatcher.send((), other).await.unwrap();
+        assert_eq!(recv(&mut receivers).await.2.ns, "xgj.d");
+        assert!(timeout(wait, dispatcher.send((), update(2))).await.is_err());
+        drop(barrier);
+        dispatcher.send((), update(2)).await.unwrap();
+        assert_eq!(recv(&mut receivers).await.2.ns, "xgj.c");
+    }
+}
@@ -0,0 +1,159 @@
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use super::op_state::{OpState, Watermark};
+
+/// 每隔多少秒保存一次断点
+pub static CHECKPOINT_SECS: u64 = 10;
+
+/// op_exec 的断点：按照读取顺序给每一行分配序号，从头开始连续执行完成的行数即为断点
+///
+/// 乱序完成的行不计入，继续时最多重复执行并发数量的行
+pub struct Checkpoint {
+    seq: Mutex<usize>,
+    watermark: Mutex<Watermark>,
+    /// 取消执行中的任务后不再计入完成的行
+    frozen: AtomicBool,
+}
+
+impl Checkpoint {
+    /// start 为已经跳过的行数
+    pub fn new(start: usize) -> Arc<Self> {
+        Arc::new(Self {
+            seq: Mutex::new(start),
+            watermark: Mutex::new(Watermark::new(start)),
+            frozen: AtomicBool::new(false),
+        })
+    }
+
+    /// 取消（abort）执行中的任务之前调用，被取消的任务持有的行 drop 时不会计入断点
+    pub fn freeze(&self) {
+        self.frozen.store(true, Ordering::SeqCst);
+    }
+
+    /// 连续执行完成的行数（包括跳过的行）
+    pub fn index(&self) -> usize {
+        self.watermark.lock().unwrap().index
+    }
+}
+
+/// 一行执行完成（包括跳过、失败）时 drop，没有执行的需要 cancel
+pub struct RowGuard {
+    checkpoint: Arc<Checkpoint>,
+    seq: usize,
+    cancelled: bool,
+}
+
+impl RowGuard {
+    pub fn cancel(mut self) {
+        self.cancelled = true;
+    }
+}
+
+impl Drop for RowGuard {
+    fn drop(&mut self) {
+        if !self.cancelled && !self.checkpoint.frozen.load(Ordering::SeqCst) {
+            self.checkpoint.watermark.lock().unwrap().done(self.seq);
+        }
+    }
+}
+
+/// 累计的指标：查询数量、耗时、跳过数量
+pub type Metrics = (usize, usize, usize);
+
+/// 保存断点到 state.json，finished 时清除断点
+pub(crate) fn save(
+    state: &mut OpState,
+    state_file: &Path,
+    index: usize,
+    metrics: Metrics,
+    finished: bool,
+) -> anyhow::Result<()> {
+    state.stress_index = if finished { 0 } else { index as i64 };
+    (
+        state.stress_query_count,
+        state.stress_cost_ms,
+        state.stress_skip_count,
+    ) = metrics;
+    state.stress_end_ts = chrono::Local::now().timestamp();
+    if finished {
+        state.stress_file = String::new();
+    }
+    fs::write(state_file, serde_json::to_string(state)?)?;
+    Ok(())
+}
+
+/// 读取下一行，有断点时读取和分配序号需要一起加锁
+pub fn read_row<T>(
+    checkpoint: &Option<Arc<Checkpoint>>,
+    read: impl FnOnce() -> Option<T>,
+) -> Option<(Option<RowGuard>, T)> {
+    match checkpoint {
+        Some(checkpoint) => {
+            let mut seq = checkpoint.seq.lock().unwrap();
+            let row = read()?;
+            let guard = RowGuard {
+                checkpoint: Arc::clone(checkpoint),
+                seq: *seq,
+                cancelled: false,
+            };
+            *seq += 1;
+            Some((Some(guard), row))
+        }
+        None => read().map(|row| (None, row)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checkpoint_guard() {
+        let checkpoint = Checkpoint::new(5);
+        let shared = Some(Arc::clone(&checkpoint));
+        let rows = Mutex::new(vec![3, 2, 1]);
+        let read = || rows.lock().unwrap().pop();
+        let (a, _) = read_row(&shared, read).unwrap();
+        let (b, _) = read_row(&shared, read).unwrap();
+        let (c, _) = read_row(&shared, read).unwrap();
+        assert!(read_row(&shared, read).is_none());
+
+        drop(b);
+        assert_eq!(checkpoint.index(), 5);
+        drop(a);
+        assert_eq!(checkpoint.index(), 7);
+        // 没有执行的行不计入断点
+        c.unwrap().cancel();
+        assert_eq!(checkpoint.index(), 7);
+        assert!(read_row(&None, || Some(1)).unwrap().0.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_abort_holding_guard() {
+        let checkpoint = Checkpoint::new(0);
+        let shared = Some(Arc::clone(&checkpoint));
+        let (done, _) = read_row(&shared, || Some(1)).unwrap();
+        let (running, _) = read_row(&shared, || Some(2)).unwrap();
+        drop(done);
+        let handle = tokio::spawn(async move {
+            let _running = running;
+            tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+        });
+        let signal = crate::signal::Signal::new();
+        signal.set(crate::signal::INTERRUPTED);
+        let aborted = crate::signal::join_all(
+            vec![handle],
+            &signal,
+            std::time::Duration::from_millis(100),
+            || checkpoint.freeze(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(aborted, 1);
+        // 被取消的行没有执行完，不计入断点
+        assert_eq!(checkpoint.index(), 1);
+    }
+}
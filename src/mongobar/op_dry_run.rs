@@ -0,0 +1,81 @@
+use std::collections::BTreeMap;
+
+use super::op_row::OpRow;
+
+/// 打印的命令样例数量
+static SAMPLE_SIZE: usize = 5;
+
+/// `--dry-run` 时统计将要执行的写操作，不会真正执行
+#[derive(Debug, Default)]
+pub struct DryRun {
+    pub rows: usize,
+    /// ns => op => 数量
+    pub writes: BTreeMap<String, BTreeMap<String, usize>>,
+    pub samples: Vec<String>,
+}
+
+impl DryRun {
+    pub fn add(&mut self, row: &OpRow) {
+        self.rows += 1;
+        if row.is_readonly() {
+            return;
+        }
+        *self
+            .writes
+            .entry(row.ns.clone())
+            .or_default()
+            .entry(format!("{:?}", row.op))
+            .or_default() += 1;
+        if self.samples.len() < SAMPLE_SIZE {
+            self.samples.push(serde_json::to_string(row).unwrap());
+        }
+    }
+
+    pub fn write_count(&self) -> usize {
+        self.writes.values().flat_map(|ops| ops.values()).sum()
+    }
+
+    pub fn summary(&self, title: &str) -> Vec<String> {
+        let mut lines = vec![format!(
+            "DryRun [{}] {} rows, {} writes",
+            title,
+            self.rows,
+            self.write_count()
+        )];
+        for (ns, ops) in self.writes.iter() {
+            let ops: Vec<String> = ops.iter().map(|(op, n)| format!("{}: {}", op, n)).collect();
+            lines.push(format!("  {} {}", ns, ops.join(" ")));
+        }
+        if !self.samples.is_empty() {
+            lines.push("DryRun sample:".to_string());
+            for sample in self.samples.iter() {
+                lines.push(format!("  {}", sample));
+            }
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dry_run_summary() {
+        let mut dry_run = DryRun::default();
+        for (op, coll) in [("Find", "a"), ("Insert", "a"), ("Update", "b"), ("Insert", "a")] {
+            let row: OpRow = serde_json::from_value(serde_json::json!({
+                "id": "1", "op": op, "db": "xgj", "coll": coll, "ns": format!("xgj.{}", coll), "ts": 0,
+                "cmd": {}
+            }))
+            .unwrap();
+            dry_run.add(&row);
+        }
+        assert_eq!(dry_run.write_count(), 3);
+        let lines = dry_run.summary("oplogs.op");
+        assert_eq!(lines[0], "DryRun [oplogs.op] 4 rows, 3 writes");
+        assert_eq!(lines[1], "  xgj.a Insert: 2");
+        assert_eq!(lines[2], "  xgj.b Update: 1");
+        assert_eq!(dry_run.samples.len(), 3);
+    }
+}
@@ -0,0 +1,342 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use bson::{Bson, Document};
+use serde_json::{json, Value};
+
+use super::op_file::OpReader;
+
+/// mongodump 格式的目录：`<dir>/<db>/<coll>.bson` 和 `<dir>/<db>/<coll>.metadata.json`，
+/// 可以直接用 mongorestore 恢复，也可以读取 mongodump（包括 `--gzip`）导出的目录
+pub struct DumpWriter {
+    dir: PathBuf,
+    /// ns => (文件, 文档数量)
+    files: BTreeMap<String, (BufWriter<File>, usize)>,
+}
+
+impl DumpWriter {
+    /// append 为 true 时在已有的文件后追加（断点续传），否则删除目录中已有的集合文件，
+    /// 目录中的其他文件保持不变
+    pub fn open(dir: &Path, append: bool) -> anyhow::Result<Self> {
+        if dir.exists() && !append {
+            Self::clear(dir)?;
+        }
+        fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            files: BTreeMap::new(),
+        })
+    }
+
+    /// 只删除导出时写入的 `<dir>/<db>/<coll>.bson` 和对应的 metadata，根目录中的文件不处理
+    fn clear(dir: &Path) -> anyhow::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let db_dir = entry?.path();
+            if !db_dir.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(&db_dir)? {
+                let path = entry?.path();
+                let name = path
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .unwrap_or_default();
+                if let Some(coll) = name.strip_suffix(".bson") {
+                    let _ = fs::remove_file(db_dir.join(format!("{}.metadata.json", coll)));
+                    fs::remove_file(&path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write(&mut self, db: &str, coll: &str, doc: &Document) -> anyhow::Result<()> {
+        let ns = format!("{}.{}", db, coll);
+        if !self.files.contains_key(&ns) {
+            let db_dir = self.dir.join(db);
+            fs::create_dir_all(&db_dir)?;
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(db_dir.join(format!("{}.bson", coll)))?;
+            self.files.insert(ns.clone(), (BufWriter::new(file), 0));
+        }
+        let (writer, count) = self.files.get_mut(&ns).unwrap();
+        doc.to_writer(writer)?;
+        *count += 1;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        for (writer, _) in self.files.values_mut() {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// 返回每个集合写入的文档数量
+    pub fn finish(self) -> anyhow::Result<Vec<(String, usize)>> {
+        let mut counts = vec![];
+        for (ns, (mut writer, count)) in self.files {
+            writer.flush()?;
+            counts.push((ns, count));
+        }
+        Ok(counts)
+    }
+}
+
+/// 写入集合的 metadata，格式和 mongodump 相同（canonical extjson）
+pub fn write_metadata(
+    dir: &Path,
+    db: &str,
+    coll: &str,
+    indexes: Vec<Document>,
+) -> anyhow::Result<()> {
+    let indexes: Vec<Value> = indexes
+        .into_iter()
+        .map(|index| Bson::Document(index).into_canonical_extjson())
+        .collect();
+    let metadata = json!({
+        "indexes": indexes,
+        "collectionName": coll,
+        "type": "collection",
+    });
+    let db_dir = dir.join(db);
+    fs::create_dir_all(&db_dir)?;
+    fs::write(
+        db_dir.join(format!("{}.metadata.json", coll)),
+        serde_json::to_string(&metadata)?,
+    )?;
+    Ok(())
+}
+
+/// dump 目录中的一个集合
+#[derive(Debug, Clone, PartialEq)]
+pub struct DumpColl {
+    pub db: String,
+    pub coll: String,
+    pub bson: PathBuf,
+    pub metadata: Option<PathBuf>,
+}
+
+impl DumpColl {
+    pub fn ns(&self) -> String {
+        format!("{}.{}", self.db, self.coll)
+    }
+
+    /// 需要创建的索引，去掉 `_id_` 和旧版本的 `ns` 字段
+    pub fn indexes(&self) -> anyhow::Result<Vec<Document>> {
+        let path = match &self.metadata {
+            Some(path) => path,
+            None => return Ok(vec![]),
+        };
+        let mut content = String::new();
+        std::io::Read::read_to_string(&mut OpReader::open(path)?, &mut content)?;
+        let metadata: Value = serde_json::from_str(&content)?;
+        let mut indexes = vec![];
+        for index in metadata
+            .get("indexes")
+            .and_then(Value::as_array)
+            .cloned()
+            .unwrap_or_default()
+        {
+            let mut index = match Bson::try_from(index)? {
+                Bson::Document(index) => index,
+                _ => continue,
+            };
+            if index.get_str("name").ok() == Some("_id_") {
+                continue;
+            }
+            index.remove("ns");
+            indexes.push(index);
+        }
+        Ok(indexes)
+    }
+
+    /// 按顺序读取集合中的文档
+    pub fn docs(&self) -> anyhow::Result<DumpDocs> {
+        Ok(DumpDocs(OpReader::open(&self.bson)?))
+    }
+}
+
+pub struct DumpDocs(OpReader);
+
+impl Iterator for DumpDocs {
+    type Item = anyhow::Result<Document>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.0.fill_buf() {
+            Ok([]) => None,
+            Ok(_) => Some(Document::from_reader(&mut self.0).map_err(anyhow::Error::from)),
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
+
+/// 扫描 dump 目录，`<dir>/<db>/<coll>.bson[.gz]`，dir 本身是库的目录（`mongodump -d`）时库名为目录名
+pub fn scan(dir: &Path) -> anyhow::Result<Vec<DumpColl>> {
+    let mut db_dirs = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            db_dirs.push(path);
+        }
+    }
+    // 没有子目录时是 `mongodump -d` 导出的库目录，否则根目录中的 oplog.bson、prelude.json 等不是集合
+    let mut colls = vec![];
+    if db_dirs.is_empty() {
+        colls = scan_db(dir)?;
+    }
+    for db_dir in db_dirs {
+        colls.extend(scan_db(&db_dir)?);
+    }
+    colls.sort_by_key(DumpColl::ns);
+    Ok(colls)
+}
+
+fn scan_db(db_dir: &Path) -> anyhow::Result<Vec<DumpColl>> {
+    let db = db_dir
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default()
+        .to_string();
+    let mut colls = vec![];
+    for entry in fs::read_dir(db_dir)? {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default()
+            .to_string();
+        let (coll, gz) = match (name.strip_suffix(".bson"), name.strip_suffix(".bson.gz")) {
+            (Some(coll), _) => (coll.to_string(), ""),
+            (_, Some(coll)) => (coll.to_string(), ".gz"),
+            _ => continue,
+        };
+        if coll.starts_with("system.") {
+            continue;
+        }
+        let metadata = db_dir.join(format!("{}.metadata.json{}", coll, gz));
+        colls.push(DumpColl {
+            db: db.clone(),
+            coll,
+            bson: path,
+            metadata: metadata.exists().then_some(metadata),
+        });
+    }
+    Ok(colls)
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::doc;
+
+    use super::super::op_fixture::TempDir;
+    use super::*;
+
+    fn a() -> Document {
+        doc! { "_id": 1_i64, "n": 1, "t": bson::DateTime::from_millis(1) }
+    }
+
+    /// 导出 xgj.c（续传追加一条，带索引）和 xgj.d
+    fn dump(dir: &Path) {
+        let mut writer = DumpWriter::open(dir, false).unwrap();
+        writer.write("xgj", "c", &a()).unwrap();
+        writer.write("xgj", "d", &doc! { "_id": 1 }).unwrap();
+        writer.finish().unwrap();
+        let mut writer = DumpWriter::open(dir, true).unwrap();
+        writer.write("xgj", "c", &doc! { "_id": 2 }).unwrap();
+        writer.finish().unwrap();
+        write_metadata(
+            dir,
+            "xgj",
+            "c",
+            vec![
+                doc! { "v": 2, "key": { "_id": 1 }, "name": "_id_" },
+                doc! { "v": 2, "key": { "n": -1 }, "name": "n_-1", "ns": "xgj.c" },
+            ],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_dump_counts() {
+        let dir = TempDir::new("dump");
+        let mut writer = DumpWriter::open(dir.path(), false).unwrap();
+        writer.write("xgj", "c", &a()).unwrap();
+        writer.write("xgj", "d", &doc! { "_id": 1 }).unwrap();
+        assert_eq!(
+            writer.finish().unwrap(),
+            vec![("xgj.c".to_string(), 1), ("xgj.d".to_string(), 1)]
+        );
+        // 续传时追加，只返回这次写入的数量
+        let mut writer = DumpWriter::open(dir.path(), true).unwrap();
+        writer.write("xgj", "c", &doc! { "_id": 2 }).unwrap();
+        assert_eq!(writer.finish().unwrap(), vec![("xgj.c".to_string(), 1)]);
+    }
+
+    #[test]
+    fn test_dump_roundtrip() {
+        let dir = TempDir::new("dump");
+        dump(dir.path());
+
+        let colls = scan(dir.path()).unwrap();
+        assert_eq!(colls.len(), 2);
+        assert_eq!(colls[0].ns(), "xgj.c");
+        assert!(colls[1].metadata.is_none());
+        let docs: Vec<Document> = colls[0].docs().unwrap().map(Result::unwrap).collect();
+        assert_eq!(docs.len(), 2);
+        // 类型需要保持不变
+        assert_eq!(docs[0], a());
+        assert_eq!(
+            colls[0].indexes().unwrap(),
+            vec![doc! { "v": 2, "key": { "n": -1 }, "name": "n_-1" }]
+        );
+    }
+
+    #[test]
+    fn test_scan_layouts() {
+        let dir = TempDir::new("dump");
+        dump(dir.path());
+        let colls = scan(dir.path()).unwrap();
+        // mongodump -d 导出的库目录
+        assert_eq!(scan(&dir.join("xgj")).unwrap(), colls);
+
+        // mongodump --oplog 在根目录中的文件不是集合
+        fs::write(dir.join("oplog.bson"), []).unwrap();
+        fs::write(dir.join("prelude.json"), "{}").unwrap();
+        assert_eq!(scan(dir.path()).unwrap(), colls);
+    }
+
+    #[test]
+    fn test_clear_only_collection_files() {
+        let dir = TempDir::new("dump");
+        dump(dir.path());
+        // 根目录中的 bson 不是导出写入的
+        fs::write(dir.join("root.bson"), []).unwrap();
+        fs::write(dir.join("xgj").join("notes.txt"), "keep").unwrap();
+        DumpWriter::open(dir.path(), false)
+            .unwrap()
+            .finish()
+            .unwrap();
+        assert!(scan(dir.path()).unwrap().is_empty());
+        assert!(dir.join("root.bson").exists());
+        assert!(dir.join("xgj").join("notes.txt").exists());
+    }
+
+    #[test]
+    fn test_clear_keeps_flat_out_dir() {
+        let dir = TempDir::new("dump");
+        // 没有子目录的 --out 中已有的文件也不会被删除
+        fs::write(dir.join("c.bson"), []).unwrap();
+        fs::write(dir.join("c.metadata.json"), "{}").unwrap();
+        DumpWriter::open(dir.path(), false)
+            .unwrap()
+            .finish()
+            .unwrap();
+        assert!(dir.join("c.bson").exists());
+        assert!(dir.join("c.metadata.json").exists());
+    }
+}
@@ -0,0 +1,236 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use bson::{doc, Document};
+use serde_json::json;
+
+use super::op_dump::{self, DumpWriter};
+use super::op_file::{self, OpFormat, OpReader};
+use super::op_row::{Op, OpRow};
+use super::op_snapshot::{self, Touched};
+use super::op_state::{OpState, Watermark};
+
+/// 每完成多少行保存一次进度
+static CHECKPOINT_ROWS: usize = 1000;
+
+/// op-export 的输出
+pub enum ExportSink {
+    /// data.op，每个文档一条 Insert
+    Op(Box<dyn Write + Send>),
+    /// mongodump 格式的目录
+    Dump(DumpWriter),
+}
+
+/// 写操作可能涉及的文档的查询条件：insert 按照 _id，update/delete/findAndModify 按照 q
+pub fn export_filters(row: &OpRow) -> Vec<Document> {
+    let mut filters: Vec<Document> = op_snapshot::write_filters(row)
+        .into_iter()
+        .map(|(q, _)| q)
+        .collect();
+    let ids = op_snapshot::insert_ids(row);
+    if !ids.is_empty() {
+        filters.push(doc! { "_id": { "$in": ids } });
+    }
+    filters
+}
+
+/// 导出的状态：输出、已经导出的文档（ns + _id 去重）和进度
+pub struct Exporter {
+    sink: ExportSink,
+    touched: Touched,
+    watermark: Watermark,
+    saved: usize,
+    state: OpState,
+    state_file: PathBuf,
+    pub docs: usize,
+}
+
+impl Exporter {
+    /// state 中的 export_index 大于 0 时从已有的输出中恢复已经导出的文档并追加
+    pub fn open(
+        data_file: &Path,
+        dump: Option<&Path>,
+        state: OpState,
+        state_file: PathBuf,
+    ) -> anyhow::Result<Self> {
+        let append = state.export_index > 0;
+        let mut touched = Touched::default();
+        let sink = match dump {
+            Some(dir) => {
+                if append && dir.exists() {
+                    for dump_coll in op_dump::scan(dir)? {
+                        let ns = dump_coll.ns();
+                        for doc in dump_coll.docs()? {
+                            if let Some(id) = doc?.get("_id") {
+                                touched.insert(&ns, id);
+                            }
+                        }
+                    }
+                }
+                ExportSink::Dump(DumpWriter::open(dir, append)?)
+            }
+            None => {
+                if append && data_file.exists() {
+                    let mut reader = OpReader::open(data_file)?;
+                    while let Some(row) = reader.read_row()? {
+                        for id in op_snapshot::insert_ids(&row) {
+                            touched.insert(&row.ns, &id);
+                        }
+                    }
+                } else {
+                    let _ = fs::remove_file(data_file);
+                }
+                ExportSink::Op(op_file::append(data_file)?)
+            }
+        };
+        Ok(Self {
+            sink,
+            touched,
+            watermark: Watermark::new(state.export_index),
+            saved: state.export_index,
+            state,
+            state_file,
+            docs: 0,
+        })
+    }
+
+    /// 写入 row 涉及的一个文档，已经导出过的跳过
+    pub fn write(&mut self, row: &OpRow, doc: Document) -> anyhow::Result<()> {
+        if let Some(id) = doc.get("_id") {
+            if !self.touched.insert(&row.ns, id) {
+                return Ok(());
+            }
+        }
+        match &mut self.sink {
+            ExportSink::Op(writer) => {
+                let re_row = OpRow {
+                    id: row.id.clone(),
+                    ns: row.ns.clone(),
+                    ts: row.ts,
+                    profile: None,
+                    op: Op::Insert,
+                    db: row.db.clone(),
+                    coll: row.coll.clone(),
+                    cmd: json!({
+                        "documents": [doc]
+                    }),
+                    args: doc! {},
+                    key: String::new(),
+                    hash: String::new(),
+                };
+                op_file::write_row(writer, OpFormat::Json, &re_row)?;
+            }
+            ExportSink::Dump(writer) => writer.write(&row.db, &row.coll, &doc)?,
+        }
+        self.docs += 1;
+        Ok(())
+    }
+
+    /// 标记第 index 行完成，连续完成的行数每增加 CHECKPOINT_ROWS 保存一次进度
+    pub fn done(&mut self, index: usize) -> anyhow::Result<()> {
+        if self.watermark.done(index) >= self.saved + CHECKPOINT_ROWS {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    /// 先把输出写入磁盘再保存进度，保证进度之前的行都已经导出
+    pub fn checkpoint(&mut self) -> anyhow::Result<()> {
+        match &mut self.sink {
+            ExportSink::Op(writer) => writer.flush()?,
+            ExportSink::Dump(writer) => writer.flush()?,
+        }
+        self.saved = self.watermark.index;
+        self.state.export_index = self.saved;
+        fs::write(&self.state_file, serde_json::to_string(&self.state)?)?;
+        Ok(())
+    }
+
+    /// 全部完成，清除进度，返回 dump 每个集合本次写入的文档数量
+    pub fn finish(mut self) -> anyhow::Result<(OpState, Vec<(String, usize)>)> {
+        self.checkpoint()?;
+        self.state.export_index = 0;
+        self.state.export_target = String::new();
+        let counts = match self.sink {
+            ExportSink::Op(mut writer) => {
+                writer.flush()?;
+                vec![]
+            }
+            ExportSink::Dump(writer) => writer.finish()?,
+        };
+        Ok((self.state, counts))
+    }
+
+    /// 中断时保存进度
+    pub fn abort(mut self) -> anyhow::Result<OpState> {
+        self.checkpoint()?;
+        Ok(self.state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::op_fixture::{row, TempDir};
+    use super::*;
+
+    fn insert_row() -> OpRow {
+        row(json!({
+            "id": "1", "op": "Insert",
+            "cmd": { "insert": "c", "documents": [{ "_id": 1 }, { "_id": 2 }] }
+        }))
+    }
+
+    /// 写入两次 `_id: 1`，乱序完成后中止
+    fn aborted(dir: &TempDir) -> OpState {
+        let row = insert_row();
+        let mut exporter = Exporter::open(
+            &dir.join("data.op"),
+            None,
+            OpState::default(),
+            dir.join("state.json"),
+        )
+        .unwrap();
+        exporter.write(&row, doc! { "_id": 1 }).unwrap();
+        exporter.write(&row, doc! { "_id": 1 }).unwrap();
+        exporter.done(1).unwrap();
+        exporter.done(0).unwrap();
+        exporter.abort().unwrap()
+    }
+
+    #[test]
+    fn test_export_filters() {
+        assert_eq!(
+            export_filters(&insert_row()),
+            vec![doc! { "_id": { "$in": [1, 2] } }]
+        );
+    }
+
+    #[test]
+    fn test_export_abort_state() {
+        let dir = TempDir::new("export");
+        assert_eq!(aborted(&dir).export_index, 2);
+    }
+
+    #[test]
+    fn test_export_resume() {
+        let dir = TempDir::new("export");
+        let state = aborted(&dir);
+        let data_file = dir.join("data.op");
+
+        // 续传时已经导出的文档不会重复导出
+        let row = insert_row();
+        let mut exporter = Exporter::open(&data_file, None, state, dir.join("state.json")).unwrap();
+        exporter.write(&row, doc! { "_id": 1 }).unwrap();
+        exporter.write(&row, doc! { "_id": 2 }).unwrap();
+        assert_eq!(exporter.docs, 1);
+        let (state, _) = exporter.finish().unwrap();
+        assert_eq!(state.export_index, 0);
+        let mut reader = OpReader::open(&data_file).unwrap();
+        let mut count = 0;
+        while reader.read_row().unwrap().is_some() {
+            count += 1;
+        }
+        assert_eq!(count, 2);
+    }
+}
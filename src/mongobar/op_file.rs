@@ -0,0 +1,555 @@
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use bson::Document;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+
+use super::op_header::{self, OpHeader};
+use super::op_row::OpRow;
+
+static READ_BUFF_SIZE: usize = 1024 * 1024;
+
+/// op 文件的压缩格式，通过后缀判断：`.op` / `.op.gz` / `.op.zst`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpCompression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl OpCompression {
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("gz") => OpCompression::Gzip,
+            Some("zst") => OpCompression::Zstd,
+            _ => OpCompression::None,
+        }
+    }
+
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            OpCompression::None => "",
+            OpCompression::Gzip => ".gz",
+            OpCompression::Zstd => ".zst",
+        }
+    }
+}
+
+/// op 文件的记录格式，通过后缀判断
+///
+/// - `.op` 每行一条 json
+/// - `.opb` 连续的 BSON 文档（文档自带 4 字节长度前缀），cmd 保存为原生 BSON，加载时不需要再从 json 转换
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpFormat {
+    Json,
+    Bson,
+}
+
+impl OpFormat {
+    pub fn from_path(path: &Path) -> Self {
+        let name = strip_compression(path);
+        if name.ends_with(".opb") {
+            OpFormat::Bson
+        } else {
+            OpFormat::Json
+        }
+    }
+
+    pub fn ext(&self) -> &'static str {
+        match self {
+            OpFormat::Json => ".op",
+            OpFormat::Bson => ".opb",
+        }
+    }
+}
+
+fn strip_compression(path: &Path) -> &str {
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    name.strip_suffix(OpCompression::from_path(path).suffix())
+        .unwrap_or(name)
+}
+
+/// 是否为 op 文件（包括 bson 格式和压缩后的）
+pub fn is_op_file(path: &Path) -> bool {
+    let name = strip_compression(path);
+    name.ends_with(".op") || name.ends_with(".opb")
+}
+
+/// 去掉 `.op`/`.opb`/`.op.gz`/`.op.zst` 等后缀的文件名，`a/qxg.op.zst` => `qxg`
+pub fn op_name(path: &Path) -> String {
+    let name = strip_compression(path);
+    name.strip_suffix(OpFormat::from_path(path).ext())
+        .unwrap_or(name)
+        .to_string()
+}
+
+/// 完整的后缀，`a/qxg.opb.zst` => `.opb.zst`
+pub fn op_suffix(path: &Path) -> String {
+    format!(
+        "{}{}",
+        OpFormat::from_path(path).ext(),
+        OpCompression::from_path(path).suffix()
+    )
+}
+
+/// 目录下存在 bson 格式或者压缩后的文件则优先使用，`oplogs.op` => `oplogs.opb`/`oplogs.op.zst`
+pub fn resolve(path: PathBuf) -> PathBuf {
+    let base = path.to_str().unwrap().to_string();
+    let base = base.strip_suffix(".op").unwrap_or(&base);
+    for format in [OpFormat::Bson, OpFormat::Json] {
+        for compression in [
+            OpCompression::None,
+            OpCompression::Zstd,
+            OpCompression::Gzip,
+        ] {
+            let candidate =
+                PathBuf::from(format!("{}{}{}", base, format.ext(), compression.suffix()));
+            if candidate.exists() {
+                return candidate;
+            }
+        }
+    }
+    path
+}
+
+/// op 文件中的一条记录
+#[derive(Debug, Clone)]
+pub enum OpRecord {
+    Line(String),
+    Bson(Document),
+}
+
+impl OpRecord {
+    /// 是否为一条 OpRow，注释、空行和头信息都不是
+    pub fn is_row(&self) -> bool {
+        match self {
+            OpRecord::Line(line) => !line.starts_with('#') && !line.trim().is_empty(),
+            OpRecord::Bson(doc) => !doc.contains_key(op_header::HEADER_KEY),
+        }
+    }
+}
+
+/// 解压后的按行读取器，记录当前读到的（解压后）字节位置
+pub struct OpReader {
+    path: PathBuf,
+    format: OpFormat,
+    inner: Box<dyn BufRead + Send>,
+    pub pos: u64,
+}
+
+impl fmt::Debug for OpReader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OpReader")
+            .field("path", &self.path)
+            .field("format", &self.format)
+            .field("pos", &self.pos)
+            .finish()
+    }
+}
+
+impl OpReader {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Self::open_with_capacity(path, READ_BUFF_SIZE)
+    }
+
+    pub fn open_with_capacity(path: &Path, capacity: usize) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let inner: Box<dyn BufRead + Send> = match OpCompression::from_path(path) {
+            OpCompression::None => Box::new(BufReader::with_capacity(capacity, file)),
+            OpCompression::Gzip => Box::new(BufReader::with_capacity(
+                capacity,
+                MultiGzDecoder::new(BufReader::new(file)),
+            )),
+            OpCompression::Zstd => Box::new(BufReader::with_capacity(
+                capacity,
+                zstd::Decoder::new(file)?,
+            )),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            format: OpFormat::from_path(path),
+            inner,
+            pos: 0,
+        })
+    }
+
+    /// 从解压后的 offset 处开始读，未压缩的文件直接 seek，压缩的文件只能解压跳过
+    pub fn open_at(path: &Path, offset: u64) -> io::Result<Self> {
+        if offset > 0 && OpCompression::from_path(path) == OpCompression::None {
+            let mut file = File::open(path)?;
+            file.seek(SeekFrom::Start(offset))?;
+            return Ok(Self {
+                path: path.to_path_buf(),
+                format: OpFormat::from_path(path),
+                inner: Box::new(BufReader::with_capacity(READ_BUFF_SIZE, file)),
+                pos: offset,
+            });
+        }
+        let mut reader = Self::open(path)?;
+        io::copy(&mut (&mut reader).take(offset), &mut io::sink())?;
+        Ok(reader)
+    }
+
+    /// 回到文件开头
+    pub fn rewind(&mut self) -> io::Result<()> {
+        *self = Self::open(&self.path)?;
+        Ok(())
+    }
+
+    /// 读取下一条记录，文件结束返回 None
+    pub fn read_record(&mut self) -> io::Result<Option<OpRecord>> {
+        match self.format {
+            OpFormat::Json => {
+                let mut line = String::new();
+                if self.read_line(&mut line)? == 0 {
+                    return Ok(None);
+                }
+                let len = line.trim_end_matches(['\n', '\r']).len();
+                line.truncate(len);
+                Ok(Some(OpRecord::Line(line)))
+            }
+            OpFormat::Bson => {
+                if self.fill_buf()?.is_empty() {
+                    return Ok(None);
+                }
+                let doc = Document::from_reader(&mut *self)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                Ok(Some(OpRecord::Bson(doc)))
+            }
+        }
+    }
+
+    /// 读取下一条 OpRow，跳过注释、空行和头信息
+    pub fn read_row(&mut self) -> anyhow::Result<Option<OpRow>> {
+        while let Some(record) = self.read_record()? {
+            if !record.is_row() {
+                continue;
+            }
+            return Ok(Some(match record {
+                OpRecord::Line(line) => serde_json::from_str::<OpRow>(&line)?,
+                OpRecord::Bson(doc) => OpRow::from_bson(doc)?,
+            }));
+        }
+        Ok(None)
+    }
+}
+
+impl Read for OpReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl BufRead for OpReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt as u64;
+        self.inner.consume(amt)
+    }
+}
+
+fn open_writer(path: &Path, file: File) -> io::Result<Box<dyn Write + Send>> {
+    Ok(match OpCompression::from_path(path) {
+        OpCompression::None => Box::new(BufWriter::new(file)),
+        OpCompression::Gzip => Box::new(GzEncoder::new(file, flate2::Compression::default())),
+        OpCompression::Zstd => Box::new(zstd::Encoder::new(file, 0)?.auto_finish()),
+    })
+}
+
+/// 追加写入，压缩文件会追加一个新的 gzip member / zstd frame，读取时会连续解压
+pub fn append(path: &Path) -> io::Result<Box<dyn Write + Send>> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    open_writer(path, file)
+}
+
+/// 覆盖写入
+pub fn create(path: &Path) -> io::Result<Box<dyn Write + Send>> {
+    let file = File::create(path)?;
+    open_writer(path, file)
+}
+
+/// 同目录下的隐藏临时文件，保持后缀不变（压缩方式和格式相同），写完后 rename 替换原文件
+pub fn sibling_tmp(path: &Path) -> PathBuf {
+    path.with_file_name(format!(".{}", path.file_name().unwrap().to_str().unwrap()))
+}
+
+/// 需要多个临时文件时用 tag 区分，例如 `.body.a.op`
+pub fn sibling_tmp_with(path: &Path, tag: &str) -> PathBuf {
+    path.with_file_name(format!(
+        ".{}.{}",
+        tag,
+        path.file_name().unwrap().to_str().unwrap()
+    ))
+}
+
+/// 可以显式结束的压缩流，结束时的错误（gzip trailer、zstd epilogue）需要返回给调用方
+pub enum Encoder {
+    None(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+impl Encoder {
+    /// 覆盖写入
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Self::open(path, File::create(path)?)
+    }
+
+    fn open(path: &Path, file: File) -> io::Result<Self> {
+        let file = BufWriter::new(file);
+        Ok(match OpCompression::from_path(path) {
+            OpCompression::None => Self::None(file),
+            OpCompression::Gzip => Self::Gzip(GzEncoder::new(file, flate2::Compression::default())),
+            OpCompression::Zstd => Self::Zstd(zstd::Encoder::new(file, 0)?),
+        })
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        let mut file = match self {
+            Self::None(file) => file,
+            Self::Gzip(encoder) => encoder.finish()?,
+            Self::Zstd(encoder) => encoder.finish()?,
+        };
+        file.flush()
+    }
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::None(w) => w.write(buf),
+            Self::Gzip(w) => w.write(buf),
+            Self::Zstd(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::None(w) => w.flush(),
+            Self::Gzip(w) => w.flush(),
+            Self::Zstd(w) => w.flush(),
+        }
+    }
+}
+
+/// 连续写入多条记录，整个过程只打开一次文件，压缩文件也只追加一个 gzip member / zstd frame
+pub struct OpWriter {
+    /// finish 之后为 None
+    writer: Option<Encoder>,
+    format: OpFormat,
+}
+
+impl OpWriter {
+    pub fn append(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            writer: Some(Encoder::open(path, file)?),
+            format: OpFormat::from_path(path),
+        })
+    }
+
+    pub fn write(&mut self, row: &OpRow) -> anyhow::Result<()> {
+        let writer = self.writer.as_mut().expect("write after finish");
+        write_row(writer, self.format, row)
+    }
+
+    /// 写入磁盘并结束压缩流
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.writer.take() {
+            Some(writer) => writer.finish(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for OpWriter {
+    /// 出错提前返回时也结束压缩流，保证已经写入的记录可以读取
+    fn drop(&mut self) {
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.finish();
+        }
+    }
+}
+
+/// 按照文件的格式写入一条记录
+pub fn write_row(writer: &mut dyn Write, format: OpFormat, row: &OpRow) -> anyhow::Result<()> {
+    match format {
+        OpFormat::Json => writeln!(writer, "{}", serde_json::to_string(row)?)?,
+        OpFormat::Bson => row.to_bson()?.to_writer(writer)?,
+    }
+    Ok(())
+}
+
+/// 按照目标文件的后缀重新压缩，只用于相同格式之间
+pub fn transcode(from: &Path, to: &Path) -> io::Result<u64> {
+    let mut reader = OpReader::open(from)?;
+    let mut writer = create(to)?;
+    let n = io::copy(&mut reader, &mut writer)?;
+    writer.flush()?;
+    Ok(n)
+}
+
+/// 在 json 和 bson 格式之间转换，注释和空行会被丢弃，头信息会保留，返回转换的条数
+pub fn convert(from: &Path, to: &Path) -> anyhow::Result<usize> {
+    let format = OpFormat::from_path(to);
+    let mut reader = OpReader::open(from)?;
+    let mut writer = create(to)?;
+    let mut n = 0;
+    while let Some(record) = reader.read_record()? {
+        if let Some(header) = OpHeader::from_record(&record) {
+            header.write(&mut writer, format)?;
+            continue;
+        }
+        if !record.is_row() {
+            continue;
+        }
+        let row = match record {
+            OpRecord::Line(line) => serde_json::from_str::<OpRow>(&line)?,
+            OpRecord::Bson(doc) => OpRow::from_bson(doc)?,
+        };
+        write_row(&mut writer, format, &row)?;
+        n += 1;
+    }
+    writer.flush()?;
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::super::op_fixture::{row, TempDir};
+    use super::*;
+
+    const COMPRESSED: [&str; 3] = ["a.op", "a.op.gz", "a.op.zst"];
+
+    /// 每行单独追加，压缩文件中是多个 gzip member / zstd frame
+    fn append_lines(dir: &TempDir, name: &str) -> PathBuf {
+        let path = dir.join(name);
+        for i in 0..3 {
+            let mut w = append(&path).unwrap();
+            writeln!(w, "line{}", i).unwrap();
+        }
+        path
+    }
+
+    #[test]
+    fn test_compressed_read_lines() {
+        let dir = TempDir::new("op-file");
+        for name in COMPRESSED {
+            let lines: Vec<String> = OpReader::open(&append_lines(&dir, name))
+                .unwrap()
+                .lines()
+                .map(|l| l.unwrap())
+                .collect();
+            assert_eq!(lines, vec!["line0", "line1", "line2"], "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_compressed_open_at() {
+        let dir = TempDir::new("op-file");
+        for name in COMPRESSED {
+            let path = append_lines(&dir, name);
+            let mut reader = OpReader::open_at(&path, 6).unwrap();
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, "line1\n", "{}", name);
+            assert_eq!(reader.pos, 12, "{}", name);
+            assert_eq!(op_name(&path), "a");
+        }
+    }
+
+    /// 带注释和扩展类型（日期、long）的 json 文件
+    fn typed_json(dir: &TempDir) -> PathBuf {
+        let json = dir.join("a.op");
+        std::fs::write(
+            &json,
+            concat!(
+                "# comment\n",
+                r#"{"id":"1","op":"Find","db":"xgj","coll":"users","cmd":{"find":"users","filter":{"at":{"$date":"2024-07-19T02:19:26.123Z"},"n":{"$numberLong":"3"}}},"ns":"xgj.users","ts":1}"#,
+                "\n",
+            ),
+        )
+        .unwrap();
+        json
+    }
+
+    #[test]
+    fn test_pack_bson() {
+        let dir = TempDir::new("op-bson");
+        let json = typed_json(&dir);
+        for name in ["a.opb", "a.opb.zst"] {
+            let packed = dir.join(name);
+            assert_eq!(convert(&json, &packed).unwrap(), 1);
+
+            let mut reader = OpReader::open(&packed).unwrap();
+            let doc = match reader.read_record().unwrap() {
+                Some(OpRecord::Bson(doc)) => doc,
+                other => panic!("unexpected record {:?}", other),
+            };
+            // 注释不会写入 bson
+            assert!(reader.read_record().unwrap().is_none());
+
+            let row = OpRow::from_bson(doc).unwrap();
+            let filter = row.args.get_document("filter").unwrap();
+            assert!(filter.get_datetime("at").is_ok());
+            assert_eq!(filter.get_i64("n").unwrap(), 3);
+        }
+    }
+
+    #[test]
+    fn test_unpack_bson_keeps_types() {
+        let dir = TempDir::new("op-bson");
+        let json = typed_json(&dir);
+        for name in ["a.opb", "a.opb.zst"] {
+            let packed = dir.join(name);
+            convert(&json, &packed).unwrap();
+            let unpacked = dir.join("b.op");
+            assert_eq!(convert(&packed, &unpacked).unwrap(), 1);
+
+            let line = std::fs::read_to_string(&unpacked).unwrap();
+            let row: OpRow = serde_json::from_str(line.trim()).unwrap();
+            let filter = Document::deserialize(&row.cmd["filter"]).unwrap();
+            assert_eq!(
+                filter
+                    .get_datetime("at")
+                    .unwrap()
+                    .try_to_rfc3339_string()
+                    .unwrap(),
+                "2024-07-19T02:19:26.123Z"
+            );
+            assert_eq!(row.cmd["filter"]["n"]["$numberLong"], "3");
+        }
+    }
+
+    #[test]
+    fn test_op_writer_finish() {
+        let dir = TempDir::new("op-writer");
+        for name in ["a.op", "a.op.gz", "a.opb.zst"] {
+            let path = dir.join(name);
+            for id in ["1", "2"] {
+                let mut writer = OpWriter::append(&path).unwrap();
+                writer.write(&row(serde_json::json!({ "id": id }))).unwrap();
+                writer.finish().unwrap();
+            }
+            let mut reader = OpReader::open(&path).unwrap();
+            let mut ids = vec![];
+            while let Some(row) = reader.read_row().unwrap() {
+                ids.push(row.id);
+            }
+            assert_eq!(ids, vec!["1", "2"], "{}", name);
+        }
+    }
+}
@@ -0,0 +1,505 @@
+use std::cmp::Ordering;
+
+use regex::Regex;
+use serde_json::Value;
+
+use super::op_file::OpRecord;
+use super::op_row::{self, OpRow};
+
+/// op 文件的过滤条件
+///
+/// 默认按照表达式解析，例如：
+///
+/// ```text
+/// op in (Find, Aggregate) and coll = "orders" and cmd.filter.status exists
+/// ts between "2024-07-19T00:00:00Z" and "2024-07-20T00:00:00Z" or not ns ~ "^xgj\."
+/// ```
+///
+/// - 字段：id/op/db/coll/ns/ts/key，以及 `cmd.xxx`/`profile.xxx` 的任意路径，数组使用下标 `cmd.updates.0.q`
+/// - 比较：`=` `!=` `>` `>=` `<` `<=` `in (..)` `between .. and ..` `exists` `~`（正则）
+/// - 组合：`and` `or` `not` `( )`
+/// - ts 可以和时间字符串比较，op 比较时不区分大小写
+///
+/// `re:` 开头时按照老的方式用正则匹配原始的行，例如 `re:orders|users`。
+/// 不带 `re:` 的老写法（例如 `orders|users`）不像表达式时仍然按正则处理并提示已废弃，像表达式但无法解析时直接返回错误
+#[derive(Debug, Clone)]
+pub enum OpFilter {
+    Expr(Expr),
+    Regex(Regex),
+}
+
+impl OpFilter {
+    pub fn parse(filter: &str) -> Result<Self, anyhow::Error> {
+        if let Some(re) = filter.strip_prefix("re:") {
+            return Ok(OpFilter::Regex(Regex::new(re)?));
+        }
+        let err = match Expr::parse(filter) {
+            Ok(expr) => return Ok(OpFilter::Expr(expr)),
+            Err(e) => e,
+        };
+        // 兼容老的写法：不像表达式的输入仍然按正则处理
+        if !looks_like_expr(filter) {
+            if let Ok(re) = Regex::new(filter) {
+                eprintln!(
+                    "OpFilter [{}] {:?} is treated as a regex, plain regex filters are deprecated, use `re:{}` instead",
+                    chrono::Local::now().timestamp(),
+                    filter,
+                    filter
+                );
+                return Ok(OpFilter::Regex(re));
+            }
+        }
+        Err(anyhow::anyhow!(
+            "invalid filter {:?}: {}, use `re:<regex>` to match raw lines",
+            filter,
+            err
+        ))
+    }
+
+    /// 在解析记录之前过滤，只有正则会生效
+    pub fn matches_record(&self, record: &OpRecord) -> bool {
+        match self {
+            OpFilter::Regex(re) => match record {
+                OpRecord::Line(line) => re.is_match(line),
+                OpRecord::Bson(doc) => re.is_match(
+                    &bson::Bson::Document(doc.clone())
+                        .into_relaxed_extjson()
+                        .to_string(),
+                ),
+            },
+            OpFilter::Expr(_) => true,
+        }
+    }
+
+    /// 在解析记录之后过滤，只有表达式会生效
+    pub fn matches_row(&self, row: &OpRow) -> bool {
+        match self {
+            OpFilter::Expr(expr) => expr.eval(row),
+            OpFilter::Regex(_) => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Cmp(String, CmpOp, Lit),
+    In(String, Vec<Lit>),
+    Between(String, Lit, Lit),
+    Exists(String),
+    Match(String, Regex),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CmpOp {
+    Eq,
+    Ne,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Lit {
+    Str(String),
+    Num(f64),
+    Bool(bool),
+    Null,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Num(f64),
+    Sym(&'static str),
+}
+
+impl Expr {
+    pub fn parse(input: &str) -> Result<Self, anyhow::Error> {
+        let tokens = tokenize(input)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.parse_or()?;
+        if parser.pos != parser.tokens.len() {
+            return Err(anyhow::anyhow!(
+                "unexpected token {:?}",
+                parser.tokens[parser.pos]
+            ));
+        }
+        Ok(expr)
+    }
+
+    pub fn eval(&self, row: &OpRow) -> bool {
+        match self {
+            Expr::And(a, b) => a.eval(row) && b.eval(row),
+            Expr::Or(a, b) => a.eval(row) || b.eval(row),
+            Expr::Not(a) => !a.eval(row),
+            Expr::Exists(path) => resolve(row, path).is_some(),
+            Expr::Cmp(path, op, lit) => match resolve(row, path) {
+                Some(v) => match compare(&v, lit, path == "op") {
+                    Some(ord) => match op {
+                        CmpOp::Eq => ord == Ordering::Equal,
+                        CmpOp::Ne => ord != Ordering::Equal,
+                        CmpOp::Gt => ord == Ordering::Greater,
+                        CmpOp::Gte => ord != Ordering::Less,
+                        CmpOp::Lt => ord == Ordering::Less,
+                        CmpOp::Lte => ord != Ordering::Greater,
+                    },
+                    None => *op == CmpOp::Ne,
+                },
+                // 和 mongo 的 $ne 一样，不存在的字段也满足 !=
+                None => *op == CmpOp::Ne,
+            },
+            Expr::In(path, lits) => match resolve(row, path) {
+                Some(v) => lits
+                    .iter()
+                    .any(|lit| compare(&v, lit, path == "op") == Some(Ordering::Equal)),
+                None => false,
+            },
+            Expr::Between(path, from, to) => match resolve(row, path) {
+                Some(v) => {
+                    compare(&v, from, false).is_some_and(|o| o != Ordering::Less)
+                        && compare(&v, to, false).is_some_and(|o| o != Ordering::Greater)
+                }
+                None => false,
+            },
+            Expr::Match(path, re) => match resolve(row, path) {
+                Some(Value::String(s)) => re.is_match(&s),
+                Some(v) => re.is_match(&v.to_string()),
+                None => false,
+            },
+        }
+    }
+}
+
+/// 取出 OpRow 中的字段
+fn resolve(row: &OpRow, path: &str) -> Option<Value> {
+    let (head, rest) = match path.split_once('.') {
+        Some((head, rest)) => (head, Some(rest)),
+        None => (path, None),
+    };
+    let root = match head {
+        "id" => Value::String(row.id.clone()),
+        "op" => Value::String(format!("{:?}", row.op)),
+        "db" => Value::String(row.db.clone()),
+        "coll" => Value::String(row.coll.clone()),
+        "ns" => Value::String(row.ns.clone()),
+        "ts" => Value::from(row.ts),
+        "key" => Value::String(if row.key.is_empty() {
+            row.build_key()
+        } else {
+            row.key.clone()
+        }),
+        "cmd" => row.cmd.clone(),
+        "profile" => serde_json::to_value(row.profile.as_ref()?).ok()?,
+        _ => return None,
+    };
+    let mut cur = &root;
+    if let Some(rest) = rest {
+        for seg in rest.split('.') {
+            cur = match cur {
+                Value::Object(map) => map.get(seg)?,
+                Value::Array(arr) => arr.get(seg.parse::<usize>().ok()?)?,
+                _ => return None,
+            };
+        }
+    }
+    Some(cur.clone())
+}
+
+fn compare(v: &Value, lit: &Lit, ignore_case: bool) -> Option<Ordering> {
+    // `.opb` 中读取的 cmd 是 canonical extjson，数字是 `{ "$numberInt": "1" }`
+    if let (Value::Object(_), Some(n)) = (v, op_row::json_f64(v)) {
+        return compare(&Value::from(n), lit, ignore_case);
+    }
+    match (v, lit) {
+        (Value::String(s), Lit::Str(l)) => Some(if ignore_case {
+            s.to_lowercase().cmp(&l.to_lowercase())
+        } else {
+            s.as_str().cmp(l.as_str())
+        }),
+        (Value::Number(n), Lit::Num(l)) => n.as_f64()?.partial_cmp(l),
+        // ts 等数字字段和时间字符串比较
+        (Value::Number(n), Lit::Str(l)) => {
+            let l = match l.parse::<f64>() {
+                Ok(l) => l,
+                Err(_) => chrono::DateTime::parse_from_rfc3339(l)
+                    .ok()?
+                    .timestamp_millis() as f64,
+            };
+            n.as_f64()?.partial_cmp(&l)
+        }
+        (Value::String(s), Lit::Num(l)) => s.parse::<f64>().ok()?.partial_cmp(l),
+        (Value::Bool(b), Lit::Bool(l)) => Some(b.cmp(l)),
+        (Value::Null, Lit::Null) => Some(Ordering::Equal),
+        _ => None,
+    }
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, anyhow::Error> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        if c == '"' || c == '\'' {
+            let mut s = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return Err(anyhow::anyhow!("unterminated string")),
+                    Some('\\') => {
+                        let next = chars
+                            .get(i + 1)
+                            .ok_or_else(|| anyhow::anyhow!("unterminated string"))?;
+                        // 正则中的转义原样保留
+                        if *next != c && *next != '\\' {
+                            s.push('\\');
+                        }
+                        s.push(*next);
+                        i += 2;
+                    }
+                    Some(ch) if *ch == c => {
+                        i += 1;
+                        break;
+                    }
+                    Some(ch) => {
+                        s.push(*ch);
+                        i += 1;
+                    }
+                }
+            }
+            tokens.push(Token::Str(s));
+            continue;
+        }
+        if c.is_ascii_digit() || (c == '-' && chars.get(i + 1).is_some_and(|c| c.is_ascii_digit()))
+        {
+            let start = i;
+            i += 1;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let s: String = chars[start..i].iter().collect();
+            tokens.push(Token::Num(s.parse()?));
+            continue;
+        }
+        if c.is_alphabetic() || c == '_' || c == '$' {
+            let start = i;
+            while i < chars.len()
+                && (chars[i].is_alphanumeric() || matches!(chars[i], '_' | '$' | '.'))
+            {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+            continue;
+        }
+        let (sym, len) = match (c, chars.get(i + 1)) {
+            ('!', Some('=')) => ("!=", 2),
+            ('>', Some('=')) => (">=", 2),
+            ('<', Some('=')) => ("<=", 2),
+            ('=', Some('=')) => ("=", 2),
+            ('(', _) => ("(", 1),
+            (')', _) => (")", 1),
+            (',', _) => (",", 1),
+            ('=', _) => ("=", 1),
+            ('>', _) => (">", 1),
+            ('<', _) => ("<", 1),
+            ('~', _) => ("~", 1),
+            _ => return Err(anyhow::anyhow!("unexpected char {:?}", c)),
+        };
+        i += len;
+        tokens.push(Token::Sym(sym));
+    }
+    Ok(tokens)
+}
+
+/// 能切分成 token 并且包含比较符号或者关键字时认为是表达式
+fn looks_like_expr(input: &str) -> bool {
+    match tokenize(input) {
+        Ok(tokens) => tokens.iter().any(|t| match t {
+            Token::Sym(_) => true,
+            Token::Ident(s) => ["and", "or", "not", "in", "between", "exists"]
+                .iter()
+                .any(|kw| s.eq_ignore_ascii_case(kw)),
+            _ => false,
+        }),
+        Err(_) => false,
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+
+    fn keyword(&mut self, kw: &str) -> bool {
+        if let Some(Token::Ident(s)) = self.peek() {
+            if s.eq_ignore_ascii_case(kw) {
+                self.pos += 1;
+                return true;
+            }
+        }
+        false
+    }
+
+    fn expect_sym(&mut self, sym: &str) -> Result<(), anyhow::Error> {
+        match self.next() {
+            Some(Token::Sym(s)) if s == sym => Ok(()),
+            t => Err(anyhow::anyhow!("expected {:?}, got {:?}", sym, t)),
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, anyhow::Error> {
+        let mut left = self.parse_and()?;
+        while self.keyword("or") {
+            left = Expr::Or(Box::new(left), Box::new(self.parse_and()?));
+        }
+        Ok(left)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, anyhow::Error> {
+        let mut left = self.parse_unary()?;
+        while self.keyword("and") {
+            left = Expr::And(Box::new(left), Box::new(self.parse_unary()?));
+        }
+        Ok(left)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, anyhow::Error> {
+        if self.keyword("not") {
+            return Ok(Expr::Not(Box::new(self.parse_unary()?)));
+        }
+        if let Some(Token::Sym("(")) = self.peek() {
+            self.pos += 1;
+            let expr = self.parse_or()?;
+            self.expect_sym(")")?;
+            return Ok(expr);
+        }
+        self.parse_cmp()
+    }
+
+    fn parse_cmp(&mut self) -> Result<Expr, anyhow::Error> {
+        let path = match self.next() {
+            Some(Token::Ident(path)) => path,
+            t => return Err(anyhow::anyhow!("expected field, got {:?}", t)),
+        };
+        if self.keyword("exists") {
+            return Ok(Expr::Exists(path));
+        }
+        if self.keyword("in") {
+            self.expect_sym("(")?;
+            let mut lits = vec![self.parse_lit()?];
+            while let Some(Token::Sym(",")) = self.peek() {
+                self.pos += 1;
+                lits.push(self.parse_lit()?);
+            }
+            self.expect_sym(")")?;
+            return Ok(Expr::In(path, lits));
+        }
+        if self.keyword("between") {
+            let from = self.parse_lit()?;
+            if !self.keyword("and") {
+                return Err(anyhow::anyhow!("expected and in between"));
+            }
+            let to = self.parse_lit()?;
+            return Ok(Expr::Between(path, from, to));
+        }
+        let op = match self.next() {
+            Some(Token::Sym("~")) => {
+                return match self.next() {
+                    Some(Token::Str(s)) => Ok(Expr::Match(path, Regex::new(&s)?)),
+                    t => Err(anyhow::anyhow!("expected regex string, got {:?}", t)),
+                };
+            }
+            Some(Token::Sym("=")) => CmpOp::Eq,
+            Some(Token::Sym("!=")) => CmpOp::Ne,
+            Some(Token::Sym(">")) => CmpOp::Gt,
+            Some(Token::Sym(">=")) => CmpOp::Gte,
+            Some(Token::Sym("<")) => CmpOp::Lt,
+            Some(Token::Sym("<=")) => CmpOp::Lte,
+            t => return Err(anyhow::anyhow!("expected operator, got {:?}", t)),
+        };
+        Ok(Expr::Cmp(path, op, self.parse_lit()?))
+    }
+
+    fn parse_lit(&mut self) -> Result<Lit, anyhow::Error> {
+        match self.next() {
+            Some(Token::Str(s)) => Ok(Lit::Str(s)),
+            Some(Token::Num(n)) => Ok(Lit::Num(n)),
+            Some(Token::Ident(s)) => Ok(match s.to_lowercase().as_str() {
+                "true" => Lit::Bool(true),
+                "false" => Lit::Bool(false),
+                "null" => Lit::Null,
+                // 不加引号的单词当作字符串，如 op in (Find, Aggregate)
+                _ => Lit::Str(s),
+            }),
+            t => Err(anyhow::anyhow!("expected value, got {:?}", t)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_expr() {
+        let row: OpRow = serde_json::from_value(serde_json::json!({
+            "id": "1", "op": "Find", "db": "xgj", "coll": "orders", "ns": "xgj.orders",
+            "ts": 1721355566123i64,
+            "cmd": { "find": "orders", "filter": { "status": 1, "tags": ["a", "b"] } }
+        }))
+        .unwrap();
+
+        let matches = |s: &str| OpFilter::parse(s).unwrap().matches_row(&row);
+        assert!(matches(
+            r#"op in (Find, Aggregate) and coll = "orders" and cmd.filter.status exists"#
+        ));
+        assert!(matches("op = find and not cmd.filter.name exists"));
+        assert!(matches(
+            r#"ts between "2024-07-19T00:00:00Z" and "2024-07-20T00:00:00Z""#
+        ));
+        assert!(matches(
+            r#"cmd.filter.status >= 1 and cmd.filter.tags.1 = "b""#
+        ));
+        assert!(matches(
+            r#"(coll = "users" or ns ~ "^xgj\.") and cmd.filter.name != 1"#
+        ));
+        assert!(!matches(r#"op = Aggregate or ts < "2024-07-19T00:00:00Z""#));
+
+        // re: 开头时按正则匹配原始行
+        assert!(matches!(
+            OpFilter::parse("re:orders|users").unwrap(),
+            OpFilter::Regex(_)
+        ));
+        // 不像表达式的老写法退化为正则，像表达式但是写错了直接报错
+        assert!(matches!(
+            OpFilter::parse("orders|users").unwrap(),
+            OpFilter::Regex(_)
+        ));
+        assert!(matches!(
+            OpFilter::parse(r"^xgj\.orders").unwrap(),
+            OpFilter::Regex(_)
+        ));
+        assert!(OpFilter::parse("op = Find and").is_err());
+        assert!(OpFilter::parse("coll in (orders").is_err());
+    }
+}
@@ -0,0 +1,114 @@
+//! 测试共用的临时目录和 OpRow 构造
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde_json::Value;
+
+use super::op_file::OpWriter;
+use super::op_row::OpRow;
+
+/// 测试用的临时目录，drop 时删除，测试失败 panic 时也会清理
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    /// 同一个进程内多次创建也不会冲突，测试可以并行执行
+    pub fn new(name: &str) -> Self {
+        static SEQ: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "mongobar-{}-{}-{}",
+            name,
+            std::process::id(),
+            SEQ.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// 构造一条 OpRow，没有指定的字段使用 `xgj.c` 上的 Find，ns 由 db 和 coll 拼接
+///
+/// ```text
+/// row(json!({ "id": "1", "op": "Insert", "cmd": { "insert": "c" } }))
+/// ```
+pub(crate) fn row(fields: Value) -> OpRow {
+    let mut value = serde_json::json!({
+        "id": "0", "op": "Find", "db": "xgj", "coll": "c", "ts": 0, "cmd": {}
+    });
+    for (k, v) in fields.as_object().expect("row fields must be an object") {
+        value[k] = v.clone();
+    }
+    if value.get("ns").is_none() {
+        value["ns"] = Value::String(format!(
+            "{}.{}",
+            value["db"].as_str().unwrap(),
+            value["coll"].as_str().unwrap()
+        ));
+    }
+    serde_json::from_value(value).unwrap()
+}
+
+/// 按照文件后缀的格式追加写入，文件不存在时创建
+pub(crate) fn write_rows(path: &Path, rows: &[OpRow]) {
+    let mut writer = OpWriter::append(path).unwrap();
+    for row in rows {
+        writer.write(row).unwrap();
+    }
+    writer.finish().unwrap();
+}
+
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_temp_dir_cleanup() {
+        let path = {
+            let dir = TempDir::new("fixture");
+            fs::write(dir.join("a.op"), "").unwrap();
+            assert_ne!(dir.path(), TempDir::new("fixture").path());
+            dir.path().to_path_buf()
+        };
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_row_defaults() {
+        let r = row(serde_json::json!({ "id": "1", "coll": "orders", "ts": 3 }));
+        assert_eq!((r.id.as_str(), r.ns.as_str(), r.ts), ("1", "xgj.orders", 3));
+        assert!(matches!(r.op, super::super::op_row::Op::Find));
+    }
+
+    #[test]
+    fn test_write_rows() {
+        let dir = TempDir::new("fixture");
+        let path = dir.join("a.opb.zst");
+        write_rows(
+            &path,
+            &[row(serde_json::json!({})), row(serde_json::json!({}))],
+        );
+        write_rows(&path, &[row(serde_json::json!({ "id": "2" }))]);
+
+        let mut reader = super::super::op_file::OpReader::open(&path).unwrap();
+        let mut ids = vec![];
+        while let Some(row) = reader.read_row().unwrap() {
+            ids.push(row.id);
+        }
+        assert_eq!(ids, vec!["0", "0", "2"]);
+    }
+}
@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+use bson::{oid::ObjectId, Bson, DateTime, Document};
+use once_cell::sync::Lazy;
+use rand::distributions::{Alphanumeric, WeightedIndex};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde_json::Value;
+
+use super::op_row::{self, OpRow};
+
+/// 模板占位符的字段，eg: `{"$gen": "int", "min": 1, "max": 100}`
+pub static GEN_KEY: &str = "$gen";
+
+/// `choice` 的 from 文件只读取一次
+static CHOICE_FILES: Lazy<Mutex<HashMap<String, Arc<Vec<Bson>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// 模板值生成器，每个线程一个，相同的 seed 生成的序列相同
+///
+/// - `oid`：ObjectId
+/// - `int`/`double`：`min`~`max` 之间的随机数（包含 max）
+/// - `choice`：从 `values` 数组或者 `from` 文件（每行一个值，可以是 json）中随机选择，可以通过 `weights` 指定每个值的权重
+/// - `now`/`now-offset`：当前时间加上 `sec`/`ms` 的偏移
+/// - `string`：长度为 `len` 的随机字母数字
+/// - `bool`
+pub struct OpGen {
+    rng: StdRng,
+}
+
+impl OpGen {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    /// 替换 cmd 和 args 中的占位符
+    pub fn resolve(&mut self, row: &mut OpRow) -> anyhow::Result<()> {
+        if !has_gen(&row.cmd) {
+            return Ok(());
+        }
+        // cmd 和 args 中同一个位置的占位符需要生成相同的值
+        let mut values: HashMap<String, Bson> = HashMap::new();
+        resolve_value(&mut row.cmd, String::new(), &mut |path, spec| {
+            let v = self.gen(spec)?;
+            values.insert(path.to_string(), v.clone());
+            Ok(v)
+        })?;
+        resolve_doc(
+            &mut row.args,
+            "",
+            &mut |path, spec| match values.remove(path) {
+                Some(v) => Ok(v),
+                None => self.gen(&Bson::Document(spec.clone()).into_relaxed_extjson()),
+            },
+        )?;
+        Ok(())
+    }
+
+    fn gen(&mut self, spec: &Value) -> anyhow::Result<Bson> {
+        let kind = spec
+            .get(GEN_KEY)
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let num = |key: &str| spec.get(key).and_then(op_row::json_f64);
+        let v = match kind {
+            "oid" => Bson::ObjectId(ObjectId::from_bytes(self.rng.gen())),
+            "int" => {
+                let min = num("min").unwrap_or(0.0) as i64;
+                let max = num("max").unwrap_or(i32::MAX as f64) as i64;
+                if min > max {
+                    return Err(anyhow::anyhow!("$gen int min > max: {}", spec));
+                }
+                // 和 json 转换回来的类型保持一致
+                let v = self.rng.gen_range(min..=max);
+                i32::try_from(v).map(Bson::Int32).unwrap_or(Bson::Int64(v))
+            }
+            "double" => {
+                let min = num("min").unwrap_or(0.0);
+                let max = num("max").unwrap_or(1.0);
+                if min > max {
+                    return Err(anyhow::anyhow!("$gen double min > max: {}", spec));
+                }
+                Bson::Double(self.rng.gen_range(min..=max))
+            }
+            "choice" => {
+                let values = match (spec.get("values"), spec.get("from").and_then(Value::as_str)) {
+                    (Some(Value::Array(values)), _) => Arc::new(
+                        values
+                            .iter()
+                            .map(|v| Bson::try_from(v.clone()))
+                            .collect::<Result<Vec<_>, _>>()?,
+                    ),
+                    (_, Some(from)) => load_choices(from)?,
+                    _ => {
+                        return Err(anyhow::anyhow!(
+                            "$gen choice requires values or from: {}",
+                            spec
+                        ))
+                    }
+                };
+                if values.is_empty() {
+                    return Err(anyhow::anyhow!("$gen choice is empty: {}", spec));
+                }
+                let index = match spec.get("weights").and_then(Value::as_array) {
+                    Some(weights) if weights.len() == values.len() => {
+                        let weights = weights.iter().map(|w| op_row::json_f64(w).unwrap_or_default());
+                        self.rng.sample(WeightedIndex::new(weights)?)
+                    }
+                    _ => self.rng.gen_range(0..values.len()),
+                };
+                values[index].clone()
+            }
+            "now" | "now-offset" => {
+                let offset = num("sec").map(|sec| sec * 1000.0).unwrap_or_default()
+                    + num("ms").unwrap_or_default();
+                Bson::DateTime(DateTime::from_millis(
+                    chrono::Local::now().timestamp_millis() + offset as i64,
+                ))
+            }
+            "string" => {
+                let len = num("len").unwrap_or(8.0) as usize;
+                Bson::String(
+                    (&mut self.rng)
+                        .sample_iter(&Alphanumeric)
+                        .take(len)
+                        .map(char::from)
+                        .collect(),
+                )
+            }
+            "bool" => Bson::Boolean(self.rng.gen()),
+            _ => return Err(anyhow::anyhow!("unknown $gen: {}", spec)),
+        };
+        Ok(v)
+    }
+}
+
+/// 是否包含 `$gen` 占位符
+pub fn has_gen(v: &Value) -> bool {
+    match v {
+        Value::Object(map) => map.contains_key(GEN_KEY) || map.values().any(has_gen),
+        Value::Array(arr) => arr.iter().any(has_gen),
+        _ => false,
+    }
+}
+
+fn resolve_value(
+    v: &mut Value,
+    path: String,
+    gen: &mut dyn FnMut(&str, &Value) -> anyhow::Result<Bson>,
+) -> anyhow::Result<()> {
+    match v {
+        Value::Object(map) if map.contains_key(GEN_KEY) => {
+            *v = gen(&path, v)?.into_relaxed_extjson();
+        }
+        Value::Object(map) => {
+            for (k, v) in map.iter_mut() {
+                resolve_value(v, format!("{}.{}", path, k), gen)?;
+            }
+        }
+        Value::Array(arr) => {
+            for (i, v) in arr.iter_mut().enumerate() {
+                resolve_value(v, format!("{}.{}", path, i), gen)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn resolve_doc(
+    doc: &mut Document,
+    path: &str,
+    gen: &mut dyn FnMut(&str, &Document) -> anyhow::Result<Bson>,
+) -> anyhow::Result<()> {
+    for (k, v) in doc.iter_mut() {
+        resolve_bson(v, format!("{}.{}", path, k), gen)?;
+    }
+    Ok(())
+}
+
+fn resolve_bson(
+    v: &mut Bson,
+    path: String,
+    gen: &mut dyn FnMut(&str, &Document) -> anyhow::Result<Bson>,
+) -> anyhow::Result<()> {
+    match v {
+        Bson::Document(doc) if doc.contains_key(GEN_KEY) => {
+            *v = gen(&path, doc)?;
+        }
+        Bson::Document(doc) => resolve_doc(doc, &path, gen)?,
+        Bson::Array(arr) => {
+            for (i, v) in arr.iter_mut().enumerate() {
+                resolve_bson(v, format!("{}.{}", path, i), gen)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn load_choices(from: &str) -> anyhow::Result<Arc<Vec<Bson>>> {
+    let mut files = CHOICE_FILES.lock().unwrap();
+    if let Some(values) = files.get(from) {
+        return Ok(values.clone());
+    }
+    let content = fs::read_to_string(from)
+        .map_err(|e| anyhow::anyhow!("$gen choice read {} error: {}", from, e))?;
+    let values: Vec<Bson> = content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            serde_json::from_str::<Value>(line)
+                .ok()
+                .and_then(|v| Bson::try_from(v).ok())
+                .unwrap_or_else(|| Bson::String(line.to_string()))
+        })
+        .collect();
+    let values = Arc::new(values);
+    files.insert(from.to_string(), values.clone());
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[test]
+    fn test_resolve_gen() {
+        let mut row: OpRow = serde_json::from_value(serde_json::json!({
+            "id": "1", "op": "Find", "db": "xgj", "coll": "c", "ns": "xgj.c", "ts": 0,
+            "cmd": { "find": "c", "filter": {
+                "_id": { "$gen": "oid" },
+                "n": { "$gen": "int", "min": 1, "max": 1e6 },
+                "s": { "$in": [{ "$gen": "choice", "values": ["a", "b"], "weights": [1, 0] }] },
+                "t": { "$gt": { "$gen": "now-offset", "sec": -3600 } }
+            } }
+        }))
+        .unwrap();
+        row.args = Document::deserialize(&row.cmd).unwrap();
+
+        let (mut a, mut b) = (row.clone(), row.clone());
+        OpGen::new(7).resolve(&mut a).unwrap();
+        OpGen::new(7).resolve(&mut b).unwrap();
+        assert!(!has_gen(&a.cmd));
+        assert_eq!(a.cmd["filter"]["s"]["$in"][0], "a");
+        assert_eq!(a.cmd["filter"]["_id"], b.cmd["filter"]["_id"]);
+
+        let filter = a.args.get_document("filter").unwrap();
+        assert!(filter.get_object_id("_id").is_ok());
+        let n = filter.get_i32("n").unwrap();
+        assert!((1..=1_000_000).contains(&n));
+        assert_eq!(a.cmd["filter"]["n"], n);
+        assert!(filter
+            .get_document("t")
+            .unwrap()
+            .get_datetime("$gt")
+            .is_ok());
+        // cmd 中生成的 extjson 可以转换回原来的类型
+        let cmd = Document::deserialize(&a.cmd).unwrap();
+        assert_eq!(cmd.get_document("filter").unwrap(), filter);
+
+        assert!(OpGen::new(0)
+            .gen(&serde_json::json!({ "$gen": "nope" }))
+            .is_err());
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::HashSet;
+use std::io::{IsTerminal, Write};
+use std::sync::Mutex;
+
+use bson::doc;
+use mongodb::Client;
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+/// 本进程中已经确认过的集群，同一个集群只需要确认一次
+static CONFIRMED: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// 实际连接到的集群，通过 `hello`（旧版本为 `isMaster`）获取
+#[derive(Debug, Default)]
+pub struct ClusterInfo {
+    /// 副本集名称，mongos 和单节点为 None
+    pub set_name: Option<String>,
+    /// uri 中的地址以及 hello 返回的 hosts/me
+    pub hosts: Vec<String>,
+}
+
+impl ClusterInfo {
+    pub async fn detect(client: &Client, seeds: Vec<String>) -> anyhow::Result<Self> {
+        let admin = client.database("admin");
+        // 4.4.2 之前的版本没有 hello
+        let hello = match admin.run_command(doc! { "hello": 1 }).await {
+            Ok(hello) => hello,
+            Err(_) => admin.run_command(doc! { "isMaster": 1 }).await?,
+        };
+        let mut hosts = seeds;
+        if let Ok(members) = hello.get_array("hosts") {
+            hosts.extend(members.iter().filter_map(|h| h.as_str().map(String::from)));
+        }
+        if let Ok(me) = hello.get_str("me") {
+            hosts.push(me.to_string());
+        }
+        hosts.dedup();
+        Ok(Self {
+            set_name: hello.get_str("setName").ok().map(String::from),
+            hosts,
+        })
+    }
+
+    /// 确认时需要输入的名称
+    pub fn name(&self) -> String {
+        self.set_name
+            .clone()
+            .or_else(|| self.hosts.first().cloned())
+            .unwrap_or_default()
+    }
+
+    /// 匹配到的 protected 规则，支持 `*` 通配符，host 可以省略端口
+    pub fn protected_by(&self, protected: &[String]) -> Option<String> {
+        let names: Vec<&str> = self
+            .set_name
+            .iter()
+            .map(String::as_str)
+            .chain(self.hosts.iter().flat_map(|h| {
+                let host = h.rsplit_once(':').map(|(host, _)| host).unwrap_or(h);
+                [h.as_str(), host]
+            }))
+            .collect();
+        protected
+            .iter()
+            .find(|pattern| {
+                let re = Regex::new(&format!(
+                    "(?i)^{}$",
+                    regex::escape(pattern).replace("\\*", ".*")
+                ))
+                .unwrap();
+                names.iter().any(|name| re.is_match(name))
+            })
+            .cloned()
+    }
+}
+
+/// 在 protected 集群上执行写操作（或者开启 profile 2）之前的保护
+///
+/// allow 为 true（`--i-know-this-is-prod`）时直接放行，否则需要在终端中输入集群名称确认，
+/// 不是交互式终端（包括 ui 中）时拒绝执行
+pub async fn guard(
+    client: &Client,
+    seeds: Vec<String>,
+    protected: &[String],
+    allow: bool,
+    action: &str,
+) -> anyhow::Result<()> {
+    if protected.is_empty() {
+        return Ok(());
+    }
+    let cluster = ClusterInfo::detect(client, seeds).await?;
+    let pattern = match cluster.protected_by(protected) {
+        Some(pattern) => pattern,
+        None => return Ok(()),
+    };
+    let name = cluster.name();
+    if allow {
+        println!(
+            "OPGuard [{}] [{}] protected by `{}`, {} allowed by --i-know-this-is-prod",
+            chrono::Local::now().timestamp(),
+            name,
+            pattern,
+            action
+        );
+        return Ok(());
+    }
+    if CONFIRMED.lock().unwrap().contains(&name) {
+        return Ok(());
+    }
+    let interactive = std::io::stdin().is_terminal()
+        && !ratatui::crossterm::terminal::is_raw_mode_enabled().unwrap_or(true);
+    if !interactive {
+        return Err(anyhow::anyhow!(
+            "OPGuard [{}] is protected by `{}`, refuse to {}, use --i-know-this-is-prod to continue",
+            name,
+            pattern,
+            action
+        ));
+    }
+    print!(
+        "OPGuard [{}] [{}] is protected by `{}`, type `{}` to {}: ",
+        chrono::Local::now().timestamp(),
+        name,
+        pattern,
+        name,
+        action
+    );
+    std::io::stdout().flush()?;
+    let mut input = String::new();
+    std::io::stdin().read_line(&mut input)?;
+    if input.trim() != name {
+        return Err(anyhow::anyhow!("OPGuard [{}] confirmation failed", name));
+    }
+    CONFIRMED.lock().unwrap().insert(name);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_protected_by() {
+        let cluster = ClusterInfo {
+            set_name: Some("rs-prod".to_string()),
+            hosts: vec!["db1.prod.internal:27017".to_string()],
+        };
+        assert_eq!(cluster.name(), "rs-prod");
+        let protected = |patterns: &[&str]| {
+            cluster.protected_by(&patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>())
+        };
+        assert_eq!(protected(&["rs-prod"]), Some("rs-prod".to_string()));
+        assert_eq!(
+            protected(&["localhost", "*.PROD.internal"]),
+            Some("*.PROD.internal".to_string())
+        );
+        assert!(protected(&["db1.prod.internal:27018"]).is_none());
+        assert!(protected(&["rs-*-staging", "prod"]).is_none());
+    }
+}
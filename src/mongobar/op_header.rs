@@ -0,0 +1,291 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use bson::Document;
+use serde::{Deserialize, Serialize};
+
+use super::op_file::{self, OpCompression, OpFormat, OpReader, OpRecord};
+
+/// json 格式的头信息行前缀，老版本读取时会当作 `#` 注释跳过
+pub static HEADER_PREFIX: &str = "#!mongobar ";
+
+/// bson 格式的头信息是第一个文档中的这个字段
+pub static HEADER_KEY: &str = "$mongobar";
+
+pub static HEADER_VERSION: u32 = 1;
+
+/// op 文件的头信息，记录文件的格式版本和来源，写在文件的第一条记录
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct OpHeader {
+    pub version: u32,
+    /// 来源：profile/oplog/audit:aliyun/mongod-log/proxy 等
+    pub source: String,
+    #[serde(default)]
+    pub db: String,
+    /// 来源集群的地址，不包含账号密码
+    #[serde(default)]
+    pub cluster: String,
+    /// 录制的时间范围（毫秒），未指定时为记录中的最小/最大 ts
+    #[serde(default)]
+    pub start_ts: i64,
+    #[serde(default)]
+    pub end_ts: i64,
+    #[serde(default)]
+    pub count: usize,
+    /// 每种 Op 的数量
+    #[serde(default)]
+    pub ops: BTreeMap<String, usize>,
+    #[serde(default)]
+    pub created_at: i64,
+    /// 头信息之后的字节数，文件被追加或者修改后和实际的大小不一致，count 不再可信
+    #[serde(default)]
+    pub size: u64,
+}
+
+impl OpHeader {
+    pub fn new(source: &str, db: &str, uri: &str) -> Self {
+        Self {
+            version: HEADER_VERSION,
+            source: source.to_string(),
+            db: db.to_string(),
+            cluster: uri_hosts(uri),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_time_range(mut self, start_ts: i64, end_ts: i64) -> Self {
+        self.start_ts = start_ts;
+        self.end_ts = end_ts;
+        self
+    }
+
+    /// 如果记录是头信息则解析出来
+    pub fn from_record(record: &OpRecord) -> Option<Self> {
+        match record {
+            OpRecord::Line(line) => serde_json::from_str(line.strip_prefix(HEADER_PREFIX)?).ok(),
+            OpRecord::Bson(doc) => {
+                bson::from_document(doc.get_document(HEADER_KEY).ok()?.clone()).ok()
+            }
+        }
+    }
+
+    pub fn write(&self, writer: &mut dyn Write, format: OpFormat) -> anyhow::Result<()> {
+        match format {
+            OpFormat::Json => {
+                writeln!(writer, "{}{}", HEADER_PREFIX, serde_json::to_string(self)?)?
+            }
+            OpFormat::Bson => {
+                let mut doc = Document::new();
+                doc.insert(HEADER_KEY, bson::to_document(self)?);
+                doc.to_writer(writer)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 按照文件的格式和压缩方式编码，压缩文件中头信息是单独的 gzip member / zstd frame
+    fn encode(&self, path: &Path) -> anyhow::Result<Vec<u8>> {
+        let mut raw = vec![];
+        self.write(&mut raw, OpFormat::from_path(path))?;
+        Ok(match OpCompression::from_path(path) {
+            OpCompression::None => raw,
+            OpCompression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+                encoder.write_all(&raw)?;
+                encoder.finish()?
+            }
+            OpCompression::Zstd => zstd::encode_all(&raw[..], 0)?,
+        })
+    }
+
+    /// 头信息写入后文件没有被修改过（追加、手动合并等），其中的 count 可以直接使用
+    pub fn is_current(&self, path: &Path) -> bool {
+        match (fs::metadata(path), self.encode(path)) {
+            (Ok(meta), Ok(encoded)) => meta.len() == encoded.len() as u64 + self.size,
+            _ => false,
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        let ops: Vec<String> = self
+            .ops
+            .iter()
+            .map(|(op, n)| format!("{}:{}", op, n))
+            .collect();
+        format!(
+            "v{} source: {} db: {} cluster: {} range: {} ~ {} rows: {} ops: [{}]",
+            self.version,
+            self.source,
+            self.db,
+            self.cluster,
+            bson::DateTime::from_millis(self.start_ts),
+            bson::DateTime::from_millis(self.end_ts),
+            self.count,
+            ops.join(" ")
+        )
+    }
+}
+
+/// 读取文件的头信息，没有头信息的老文件返回 None
+pub fn read(path: &Path) -> Option<OpHeader> {
+    let mut reader = OpReader::open(path).ok()?;
+    let record = reader.read_record().ok()??;
+    OpHeader::from_record(&record)
+}
+
+/// 统计文件中的记录并把头信息写到文件的第一条，已有的头信息会被替换
+pub fn stamp(path: &Path, mut header: OpHeader) -> anyhow::Result<OpHeader> {
+    let mut count = 0;
+    let mut ops: BTreeMap<String, usize> = BTreeMap::new();
+    let (mut min_ts, mut max_ts) = (i64::MAX, i64::MIN);
+
+    let mut reader = OpReader::open(path)?;
+    while let Some(record) = reader.read_record()? {
+        let (op, ts) = match &record {
+            _ if !record.is_row() => continue,
+            OpRecord::Line(line) => {
+                let row: RowMeta = serde_json::from_str(line)?;
+                (row.op, row.ts)
+            }
+            OpRecord::Bson(doc) => (
+                doc.get_str("op").unwrap_or_default().to_string(),
+                doc.get_i64("ts").unwrap_or_default(),
+            ),
+        };
+        count += 1;
+        *ops.entry(op).or_default() += 1;
+        min_ts = min_ts.min(ts);
+        max_ts = max_ts.max(ts);
+    }
+
+    header.version = HEADER_VERSION;
+    header.count = count;
+    header.ops = ops;
+    header.created_at = chrono::Local::now().timestamp_millis();
+    if header.start_ts == 0 && header.end_ts == 0 && count > 0 {
+        header.start_ts = min_ts;
+        header.end_ts = max_ts;
+    }
+
+    let tmp = op_file::sibling_tmp(path);
+    let body = op_file::sibling_tmp_with(path, "body");
+    {
+        let mut writer = op_file::create(&body)?;
+        let mut reader = OpReader::open(path)?;
+        while let Some(record) = reader.read_record()? {
+            match record {
+                OpRecord::Line(line) if !line.starts_with(HEADER_PREFIX) => {
+                    writeln!(writer, "{}", line)?
+                }
+                OpRecord::Bson(doc) if !doc.contains_key(HEADER_KEY) => {
+                    doc.to_writer(&mut writer)?
+                }
+                _ => {}
+            }
+        }
+        writer.flush()?;
+    }
+    // 头信息中记录之后的字节数，读取时用来判断文件是否被修改过
+    header.size = fs::metadata(&body)?.len();
+    {
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(&header.encode(path)?)?;
+        io::copy(&mut fs::File::open(&body)?, &mut file)?;
+    }
+    fs::remove_file(&body)?;
+    fs::rename(&tmp, path)?;
+
+    Ok(header)
+}
+
+/// 写入 `{report}.header.json`，测试结果可以追溯到录制的来源
+pub fn write_sidecar(header: &OpHeader, path: &Path) -> io::Result<PathBuf> {
+    let sidecar = path.with_extension("header.json");
+    fs::write(&sidecar, serde_json::to_string_pretty(header)?)?;
+    Ok(sidecar)
+}
+
+#[derive(Deserialize)]
+struct RowMeta {
+    op: String,
+    #[serde(default)]
+    ts: i64,
+}
+
+/// `mongodb://user:pass@a:27017,b:27017/db?x=y` => `a:27017,b:27017`
+fn uri_hosts(uri: &str) -> String {
+    let rest = uri.split_once("://").map(|v| v.1).unwrap_or(uri);
+    let rest = rest.rsplit_once('@').map(|v| v.1).unwrap_or(rest);
+    rest.split(['/', '?'])
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::op_fixture::{row, write_rows, TempDir};
+    use super::*;
+
+    const FORMATS: [&str; 2] = ["a.op", "a.opb.zst"];
+
+    fn stamped(dir: &TempDir, name: &str) -> (PathBuf, OpHeader) {
+        let path = dir.join(name);
+        let rows: Vec<_> = [("Find", 3), ("Find", 1), ("Insert", 2)]
+            .into_iter()
+            .map(|(op, ts)| row(serde_json::json!({ "id": "1", "op": op, "ts": ts })))
+            .collect();
+        write_rows(&path, &rows);
+        let header = OpHeader::new("profile", "xgj", "mongodb://u:p@a:27017,b:27017/xgj?x=1");
+        stamp(&path, header.clone()).unwrap();
+        // 重复写入会替换已有的头信息
+        stamp(&path, header).unwrap();
+        let header = read(&path).unwrap();
+        (path, header)
+    }
+
+    #[test]
+    fn test_stamp_header() {
+        let dir = TempDir::new("op-header");
+        for name in FORMATS {
+            let (_, header) = stamped(&dir, name);
+            assert_eq!(header.cluster, "a:27017,b:27017", "{}", name);
+            assert_eq!(header.count, 3, "{}", name);
+            assert_eq!(header.ops["Find"], 2, "{}", name);
+            assert_eq!((header.start_ts, header.end_ts), (1, 3), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_stamp_replaces_header() {
+        let dir = TempDir::new("op-header");
+        for name in FORMATS {
+            let (path, header) = stamped(&dir, name);
+            let mut reader = OpReader::open(&path).unwrap();
+            let mut records = 0;
+            while let Some(record) = reader.read_record().unwrap() {
+                records += 1;
+                assert_eq!(record.is_row(), records > 1, "{}", name);
+            }
+            assert_eq!(records, 4, "{}", name);
+            assert!(header.is_current(&path), "{}", name);
+            // 临时文件都已经清理
+            assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1, "{}", name);
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_header_stale_after_append() {
+        let dir = TempDir::new("op-header");
+        for name in FORMATS {
+            let (path, header) = stamped(&dir, name);
+            // 追加之后头信息中的 count 不再可信
+            write_rows(&path, &[row(serde_json::json!({ "id": "2", "ts": 4 }))]);
+            assert!(!header.is_current(&path), "{}", name);
+        }
+    }
+}
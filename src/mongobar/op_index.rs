@@ -0,0 +1,178 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use super::op_file::OpReader;
+
+static MAGIC: &[u8; 8] = b"MBIDX\0\0\x01";
+
+/// `{op_file}.idx`：每条 OpRow 在（解压后的）文件中的起始位置，不包括注释和头信息
+///
+/// 文件格式为小端的 u64：magic | 源文件大小 | 源文件修改时间(ns) | 结束位置 | 条数 | offsets...
+/// 源文件大小或者修改时间变化后索引失效，需要重新构建
+#[derive(Debug, Clone, Default)]
+pub struct OpIndex {
+    pub offsets: Vec<u64>,
+    /// 最后一条记录的结束位置
+    pub end: u64,
+}
+
+impl OpIndex {
+    pub fn path(op_file: &Path) -> PathBuf {
+        PathBuf::from(format!("{}.idx", op_file.to_str().unwrap()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+
+    /// 第 n 条记录的起始位置
+    pub fn offset(&self, n: usize) -> Option<u64> {
+        self.offsets.get(n).copied()
+    }
+
+    pub fn build(op_file: &Path) -> io::Result<Self> {
+        let mut reader = OpReader::open(op_file)?;
+        let mut offsets = vec![];
+        loop {
+            let pos = reader.pos;
+            match reader.read_record()? {
+                Some(record) => {
+                    if record.is_row() {
+                        offsets.push(pos);
+                    }
+                }
+                None => break,
+            }
+        }
+        Ok(Self {
+            offsets,
+            end: reader.pos,
+        })
+    }
+
+    pub fn save(&self, op_file: &Path) -> io::Result<PathBuf> {
+        let (size, mtime) = fingerprint(op_file)?;
+        let path = Self::path(op_file);
+        let mut w = BufWriter::new(File::create(&path)?);
+        w.write_all(MAGIC)?;
+        for v in [size, mtime, self.end, self.offsets.len() as u64] {
+            w.write_all(&v.to_le_bytes())?;
+        }
+        for offset in self.offsets.iter() {
+            w.write_all(&offset.to_le_bytes())?;
+        }
+        w.flush()?;
+        Ok(path)
+    }
+
+    /// 读取索引，不存在或者已经失效返回 None
+    pub fn load(op_file: &Path) -> Option<Self> {
+        let (size, mtime) = fingerprint(op_file).ok()?;
+        let mut r = BufReader::new(File::open(Self::path(op_file)).ok()?);
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic).ok()?;
+        if &magic != MAGIC {
+            return None;
+        }
+        let mut read_u64 = || -> Option<u64> {
+            let mut buf = [0u8; 8];
+            r.read_exact(&mut buf).ok()?;
+            Some(u64::from_le_bytes(buf))
+        };
+        if read_u64()? != size || read_u64()? != mtime {
+            return None;
+        }
+        let end = read_u64()?;
+        let count = read_u64()? as usize;
+        let mut offsets = Vec::with_capacity(count);
+        for _ in 0..count {
+            offsets.push(read_u64()?);
+        }
+        Some(Self { offsets, end })
+    }
+
+    /// 首次使用时构建并保存，保存失败（如只读目录）不影响使用
+    pub fn load_or_build(op_file: &Path) -> io::Result<Self> {
+        if let Some(index) = Self::load(op_file) {
+            return Ok(index);
+        }
+        let index = Self::build(op_file)?;
+        let _ = index.save(op_file);
+        Ok(index)
+    }
+
+    /// 按条数切分为 parts 段 [start, end) 的字节范围，用于多线程并行加载
+    pub fn ranges(&self, parts: usize) -> Vec<(u64, u64)> {
+        if self.is_empty() {
+            return vec![];
+        }
+        let step = self.offsets.len().div_ceil(parts.max(1));
+        self.offsets
+            .chunks(step)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let end = self
+                    .offsets
+                    .get((i + 1) * step)
+                    .copied()
+                    .unwrap_or(self.end);
+                (chunk[0], end)
+            })
+            .collect()
+    }
+}
+
+fn fingerprint(op_file: &Path) -> io::Result<(u64, u64)> {
+    let meta = fs::metadata(op_file)?;
+    let mtime = meta
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default();
+    Ok((meta.len(), mtime))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::op_fixture::TempDir;
+    use super::*;
+
+    fn write_op(dir: &TempDir) -> PathBuf {
+        let path = dir.join("a.op");
+        fs::write(&path, "#!mongobar {}\nrow0\n# comment\nrow1\nrow2\n").unwrap();
+        path
+    }
+
+    #[test]
+    fn test_build_index() {
+        let dir = TempDir::new("op-index");
+        let index = OpIndex::load_or_build(&write_op(&dir)).unwrap();
+        // 头信息和注释不计入索引
+        assert_eq!(index.offsets, vec![14, 29, 34]);
+        assert_eq!(index.end, 39);
+        assert_eq!(index.ranges(2), vec![(14, 34), (34, 39)]);
+    }
+
+    #[test]
+    fn test_load_index() {
+        let dir = TempDir::new("op-index");
+        let path = write_op(&dir);
+        let index = OpIndex::load_or_build(&path).unwrap();
+        assert_eq!(OpIndex::load(&path).unwrap().offsets, index.offsets);
+    }
+
+    #[test]
+    fn test_index_stale_after_change() {
+        let dir = TempDir::new("op-index");
+        let path = write_op(&dir);
+        OpIndex::load_or_build(&path).unwrap();
+        fs::write(&path, "row0\n").unwrap();
+        assert!(OpIndex::load(&path).is_none());
+    }
+}
@@ -1,16 +1,21 @@
-use std::fs::{self, File, OpenOptions};
+use std::fs::{self, File};
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::io::Write;
 use std::sync::atomic::AtomicUsize;
-use std::sync::{Arc, Mutex, RwLock};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
 
 use bson::Document;
-use regex::Regex;
+use rayon::prelude::*;
 use serde::Deserialize;
 use serde_json::Value;
 
+use super::op_file::{self, OpCompression, OpReader, OpRecord};
+use super::op_filter::OpFilter;
+use super::op_header;
+use super::op_index::OpIndex;
+use super::op_ns_map::NsMap;
 use super::op_row::{self, OpRow};
 
 static BUFF_SIZE: usize = 10000;
@@ -32,16 +37,36 @@ pub struct OpLogs {
     pub op_file: PathBuf,
     pub length: usize,
     pub mode: OpReadMode,
-    pub buf_reader: Option<Mutex<BufReader<File>>>,
+    pub buf_reader: Option<Mutex<OpReader>>,
+    /// StreamLine 模式下顺序读取时复用，压缩文件不能 seek，重新打开需要从头解压
+    pub stream_reader: Mutex<Option<OpReader>>,
     pub lock: Arc<Mutex<()>>,
     pub ignore_field: Vec<String>,
+    /// `.idx` 索引，需要随机访问时才会加载或构建
+    pub row_index: OnceLock<Option<OpIndex>>,
+    /// 回放到其他库时的命名空间映射，在加载时重写
+    pub ns_map: NsMap,
 }
 
 impl OpLogs {
     pub fn new(op_file: PathBuf, mode: OpReadMode, ignore_field: Vec<String>) -> Self {
+        // 头信息之后没有修改过时直接使用其中的条数，否则通过索引获取，都不需要每次读取整个文件
+        let header = op_header::read(&op_file);
+        let row_index = OnceLock::new();
+        let length = match header {
+            Some(header) if header.is_current(&op_file) => header.count,
+            _ => match OpIndex::load_or_build(&op_file) {
+                Ok(index) => {
+                    let length = index.len();
+                    let _ = row_index.set(Some(index));
+                    length
+                }
+                Err(_) => count_records(op_file.to_str().unwrap()),
+            },
+        };
         Self {
             op_file: op_file.clone(),
-            length: count_lines(op_file.to_str().unwrap()),
+            length,
             // buffer: RwLock::new(Vec::new()),
             // next_buffer: RwLock::new(Vec::new()),
             buffers: [RwLock::new(Vec::new()), RwLock::new(Vec::new())],
@@ -51,30 +76,68 @@ impl OpLogs {
             index: AtomicUsize::new(0),
             lock: Arc::new(Mutex::new(())),
             buf_reader: if let OpReadMode::ReadLine(_) = mode {
-                Some(Mutex::new(BufReader::with_capacity(
-                    1024 * 1024 * 100,
-                    File::open(op_file.to_str().unwrap()).unwrap(),
-                )))
+                Some(Mutex::new(
+                    OpReader::open_with_capacity(&op_file, 1024 * 1024 * 100).unwrap(),
+                ))
             } else {
                 None
             },
+            stream_reader: Mutex::new(None),
             mode,
             ignore_field,
+            row_index,
+            ns_map: NsMap::default(),
+        }
+    }
+
+    pub fn set_ns_map(mut self, ns_map: NsMap) -> Self {
+        self.ns_map = ns_map;
+        self
+    }
+
+    /// 转换为可以执行的 OpRow 并映射命名空间
+    fn prepare_row(&self, row: OpRow) -> anyhow::Result<OpRow> {
+        let mut row = trans_value_to_doc(row, &self.ignore_field);
+        self.ns_map.apply(&mut row)?;
+        Ok(row)
+    }
+
+    /// 逐行读取时映射失败的记录（例如 pipeline 关联的集合映射到其他库）输出后跳过
+    fn prepare_row_or_skip(&self, row: OpRow) -> Option<OpRow> {
+        let id = row.id.clone();
+        match self.prepare_row(row) {
+            Ok(row) => Some(row),
+            Err(e) => {
+                eprintln!(
+                    "OpLogs [{}] skip record {}: {}",
+                    chrono::Local::now().timestamp(),
+                    id,
+                    e
+                );
+                None
+            }
         }
     }
 
-    pub fn init(mut self) -> Self {
+    pub fn row_index(&self) -> Option<&OpIndex> {
+        self.row_index
+            .get_or_init(|| OpIndex::load_or_build(&self.op_file).ok())
+            .as_ref()
+    }
+
+    /// 加载数据，过滤条件错误时返回错误
+    pub fn init(mut self) -> anyhow::Result<Self> {
         match &self.mode {
             OpReadMode::StreamLine => {
                 self.load_stream_line(0, 0);
                 // self.load_stream_line(1);
             }
             OpReadMode::FullLine(filter) => {
-                self.load_full_line(filter.clone());
+                self.load_full_line(filter.clone())?;
             }
             OpReadMode::ReadLine(_) => {}
         }
-        self
+        Ok(self)
     }
 
     pub fn load_stream_line(&self, active: usize, offset: usize) -> usize {
@@ -95,80 +158,174 @@ impl OpLogs {
             .store(offset + BUFF_SIZE, std::sync::atomic::Ordering::SeqCst);
 
         let byte_offset = self.byte_offset.load(std::sync::atomic::Ordering::SeqCst);
-        let mut byte_size = 0;
-        let buffer: Vec<OpRow> =
-            read_file_part_pro(self.op_file.to_str().unwrap(), byte_offset, BUFF_SIZE)
-                .iter()
-                .filter(|line| !line.starts_with("#") && !line.trim().is_empty())
-                .map(|line: &String| {
-                    byte_size += line.len() + 1;
-                    // append file
-                    // let mut file = OpenOptions::new()
-                    //     .create(true)
-                    //     .write(true)
-                    //     .append(true)
-                    //     .open("test.log")
-                    //     .unwrap();
-                    // writeln!(file, "[{}]{}", line.len(), line).unwrap();
-                    serde_json::from_str(&line).expect(&line)
-                })
-                .map(|item| trans_value_to_doc(item, &self.ignore_field))
-                .collect();
+        let (records, next_byte_offset) = self.read_stream_part(byte_offset, BUFF_SIZE);
+        let buffer: Vec<OpRow> = records
+            .into_iter()
+            .filter_map(record_to_row)
+            .filter_map(|item| self.prepare_row_or_skip(item))
+            .collect();
         let len = buffer.len();
 
         self.byte_offset
-            .store(byte_size + byte_offset, std::sync::atomic::Ordering::SeqCst);
+            .store(next_byte_offset, std::sync::atomic::Ordering::SeqCst);
 
         *buffer_write = buffer;
 
         len
     }
 
-    pub fn load_full_line(&mut self, filter: Option<String>) {
-        let buffer: Vec<OpRow> = read_file_part(self.op_file.to_str().unwrap(), 0, self.length)
-            .iter()
-            .filter(|line| !line.starts_with("#") && !line.trim().is_empty())
-            .filter(|line| {
-                if let Some(filter) = &filter {
-                    let filter = Regex::new(filter).unwrap();
-                    return filter.is_match(line);
-                }
-                return true;
-            })
-            .map(|line| serde_json::from_str(&line).unwrap())
-            .map(|item| trans_value_to_doc(item, &self.ignore_field))
-            .collect();
+    /// 从解压后的 byte_offset 读取 length 条 OpRow 记录（跳过注释和头信息），返回读到的记录和下一次读取的位置
+    fn read_stream_part(&self, byte_offset: usize, length: usize) -> (Vec<OpRecord>, usize) {
+        let mut stream_reader = self.stream_reader.lock().unwrap();
+        let mut reader = match stream_reader.take() {
+            Some(reader) if reader.pos == byte_offset as u64 => reader,
+            _ => OpReader::open_at(&self.op_file, byte_offset as u64).unwrap(),
+        };
+        let mut buffer: Vec<OpRecord> = Vec::new();
+        while buffer.len() < length {
+            match reader.read_record().unwrap() {
+                Some(record) if record.is_row() => buffer.push(record),
+                Some(_) => {}
+                None => break,
+            }
+        }
+        let next = reader.pos as usize;
+        *stream_reader = Some(reader);
+        (buffer, next)
+    }
+
+    pub fn load_full_line(&mut self, filter: Option<String>) -> anyhow::Result<()> {
+        let filter = filter.map(|filter| OpFilter::parse(&filter)).transpose()?;
+        let to_rows = |records: Vec<OpRecord>| -> anyhow::Result<Vec<OpRow>> {
+            records
+                .into_iter()
+                .filter(|record| match &filter {
+                    Some(filter) => filter.matches_record(record),
+                    None => true,
+                })
+                .filter_map(record_to_row)
+                .map(|item| trans_value_to_doc(item, &self.ignore_field))
+                .filter(|row| match &filter {
+                    Some(filter) => filter.matches_row(row),
+                    None => true,
+                })
+                .map(|mut row| self.ns_map.apply(&mut row).map(|_| row))
+                .collect()
+        };
+        let ranges = match self.row_index() {
+            // 未压缩的文件可以通过索引切分后多线程并行加载
+            Some(index)
+                if index.len() > BUFF_SIZE
+                    && OpCompression::from_path(&self.op_file) == OpCompression::None =>
+            {
+                index.ranges(rayon::current_num_threads())
+            }
+            _ => vec![],
+        };
+        let buffer: Vec<OpRow> = if ranges.is_empty() {
+            to_rows(read_file_part(
+                self.op_file.to_str().unwrap(),
+                0,
+                self.length,
+            ))?
+        } else {
+            ranges
+                .into_par_iter()
+                .map(|(start, end)| to_rows(read_byte_range(&self.op_file, start, end)))
+                .collect::<anyhow::Result<Vec<Vec<OpRow>>>>()?
+                .concat()
+        };
         self.full_buffer = buffer;
         self.length = self.full_buffer.len();
+        Ok(())
     }
 
+    /// 追加一行，支持压缩文件和 `.opb`，每次调用都会打开文件（压缩文件会追加一个 frame），
+    /// 连续写入多行时使用 [`op_file::OpWriter`]
+    #[allow(dead_code)]
     pub fn push_line(op_file: PathBuf, row: op_row::OpRow) {
-        let mut file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(op_file)
-            .unwrap();
-        let content = serde_json::to_string(&row.clone()).unwrap();
-        writeln!(file, "{}", content).unwrap();
+        let mut writer = op_file::OpWriter::append(&op_file).unwrap();
+        writer.write(&row).unwrap();
+        writer.finish().unwrap();
     }
 
     pub fn len(&self) -> usize {
         return self.length;
     }
 
+    /// 第 start 条开始的 length 条 OpRow，不过滤
     pub fn limit(&self, start: usize, length: usize) -> Vec<op_row::OpRow> {
-        return read_file_part(self.op_file.to_str().unwrap(), start, length)
-            .iter()
-            .filter(|line| {
-                if let OpReadMode::FullLine(Some(filter)) = &self.mode {
-                    let filter = Regex::new(filter).unwrap();
-                    return filter.is_match(line);
+        let records = match self.row_index().and_then(|index| index.offset(start)) {
+            // 通过索引直接定位到第 start 条
+            Some(offset) => read_rows_at(&self.op_file, offset, length),
+            None => read_file_part(self.op_file.to_str().unwrap(), start, length),
+        };
+        records.into_iter().filter_map(record_to_row).collect()
+    }
+
+    /// 满足过滤条件的 OpRow 的序号，按照过滤后的结果分页时使用
+    pub fn matched_rows(&self, filter: &str) -> anyhow::Result<Vec<usize>> {
+        let filter = OpFilter::parse(filter)?;
+        let mut reader = OpReader::open(&self.op_file)?;
+        let mut rows = vec![];
+        let mut n = 0;
+        while let Some(record) = reader.read_record()? {
+            if !record.is_row() {
+                continue;
+            }
+            if filter.matches_record(&record)
+                && record_to_row(record).is_some_and(|row| filter.matches_row(&row))
+            {
+                rows.push(n);
+            }
+            n += 1;
+        }
+        Ok(rows)
+    }
+
+    /// 按照序号读取 OpRow，有索引时直接定位
+    pub fn rows_at(&self, rows: &[usize]) -> Vec<op_row::OpRow> {
+        match self.row_index() {
+            Some(index) => rows
+                .iter()
+                .filter_map(|n| index.offset(*n))
+                .flat_map(|offset| read_rows_at(&self.op_file, offset, 1))
+                .filter_map(record_to_row)
+                .collect(),
+            None => {
+                let last = match rows.iter().max() {
+                    Some(last) => *last,
+                    None => return vec![],
+                };
+                read_file_part(self.op_file.to_str().unwrap(), 0, last + 1)
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(n, _)| rows.contains(n))
+                    .filter_map(|(_, record)| record_to_row(record))
+                    .collect()
+            }
+        }
+    }
+
+    /// 跳过前 n 条 OpRow，用于从断点继续，ReadLine 模式不转换为 OpRow
+    pub fn skip(&self, n: usize) {
+        if let OpReadMode::ReadLine(_) = self.mode {
+            let mut buf_reader = self.buf_reader.as_ref().unwrap().lock().unwrap();
+            let mut skipped = 0;
+            while skipped < n {
+                match buf_reader.read_record() {
+                    Ok(Some(record)) if record.is_row() => skipped += 1,
+                    Ok(Some(_)) => {}
+                    _ => break,
                 }
-                return true;
-            })
-            .map(|line| serde_json::from_str(&line).unwrap())
-            .collect();
+            }
+            return;
+        }
+        for _ in 0..n {
+            if self.read(0, 0).is_none() {
+                break;
+            }
+        }
     }
 
     pub fn read(&self, thread_index: usize, row_index: usize) -> Option<op_row::OpRow> {
@@ -235,130 +392,198 @@ impl OpLogs {
             OpReadMode::ReadLine(never_stop) => {
                 // println!("read line");
                 let mut buf_reader = self.buf_reader.as_ref().unwrap().lock().unwrap();
-                let mut buffer = String::new();
 
-                if let Ok(b) = buf_reader.read_line(&mut buffer) {
-                    // println!("read lined {:?}", buffer);
-
-                    if b == 0 {
-                        if never_stop {
-                            buf_reader.seek(SeekFrom::Start(0)).unwrap();
+                // 注释、空行和头信息直接跳过，不作为空的 OpRow 执行
+                loop {
+                    let record = match buf_reader.read_record() {
+                        Ok(Some(record)) => record,
+                        Ok(None) => {
+                            if never_stop {
+                                buf_reader.rewind().unwrap();
+                            }
+                            return None;
                         }
-                        return None;
+                        Err(_) => return None,
+                    };
+                    if let Some(row) =
+                        record_to_row(record).and_then(|row| self.prepare_row_or_skip(row))
+                    {
+                        return Some(row);
                     }
-                } else {
-                    return None;
-                }
-                if buffer.starts_with("#") || buffer.trim().is_empty() {
-                    return Some(OpRow::default());
-                } else {
-                    let row: OpRow = serde_json::from_str(&buffer).unwrap();
-                    return Some(trans_value_to_doc(row, &self.ignore_field));
                 }
             }
         }
     }
 }
 
-fn count_lines(file_path: &str) -> usize {
-    let file = File::open(file_path).expect("Failed to open file");
-    let mut reader = BufReader::new(file);
-    let mut line_count = 0;
-
-    let mut buffer = String::new();
-    while reader.read_line(&mut buffer).unwrap_or(0) > 0 {
-        line_count += 1;
-        buffer.clear();
+/// 转换为 OpRow，注释、空行和头信息返回 None，无法解析的记录输出后跳过
+fn record_to_row(record: OpRecord) -> Option<OpRow> {
+    if !record.is_row() {
+        return None;
+    }
+    let row = match record {
+        OpRecord::Line(line) => serde_json::from_str(&line).map_err(|e| format!("{}: {}", e, line)),
+        OpRecord::Bson(doc) => OpRow::from_bson(doc).map_err(|e| e.to_string()),
+    };
+    match row {
+        Ok(row) => Some(row),
+        Err(e) => {
+            eprintln!(
+                "OpLogs [{}] skip bad record {}",
+                chrono::Local::now().timestamp(),
+                e
+            );
+            None
+        }
     }
-
-    line_count
 }
 
-fn read_file_part_pro(file_path: &str, offset: usize, length: usize) -> Vec<String> {
-    let file = File::open(file_path).unwrap();
-    let mut reader = BufReader::new(file.try_clone().unwrap());
-    reader.seek(SeekFrom::Start(offset as u64)).unwrap();
-    let mut buffer: Vec<String> = Vec::new();
-    for (i, line) in reader.lines().enumerate() {
-        if i < length {
-            buffer.push(line.unwrap());
+fn count_records(file_path: &str) -> usize {
+    let mut reader = OpReader::open(Path::new(file_path)).expect("Failed to open file");
+    let mut count = 0;
+    while let Ok(Some(record)) = reader.read_record() {
+        if record.is_row() {
+            count += 1;
         }
     }
 
-    return buffer;
+    count
 }
 
-fn read_file_part(file_path: &str, start: usize, length: usize) -> Vec<String> {
-    let file = File::open(file_path).unwrap();
-    let reader = BufReader::new(file.try_clone().unwrap());
-    let mut buffer: Vec<String> = Vec::new();
-    for (i, line) in reader.lines().enumerate() {
-        if i >= start && i < start + length {
-            buffer.push(line.unwrap());
+/// 读取第 start 条开始的 length 条 OpRow 记录，注释和头信息不计数
+fn read_file_part(file_path: &str, start: usize, length: usize) -> Vec<OpRecord> {
+    let mut reader = OpReader::open(Path::new(file_path)).unwrap();
+    let mut buffer: Vec<OpRecord> = Vec::new();
+    let mut i = 0;
+    while i < start + length {
+        match reader.read_record().unwrap() {
+            Some(record) if record.is_row() => {
+                if i >= start {
+                    buffer.push(record);
+                }
+                i += 1;
+            }
+            Some(_) => {}
+            None => break,
         }
     }
 
     return buffer;
 }
 
-/// 翻转文件
-pub fn reverse_file(file_path: &str) -> std::io::Result<()> {
-    if !PathBuf::from(file_path).exists() {
-        let _ = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(file_path.to_string())
-            .unwrap();
-        return Ok(());
+/// 从 offset 开始读取 length 条 OpRow 记录
+fn read_rows_at(op_file: &Path, offset: u64, length: usize) -> Vec<OpRecord> {
+    let mut reader = OpReader::open_at(op_file, offset).unwrap();
+    let mut buffer: Vec<OpRecord> = Vec::new();
+    while buffer.len() < length {
+        match reader.read_record().unwrap() {
+            Some(record) if record.is_row() => buffer.push(record),
+            Some(_) => {}
+            None => break,
+        }
     }
+    buffer
+}
 
-    let mut reverse = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(file_path.to_string() + ".reverse")
-        .unwrap();
-
-    let file = File::open(file_path)?;
-    let mut reader = BufReader::new(file);
-    let file_size = reader.seek(SeekFrom::End(0))?;
-
-    let mut line = String::new();
-    let mut current_position = file_size;
+/// 读取 [start, end) 字节范围内的记录
+fn read_byte_range(op_file: &Path, start: u64, end: u64) -> Vec<OpRecord> {
+    let mut reader = OpReader::open_at(op_file, start).unwrap();
+    let mut buffer: Vec<OpRecord> = Vec::new();
+    while reader.pos < end {
+        match reader.read_record().unwrap() {
+            Some(record) => buffer.push(record),
+            None => break,
+        }
+    }
+    buffer
+}
 
-    while current_position > 0 {
-        current_position -= 1;
-        reader.seek(SeekFrom::Start(current_position))?;
+/// reverse_file 每段的大小，超过后翻转写入临时文件
+static REVERSE_CHUNK_BYTES: usize = 64 * 1024 * 1024;
 
-        let mut byte = [0; 1];
-        reader.read_exact(&mut byte)?;
+/// 翻转文件中记录的顺序，头信息保持在第一条
+///
+/// 分段读取，每段在内存中翻转后写入临时文件，最后按照相反的顺序拼接到同目录的临时文件中再替换原文件，
+/// 出错时原文件不变
+pub fn reverse_file(file_path: &str) -> std::io::Result<()> {
+    reverse_file_with_chunk(Path::new(file_path), REVERSE_CHUNK_BYTES)
+}
 
-        if byte[0] == b'\n' {
-            if !line.is_empty() {
-                line.push('\n');
-                reverse.write_all(line.as_bytes())?;
-                line.clear();
+fn reverse_file_with_chunk(path: &Path, chunk_bytes: usize) -> std::io::Result<()> {
+    if !path.exists() {
+        File::create(path)?;
+        return Ok(());
+    }
+    let name = path.file_name().unwrap().to_str().unwrap();
+    let run_path = |i: usize| path.with_file_name(format!(".{}.run{}", name, i));
+    let write_chunk = |writer: &mut dyn Write, chunk: &[Vec<u8>]| -> std::io::Result<()> {
+        for record in chunk.iter().rev() {
+            writer.write_all(record)?;
+        }
+        Ok(())
+    };
+
+    let mut reader = OpReader::open(path)?;
+    let mut header = None;
+    let mut chunk: Vec<Vec<u8>> = vec![];
+    let mut chunk_size = 0;
+    let mut runs = 0;
+    let mut first = true;
+    let result = (|| -> std::io::Result<()> {
+        while let Some(record) = reader.read_record()? {
+            let is_header = first && op_header::OpHeader::from_record(&record).is_some();
+            first = false;
+            let bytes = match record {
+                OpRecord::Line(line) if line.is_empty() => continue,
+                OpRecord::Line(line) => line.into_bytes().into_iter().chain([b'\n']).collect(),
+                OpRecord::Bson(doc) => bson::to_vec(&doc)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?,
+            };
+            if is_header {
+                header = Some(bytes);
+                continue;
+            }
+            chunk_size += bytes.len();
+            chunk.push(bytes);
+            if chunk_size >= chunk_bytes {
+                let mut run = std::io::BufWriter::new(File::create(run_path(runs))?);
+                runs += 1;
+                write_chunk(&mut run, &chunk)?;
+                run.flush()?;
+                chunk.clear();
+                chunk_size = 0;
             }
-        } else {
-            line.insert(0, byte[0] as char);
         }
-    }
-
-    if !line.is_empty() {
-        reverse.write_all(line.as_bytes())?;
-    }
 
-    reverse.write(b"\n")?;
-
-    fs::rename(file_path.to_string() + ".reverse", file_path)?;
+        let tmp = op_file::sibling_tmp(path);
+        let mut writer = op_file::Encoder::create(&tmp)?;
+        if let Some(header) = &header {
+            writer.write_all(header)?;
+        }
+        write_chunk(&mut writer, &chunk)?;
+        for i in (0..runs).rev() {
+            std::io::copy(&mut File::open(run_path(i))?, &mut writer)?;
+        }
+        writer.finish()?;
+        fs::rename(&tmp, path)
+    })();
 
-    Ok(())
+    for i in 0..runs {
+        let _ = fs::remove_file(run_path(i));
+    }
+    if result.is_err() {
+        let _ = fs::remove_file(op_file::sibling_tmp(path));
+    }
+    result
 }
 
 pub fn trans_value_to_doc(mut item: OpRow, ignore_field: &[String]) -> OpRow {
     match &item.op {
-        op_row::Op::Find | op_row::Op::Count | op_row::Op::Command => {
+        op_row::Op::Find
+        | op_row::Op::Count
+        | op_row::Op::Command
+        | op_row::Op::Distinct
+        | op_row::Op::GeoNear => {
             if let Value::Object(ref mut cmd) = item.cmd {
                 cmd.remove("lsid");
                 cmd.remove("$clusterTime");
@@ -367,9 +592,16 @@ pub fn trans_value_to_doc(mut item: OpRow, ignore_field: &[String]) -> OpRow {
                 cmd.remove("cursorId");
             }
             // println!("after cmd {:?}", item.key);
-            let cmd: Document = Document::deserialize(&item.cmd)
-                .expect(format!("Id[{}] cmd deserialize error", item.id).as_str());
-            item.args = cmd;
+            if item.args.is_empty() {
+                let cmd: Document = Document::deserialize(&item.cmd)
+                    .expect(format!("Id[{}] cmd deserialize error", item.id).as_str());
+                item.args = cmd;
+            } else {
+                // .opb 中读取的 args 已经是原生的 BSON
+                for key in ["lsid", "$clusterTime", "$db", "cursor", "cursorId"] {
+                    item.args.remove(key);
+                }
+            }
         }
         op_row::Op::Insert => {
             let documents = item
@@ -396,3 +628,176 @@ pub fn trans_value_to_doc(mut item: OpRow, ignore_field: &[String]) -> OpRow {
     // item.hash = to_sha3_8(&item.key);
     item
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mongobar::op_file::OpFormat;
+    use crate::mongobar::op_fixture::{row, write_rows, TempDir};
+    use crate::mongobar::op_header::{self, OpHeader};
+
+    const FORMATS: [&str; 3] = ["a.op", "a.op.gz", "a.opb.zst"];
+
+    fn find_row(id: usize) -> OpRow {
+        row(serde_json::json!({
+            "id": id.to_string(), "ts": id, "cmd": { "find": "c", "filter": { "a": id } }
+        }))
+    }
+
+    /// 写入 count 条记录并加上头信息，json 格式的文件多一行注释
+    fn stamped(dir: &TempDir, name: &str, count: usize) -> PathBuf {
+        let path = dir.join(name);
+        if OpFormat::from_path(&path) == OpFormat::Json {
+            let mut w = op_file::create(&path).unwrap();
+            writeln!(w, "# comment").unwrap();
+        }
+        write_rows(&path, &(0..count).map(find_row).collect::<Vec<_>>());
+        op_header::stamp(&path, OpHeader::new("profile", "xgj", "mongodb://a")).unwrap();
+        path
+    }
+
+    fn read_ids(path: &Path, mode: OpReadMode) -> Vec<String> {
+        let op_logs = OpLogs::new(path.to_path_buf(), mode, vec![])
+            .init()
+            .unwrap();
+        let mut ids = vec![];
+        while let Some(row) = op_logs.read(0, 0) {
+            ids.push(row.id);
+        }
+        ids
+    }
+
+    #[test]
+    fn test_read_modes_with_header() {
+        let dir = TempDir::new("op-logs");
+        for name in FORMATS {
+            let path = stamped(&dir, name, 3);
+            let expected: Vec<String> = (0..3).map(|i| i.to_string()).collect();
+            assert_eq!(
+                read_ids(&path, OpReadMode::FullLine(None)),
+                expected,
+                "{}",
+                name
+            );
+            assert_eq!(
+                read_ids(&path, OpReadMode::ReadLine(false)),
+                expected,
+                "{}",
+                name
+            );
+            assert_eq!(
+                read_ids(&path, OpReadMode::StreamLine),
+                expected,
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_skip_and_page_with_header() {
+        let dir = TempDir::new("op-logs");
+        for name in FORMATS {
+            let path = stamped(&dir, name, 3);
+            let op_logs = OpLogs::new(path, OpReadMode::ReadLine(false), vec![])
+                .init()
+                .unwrap();
+            op_logs.skip(2);
+            assert_eq!(op_logs.read(0, 0).unwrap().id, "2", "{}", name);
+
+            // 按照过滤后的行分页
+            let rows = op_logs.matched_rows("id != 1").unwrap();
+            assert_eq!(rows, vec![0, 2], "{}", name);
+            let page: Vec<String> = op_logs.rows_at(&rows).into_iter().map(|r| r.id).collect();
+            assert_eq!(page, vec!["0", "2"], "{}", name);
+            assert_eq!(op_logs.limit(1, 5).len(), 2, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_read_rows_appended_after_header() {
+        let dir = TempDir::new("op-logs");
+        for name in FORMATS {
+            let path = stamped(&dir, name, 3);
+            write_rows(&path, &[find_row(3)]);
+            let expected: Vec<String> = (0..4).map(|i| i.to_string()).collect();
+            assert_eq!(
+                read_ids(&path, OpReadMode::FullLine(None)),
+                expected,
+                "{}",
+                name
+            );
+            assert_eq!(
+                read_ids(&path, OpReadMode::StreamLine),
+                expected,
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_skip_rows_failing_ns_map() {
+        let dir = TempDir::new("op-ns-map");
+        let path = dir.join("a.op");
+        let orders = |id: &str| {
+            row(serde_json::json!({
+                "id": id, "db": "prod_app", "coll": "orders", "cmd": { "find": "orders", "filter": {} }
+            }))
+        };
+        // users 留在 prod_app，关联的 orders 被映射到了 perf_app，无法执行
+        let lookup = row(serde_json::json!({
+            "id": "1", "op": "Aggregate", "db": "prod_app", "coll": "users",
+            "cmd": { "aggregate": "users", "pipeline": [
+                { "$lookup": { "from": "orders", "localField": "_id", "foreignField": "uid", "as": "o" } }
+            ] }
+        }));
+        write_rows(&path, &[orders("0"), lookup, orders("2")]);
+
+        let ns_map = NsMap::new(&["prod_app.orders=perf_app.orders_v2".to_string()], &[]).unwrap();
+        for mode in [OpReadMode::ReadLine(false), OpReadMode::StreamLine] {
+            let op_logs = OpLogs::new(path.clone(), mode, vec![])
+                .set_ns_map(ns_map.clone())
+                .init()
+                .unwrap();
+            let mut rows = vec![];
+            while let Some(row) = op_logs.read(0, 0) {
+                rows.push(row);
+            }
+            let ids: Vec<&str> = rows.iter().map(|r| r.id.as_str()).collect();
+            assert_eq!(ids, vec!["0", "2"]);
+            assert_eq!(rows[0].ns, "perf_app.orders_v2");
+        }
+    }
+
+    #[test]
+    fn test_reverse_file_in_chunks() {
+        for name in FORMATS {
+            let dir = TempDir::new("op-reverse");
+            let path = stamped(&dir, name, 5);
+
+            // 每条记录一段
+            reverse_file_with_chunk(&path, 1).unwrap();
+            assert!(op_header::read(&path).is_some(), "{}", name);
+            let mut reader = OpReader::open(&path).unwrap();
+            let mut ids = vec![];
+            while let Some(row) = reader.read_row().unwrap() {
+                ids.push(row.id);
+            }
+            assert_eq!(ids, vec!["4", "3", "2", "1", "0"], "{}", name);
+            // 临时文件都已经清理
+            assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_push_line_compressed() {
+        let dir = TempDir::new("op-push");
+        let path = dir.join("a.op.zst");
+        OpLogs::push_line(path.clone(), find_row(1));
+        OpLogs::push_line(path.clone(), find_row(2));
+        let mut reader = OpReader::open(&path).unwrap();
+        assert_eq!(reader.read_row().unwrap().unwrap().id, "1");
+        assert_eq!(reader.read_row().unwrap().unwrap().id, "2");
+    }
+}
@@ -0,0 +1,383 @@
+use bson::Bson;
+use serde_json::Value;
+
+use super::op_row::OpRow;
+
+/// 命令中值为集合名称的字段
+static COLL_FIELDS: [&str; 10] = [
+    "find",
+    "insert",
+    "update",
+    "delete",
+    "aggregate",
+    "count",
+    "distinct",
+    "findAndModify",
+    "findandmodify",
+    "geoNear",
+];
+
+/// 一条命名空间映射规则，`coll` 为 None 时表示 `*`
+#[derive(Clone, Debug, PartialEq)]
+pub struct NsRule {
+    pub from_db: String,
+    pub from_coll: Option<String>,
+    pub to_db: String,
+    pub to_coll: Option<String>,
+}
+
+impl NsRule {
+    /// `prod_app.*=perf_app.*`、`prod_app.orders=perf_app.orders_v2`
+    pub fn parse_ns(rule: &str) -> anyhow::Result<Self> {
+        let (from, to) = rule
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("invalid ns map rule: {}", rule))?;
+        let split = |ns: &str| -> anyhow::Result<(String, Option<String>)> {
+            let (db, coll) = ns
+                .trim()
+                .split_once('.')
+                .ok_or_else(|| anyhow::anyhow!("invalid ns map rule: {}", rule))?;
+            if db.is_empty() || coll.is_empty() {
+                return Err(anyhow::anyhow!("invalid ns map rule: {}", rule));
+            }
+            Ok((db.to_string(), (coll != "*").then(|| coll.to_string())))
+        };
+        let (from_db, from_coll) = split(from)?;
+        let (to_db, to_coll) = split(to)?;
+        if from_coll.is_none() && to_coll.is_some() {
+            return Err(anyhow::anyhow!(
+                "invalid ns map rule: {}, `*` can only map to `*`",
+                rule
+            ));
+        }
+        Ok(Self {
+            from_db,
+            from_coll,
+            to_db,
+            to_coll,
+        })
+    }
+
+    /// `prod_app=perf_app`，等同于 `prod_app.*=perf_app.*`
+    pub fn parse_db(rule: &str) -> anyhow::Result<Self> {
+        match rule.split_once('=') {
+            Some((from, to)) if !from.trim().is_empty() && !to.trim().is_empty() => Ok(Self {
+                from_db: from.trim().to_string(),
+                from_coll: None,
+                to_db: to.trim().to_string(),
+                to_coll: None,
+            }),
+            _ => Err(anyhow::anyhow!("invalid db map rule: {}", rule)),
+        }
+    }
+
+    fn map(&self, db: &str, coll: &str) -> Option<(String, String)> {
+        if self.from_db != db {
+            return None;
+        }
+        match &self.from_coll {
+            Some(from_coll) if from_coll != coll => None,
+            _ => Some((
+                self.to_db.clone(),
+                self.to_coll.clone().unwrap_or_else(|| coll.to_string()),
+            )),
+        }
+    }
+}
+
+/// 回放到其他库时的命名空间映射，按顺序匹配第一条规则
+#[derive(Clone, Debug, Default)]
+pub struct NsMap {
+    pub rules: Vec<NsRule>,
+}
+
+impl NsMap {
+    pub fn new(map_ns: &[String], map_db: &[String]) -> anyhow::Result<Self> {
+        let mut rules = vec![];
+        for rule in map_ns.iter() {
+            rules.push(NsRule::parse_ns(rule)?);
+        }
+        for rule in map_db.iter() {
+            rules.push(NsRule::parse_db(rule)?);
+        }
+        Ok(Self { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn map(&self, db: &str, coll: &str) -> Option<(String, String)> {
+        self.rules.iter().find_map(|rule| rule.map(db, coll))
+    }
+
+    /// 重写 db/coll/ns 以及命令中的集合名称和 pipeline 中关联的集合
+    ///
+    /// pipeline 中只写了集合名称的（`$lookup.from` 等）和当前集合在同一个库，映射到其他库时返回错误
+    pub fn apply(&self, row: &mut OpRow) -> anyhow::Result<()> {
+        if self.is_empty() || row.db.is_empty() {
+            return Ok(());
+        }
+        let src = (row.db.clone(), row.coll.clone());
+
+        if let Some((to_db, to_coll)) = self.map(&row.db, &row.coll) {
+            for field in COLL_FIELDS.iter() {
+                if row.cmd.get(field).and_then(Value::as_str) == Some(row.coll.as_str()) {
+                    row.cmd[field] = Value::String(to_coll.clone());
+                }
+                if row.args.get_str(field).ok() == Some(row.coll.as_str()) {
+                    row.args.insert(*field, to_coll.clone());
+                }
+            }
+            if row.cmd.get("$db").is_some() {
+                row.cmd["$db"] = Value::String(to_db.clone());
+            }
+            if row.args.contains_key("$db") {
+                row.args.insert("$db", to_db.clone());
+            }
+            row.ns = format!("{}.{}", to_db, to_coll);
+            row.db = to_db;
+            row.coll = to_coll;
+        }
+
+        let remap = Remap {
+            map: self,
+            src,
+            db: row.db.clone(),
+        };
+        if let Some(pipeline) = row.cmd.get_mut("pipeline") {
+            remap_pipeline_value(pipeline, &remap)?;
+        }
+        if let Ok(pipeline) = row.args.get_array_mut("pipeline") {
+            remap_pipeline_bson(pipeline, &remap)?;
+        }
+        Ok(())
+    }
+}
+
+/// pipeline 中关联的集合的映射，src 为映射前的 ns，db 为映射后执行的库
+struct Remap<'a> {
+    map: &'a NsMap,
+    src: (String, String),
+    db: String,
+}
+
+impl Remap<'_> {
+    /// 只有集合名称时在映射前的库中查找，映射后必须还在执行的库中
+    fn coll(&self, coll: &str) -> anyhow::Result<Option<String>> {
+        let (to_db, to_coll) = self
+            .map
+            .map(&self.src.0, coll)
+            .unwrap_or_else(|| (self.src.0.clone(), coll.to_string()));
+        if to_db != self.db {
+            return Err(anyhow::anyhow!(
+                "pipeline of {}.{} references {}.{}, which maps to {}.{} outside {}, add a rule for it",
+                self.src.0,
+                self.src.1,
+                self.src.0,
+                coll,
+                to_db,
+                to_coll,
+                self.db
+            ));
+        }
+        Ok((to_coll != coll).then_some(to_coll))
+    }
+
+    /// `{ db, coll }` 形式可以指定其他库，直接映射
+    fn ns(&self, db: &str, coll: &str) -> Option<(String, String)> {
+        self.map.map(db, coll)
+    }
+}
+
+fn remap_pipeline_value(pipeline: &mut Value, remap: &Remap) -> anyhow::Result<()> {
+    let Some(stages) = pipeline.as_array_mut() else {
+        return Ok(());
+    };
+    for stage in stages.iter_mut().filter_map(Value::as_object_mut) {
+        for (name, spec) in stage.iter_mut() {
+            let key = match name.as_str() {
+                "$lookup" | "$graphLookup" => "from",
+                "$unionWith" | "$out" => "coll",
+                "$merge" => "into",
+                "$facet" => {
+                    if let Some(facets) = spec.as_object_mut() {
+                        for sub in facets.values_mut() {
+                            remap_pipeline_value(sub, remap)?;
+                        }
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+            // `$unionWith`/`$out`/`$merge` 可以直接是集合名称
+            if let Some(coll) = spec.as_str() {
+                if let Some(to) = remap.coll(coll)? {
+                    *spec = Value::String(to);
+                }
+                continue;
+            }
+            // `$out: { db, coll }`、`$merge: { into: { db, coll } }`
+            let target = match name.as_str() {
+                "$out" => Some(&mut *spec),
+                _ => spec.get_mut(key).filter(|v| v.is_object()),
+            };
+            if let Some(target) = target.filter(|v| v.get("db").is_some()) {
+                let db = target.get("db").and_then(Value::as_str).unwrap_or_default();
+                let coll = target
+                    .get("coll")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default();
+                if let Some((to_db, to_coll)) = remap.ns(db, coll) {
+                    target["db"] = Value::String(to_db);
+                    target["coll"] = Value::String(to_coll);
+                }
+                continue;
+            }
+            if let Some(coll) = spec.get(key).and_then(Value::as_str) {
+                if let Some(to) = remap.coll(coll)? {
+                    spec[key] = Value::String(to);
+                }
+            }
+            if let Some(sub) = spec.get_mut("pipeline") {
+                remap_pipeline_value(sub, remap)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn remap_pipeline_bson(pipeline: &mut [Bson], remap: &Remap) -> anyhow::Result<()> {
+    for stage in pipeline.iter_mut().filter_map(Bson::as_document_mut) {
+        for (name, spec) in stage.iter_mut() {
+            let key = match name.as_str() {
+                "$lookup" | "$graphLookup" => "from",
+                "$unionWith" | "$out" => "coll",
+                "$merge" => "into",
+                "$facet" => {
+                    if let Some(facets) = spec.as_document_mut() {
+                        for (_, sub) in facets.iter_mut() {
+                            if let Bson::Array(sub) = sub {
+                                remap_pipeline_bson(sub, remap)?;
+                            }
+                        }
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+            if let Bson::String(coll) = spec {
+                if let Some(to) = remap.coll(coll)? {
+                    *coll = to;
+                }
+                continue;
+            }
+            let Some(spec) = spec.as_document_mut() else {
+                continue;
+            };
+            let target = match name.as_str() {
+                "$out" => Some(&mut *spec),
+                _ => spec.get_document_mut(key).ok(),
+            };
+            if let Some(target) = target.filter(|v| v.contains_key("db")) {
+                let db = target.get_str("db").unwrap_or_default();
+                let coll = target.get_str("coll").unwrap_or_default();
+                if let Some((to_db, to_coll)) = remap.ns(db, coll) {
+                    target.insert("db", to_db);
+                    target.insert("coll", to_coll);
+                }
+                continue;
+            }
+            if let Ok(coll) = spec.get_str(key) {
+                if let Some(to) = remap.coll(coll)? {
+                    spec.insert(key, to);
+                }
+            }
+            if let Ok(sub) = spec.get_array_mut("pipeline") {
+                remap_pipeline_bson(sub, remap)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ns_map() {
+        let map = NsMap::new(
+            &["prod_app.orders=perf_app.orders_v2".to_string()],
+            &["prod_app=perf_app".to_string()],
+        )
+        .unwrap();
+        assert_eq!(
+            map.map("prod_app", "orders"),
+            Some(("perf_app".to_string(), "orders_v2".to_string()))
+        );
+        assert_eq!(
+            map.map("prod_app", "users"),
+            Some(("perf_app".to_string(), "users".to_string()))
+        );
+        assert_eq!(map.map("other", "users"), None);
+        assert!(NsRule::parse_ns("prod_app.*=perf_app.orders").is_err());
+
+        let mut row: OpRow = serde_json::from_value(serde_json::json!({
+            "id": "1", "op": "Aggregate", "db": "prod_app", "coll": "users", "ns": "prod_app.users", "ts": 0,
+            "cmd": { "aggregate": "users", "$db": "prod_app", "pipeline": [
+                { "$lookup": { "from": "orders", "localField": "_id", "foreignField": "uid", "as": "o" } },
+                { "$unionWith": "orders" }
+            ] }
+        }))
+        .unwrap();
+        row.args = bson::to_document(&row.cmd).unwrap();
+        let mut users = row.clone();
+        map.apply(&mut row).unwrap();
+
+        assert_eq!(row.ns, "perf_app.users");
+        assert_eq!(row.cmd["$db"], "perf_app");
+        assert_eq!(row.cmd["pipeline"][0]["$lookup"]["from"], "orders_v2");
+        assert_eq!(row.cmd["pipeline"][1]["$unionWith"], "orders_v2");
+        let stage = row.args.get_array("pipeline").unwrap()[0]
+            .as_document()
+            .unwrap();
+        assert_eq!(
+            stage
+                .get_document("$lookup")
+                .unwrap()
+                .get_str("from")
+                .unwrap(),
+            "orders_v2"
+        );
+
+        // 只映射 orders 时，users 留在 prod_app，关联的 orders 被映射到了 perf_app
+        let only_ns = NsMap::new(&["prod_app.orders=perf_app.orders_v2".to_string()], &[]).unwrap();
+        assert!(only_ns.apply(&mut users.clone()).is_err());
+        // 同一个库内的映射可以重写
+        let same_db = NsMap::new(&["prod_app.orders=prod_app.orders_v2".to_string()], &[]).unwrap();
+        same_db.apply(&mut users).unwrap();
+        assert_eq!(users.ns, "prod_app.users");
+        assert_eq!(users.cmd["pipeline"][0]["$lookup"]["from"], "orders_v2");
+
+        // `{ db, coll }` 指定了库的直接映射
+        let mut out: OpRow = serde_json::from_value(serde_json::json!({
+            "id": "2", "op": "Aggregate", "db": "prod_app", "coll": "users", "ns": "prod_app.users", "ts": 0,
+            "cmd": { "aggregate": "users", "pipeline": [
+                { "$merge": { "into": { "db": "prod_app", "coll": "orders" } } },
+                { "$out": { "db": "prod_app", "coll": "orders" } }
+            ] }
+        }))
+        .unwrap();
+        only_ns.apply(&mut out).unwrap();
+        assert_eq!(out.ns, "prod_app.users");
+        assert_eq!(
+            out.cmd["pipeline"][0]["$merge"]["into"],
+            serde_json::json!({ "db": "perf_app", "coll": "orders_v2" })
+        );
+        assert_eq!(
+            out.cmd["pipeline"][1]["$out"],
+            serde_json::json!({ "db": "perf_app", "coll": "orders_v2" })
+        );
+    }
+}
@@ -0,0 +1,219 @@
+use bson::{Bson, Document};
+use serde_json::json;
+
+use crate::utils::{get_db_coll, to_sha3};
+
+use super::op_row::{Op, OpRow};
+
+/// 将 local.oplog.rs 中的一条记录转换为可执行的 OpRow
+///
+/// - `i` => Insert
+/// - `u` => Update（`$v: 2` 的 diff 格式会被还原成 `$set`/`$unset`）
+/// - `d` => Delete
+/// - `c` 中的 `applyOps`（事务/批量写入）会被展开，内部的记录继承外层的 ts/wall
+///
+/// 只保留 `db` 下的非 system 集合，其他的记录返回空
+pub fn oplog_to_rows(entry: &Document, db: &str) -> Vec<OpRow> {
+    let ts = oplog_ts(entry);
+    let mut rows = vec![];
+    push_oplog_rows(entry, db, ts, &mut rows);
+    rows
+}
+
+fn push_oplog_rows(entry: &Document, db: &str, ts: i64, rows: &mut Vec<OpRow>) {
+    let op = entry.get_str("op").unwrap_or_default();
+
+    if op == "c" {
+        if let Ok(ops) = entry
+            .get_document("o")
+            .and_then(|o| o.get_array("applyOps"))
+        {
+            for inner in ops.iter() {
+                if let Bson::Document(inner) = inner {
+                    push_oplog_rows(inner, db, ts, rows);
+                }
+            }
+        }
+        return;
+    }
+
+    let ns = entry.get_str("ns").unwrap_or_default();
+    let (row_db, coll) = get_db_coll(ns);
+    if row_db != db || coll.is_empty() || coll.starts_with("system.") {
+        return;
+    }
+
+    let o = match entry.get_document("o") {
+        Ok(o) => o,
+        Err(_) => return,
+    };
+
+    let (op, cmd) = match op {
+        "i" => (
+            Op::Insert,
+            json!({
+                "insert": coll,
+                "documents": [o],
+            }),
+        ),
+        "u" => {
+            let q = match entry.get_document("o2") {
+                Ok(q) => q,
+                Err(_) => return,
+            };
+            (
+                Op::Update,
+                json!({
+                    "update": coll,
+                    "updates": [
+                        {
+                            "q": q,
+                            "u": oplog_update_to_doc(o),
+                            "multi": false,
+                            "upsert": false
+                        }
+                    ],
+                }),
+            )
+        }
+        "d" => (
+            Op::Delete,
+            json!({
+                "delete": coll,
+                "deletes": [
+                    {
+                        "q": o,
+                        "limit": 1
+                    }
+                ],
+            }),
+        ),
+        _ => return,
+    };
+
+    rows.push(OpRow {
+        id: to_sha3(&entry.to_string()),
+        op,
+        db: row_db,
+        coll,
+        cmd,
+        ns: ns.to_string(),
+        ts,
+        ..Default::default()
+    });
+}
+
+/// 优先使用 wall（毫秒），老版本的 oplog 没有 wall 时使用 ts 的秒
+fn oplog_ts(entry: &Document) -> i64 {
+    if let Ok(wall) = entry.get_datetime("wall") {
+        return wall.timestamp_millis();
+    }
+    if let Ok(ts) = entry.get_timestamp("ts") {
+        return ts.time as i64 * 1000;
+    }
+    0
+}
+
+/// 将 oplog 中的更新内容转为可以直接执行的 update 文档
+///
+/// 5.0 之后的 oplog 使用 `{ "$v": 2, "diff": {...} }`，老版本是 `$set`/`$unset` 或者整条替换
+fn oplog_update_to_doc(o: &Document) -> Document {
+    let diff = match (o.get("$v"), o.get_document("diff")) {
+        (Some(_), Ok(diff)) => diff,
+        _ => {
+            let mut o = o.clone();
+            o.remove("$v");
+            return o;
+        }
+    };
+
+    let mut set = Document::new();
+    let mut unset = Document::new();
+    collect_diff("", diff, &mut set, &mut unset);
+
+    let mut u = Document::new();
+    if !set.is_empty() {
+        u.insert("$set", set);
+    }
+    if !unset.is_empty() {
+        u.insert("$unset", unset);
+    }
+    u
+}
+
+fn collect_diff(prefix: &str, diff: &Document, set: &mut Document, unset: &mut Document) {
+    let is_array = diff.get_bool("a").unwrap_or_default();
+    for (k, v) in diff.iter() {
+        match k.as_str() {
+            "a" | "l" if is_array => {}
+            "u" | "i" => {
+                if let Bson::Document(fields) = v {
+                    for (f, fv) in fields.iter() {
+                        set.insert(join_path(prefix, f), fv.clone());
+                    }
+                }
+            }
+            "d" => {
+                if let Bson::Document(fields) = v {
+                    for (f, _) in fields.iter() {
+                        unset.insert(join_path(prefix, f), "");
+                    }
+                }
+            }
+            _ if is_array && k.starts_with('u') => {
+                set.insert(join_path(prefix, &k[1..]), v.clone());
+            }
+            _ if k.starts_with('s') => {
+                if let Bson::Document(sub) = v {
+                    collect_diff(&join_path(prefix, &k[1..]), sub, set, unset);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn join_path(prefix: &str, field: &str) -> String {
+    if prefix.is_empty() {
+        field.to_string()
+    } else {
+        format!("{}.{}", prefix, field)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::{doc, DateTime, Timestamp};
+
+    #[test]
+    fn test_oplog_to_rows() {
+        let wall = DateTime::from_millis(1721355566123);
+        let entry = doc! {
+            "op": "c",
+            "ns": "admin.$cmd",
+            "ts": Timestamp { time: 1721355566, increment: 1 },
+            "wall": wall,
+            "o": {
+                "applyOps": [
+                    { "op": "i", "ns": "xgj.users", "o": { "_id": 1, "name": "a" } },
+                    { "op": "u", "ns": "xgj.users", "o2": { "_id": 1 }, "o": { "$v": 2, "diff": { "u": { "name": "b" }, "d": { "age": false }, "sprofile": { "i": { "city": "x" } } } } },
+                    { "op": "d", "ns": "xgj.users", "o": { "_id": 1 } },
+                    { "op": "i", "ns": "other.users", "o": { "_id": 2 } },
+                ]
+            }
+        };
+
+        let rows = oplog_to_rows(&entry, "xgj");
+        assert_eq!(rows.len(), 3);
+        assert!(rows.iter().all(|r| r.ts == 1721355566123));
+        assert!(matches!(rows[0].op, Op::Insert));
+        assert!(matches!(rows[1].op, Op::Update));
+        assert!(matches!(rows[2].op, Op::Delete));
+
+        let u = &rows[1].cmd["updates"][0]["u"];
+        assert_eq!(u["$set"]["name"], "b");
+        assert_eq!(u["$set"]["profile.city"], "x");
+        assert_eq!(u["$unset"]["age"], "");
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::BTreeSet;
+
+use bson::{doc, Bson, Document};
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::op_row::Op;
+
+/// 真正访问数据的 stage，planSummary 里出现的也就是这些
+static ACCESS_STAGES: &[&str] = &[
+    "COLLSCAN",
+    "IXSCAN",
+    "IDHACK",
+    "COUNT_SCAN",
+    "DISTINCT_SCAN",
+    "CLUSTERED_IXSCAN",
+    "EXPRESS_IXSCAN",
+    "EXPRESS_CLUSTERED_IXSCAN",
+    "TEXT_MATCH",
+    "GEO_NEAR_2D",
+    "GEO_NEAR_2DSPHERE",
+    "EOF",
+];
+
+/// 构建 queryPlanner 级别的 explain 命令，不会真正执行写操作
+pub fn explain_cmd(op: &Op, cmd: &Value) -> Option<Document> {
+    match op {
+        Op::Find
+        | Op::Count
+        | Op::Distinct
+        | Op::Aggregate
+        | Op::CountDocuments
+        | Op::GeoNear
+        | Op::Update
+        | Op::Delete
+        | Op::FindAndModify => {}
+        _ => return None,
+    }
+    let mut cmd = cmd.clone();
+    if let Value::Object(ref mut cmd) = cmd {
+        for key in [
+            "lsid",
+            "$clusterTime",
+            "$db",
+            "$readPreference",
+            "txnNumber",
+            "autocommit",
+            "startTransaction",
+            "writeConcern",
+            "readConcern",
+            "cursorId",
+        ] {
+            cmd.remove(key);
+        }
+    } else {
+        return None;
+    }
+    let cmd = Document::deserialize(cmd).ok()?;
+    Some(doc! { "explain": cmd, "verbosity": "queryPlanner" })
+}
+
+/// 将 explain 的结果转换为和 system.profile 中 planSummary 相同的格式，如 `IXSCAN { a: 1 }`
+pub fn summarize(explain: &Document) -> Option<String> {
+    let planner = find_doc(explain, "queryPlanner")?;
+    let winning = planner.get_document("winningPlan").ok()?;
+    let mut stages = vec![];
+    collect_stages(winning, &mut stages);
+    if stages.is_empty() {
+        return winning.get_str("stage").ok().map(|v| v.to_string());
+    }
+    Some(stages.join(", "))
+}
+
+fn find_doc<'a>(doc: &'a Document, key: &str) -> Option<&'a Document> {
+    if let Ok(v) = doc.get_document(key) {
+        return Some(v);
+    }
+    doc.values().find_map(|v| find_in_bson(v, key))
+}
+
+fn find_in_bson<'a>(v: &'a Bson, key: &str) -> Option<&'a Document> {
+    match v {
+        Bson::Document(d) => find_doc(d, key),
+        Bson::Array(a) => a.iter().find_map(|v| find_in_bson(v, key)),
+        _ => None,
+    }
+}
+
+fn collect_stages(plan: &Document, stages: &mut Vec<String>) {
+    if let Ok(stage) = plan.get_str("stage") {
+        if ACCESS_STAGES.contains(&stage) {
+            match plan.get_document("keyPattern") {
+                Ok(key_pattern) => {
+                    let keys: Vec<String> = key_pattern
+                        .iter()
+                        .map(|(k, v)| format!("{}: {}", k, v))
+                        .collect();
+                    stages.push(format!("{} {{ {} }}", stage, keys.join(", ")));
+                }
+                Err(_) => stages.push(stage.to_string()),
+            }
+        }
+    }
+    for v in plan.values() {
+        match v {
+            Bson::Document(d) => collect_stages(d, stages),
+            Bson::Array(a) => {
+                for v in a.iter() {
+                    if let Bson::Document(d) = v {
+                        collect_stages(d, stages);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// 取出 planSummary 中的 stage 和索引，`IXSCAN { a: 1 }, COLLSCAN` => {COLLSCAN, IXSCAN{a:1}}
+///
+/// 去掉空白并统一 `1.0` 和 `1`，profile 和 explain 中数字的格式可能不同
+pub fn plan_stages(summary: &str) -> BTreeSet<String> {
+    let mut stages = BTreeSet::new();
+    let mut depth = 0;
+    let mut segment = String::new();
+    for c in summary.chars().chain(std::iter::once(',')) {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            ',' if depth == 0 => {
+                if !segment.is_empty() {
+                    stages.insert(segment.replace(".0,", ",").replace(".0}", "}"));
+                }
+                segment.clear();
+                continue;
+            }
+            _ => {}
+        }
+        if !c.is_whitespace() {
+            segment.push(c);
+        }
+    }
+    stages
+}
+
+/// 录制时和回放时使用的 stage 或者索引不同则认为执行计划发生了变化
+pub fn plan_changed(recorded: &str, replayed: &str) -> bool {
+    plan_stages(recorded) != plan_stages(replayed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summarize_and_compare() {
+        let explain = doc! {
+            "queryPlanner": {
+                "winningPlan": {
+                    "stage": "FETCH",
+                    "inputStage": { "stage": "IXSCAN", "keyPattern": { "a": 1 } }
+                }
+            }
+        };
+        let summary = summarize(&explain).unwrap();
+        assert_eq!(summary, "IXSCAN { a: 1 }");
+
+        let aggregate = doc! {
+            "stages": [
+                { "$cursor": { "queryPlanner": { "winningPlan": { "stage": "COLLSCAN" } } } }
+            ]
+        };
+        assert_eq!(summarize(&aggregate).unwrap(), "COLLSCAN");
+
+        assert!(!plan_changed("IXSCAN { a: 1.0 }", &summary));
+        assert!(plan_changed("IXSCAN { a: 1, b: -1 }", &summary));
+        assert!(plan_changed("IXSCAN { a: 1 }", "COLLSCAN"));
+        assert!(!plan_changed(
+            "IXSCAN { a: 1 }, COLLSCAN",
+            "COLLSCAN, IXSCAN {a: 1}"
+        ));
+    }
+}
@@ -1,4 +1,4 @@
-use mongodb::bson::Document;
+use mongodb::bson::{Bson, Document};
 
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
@@ -15,6 +15,10 @@ pub(crate) struct OpRow {
     pub ns: String,
     pub ts: i64,
 
+    /// 录制时 system.profile 中的执行信息，用于和回放的结果做对比
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<OpProfile>,
+
     #[serde(skip)]
     pub args: Document,
 
@@ -25,7 +29,59 @@ pub(crate) struct OpRow {
     pub hash: String,
 }
 
+#[derive(Clone, Debug, Deserialize, Serialize, Default)]
+pub(crate) struct OpProfile {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub millis: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub plan_summary: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub docs_examined: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keys_examined: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nreturned: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_length: Option<i64>,
+}
+
+impl OpProfile {
+    /// 从 system.profile 的记录中取出执行信息
+    pub fn from_profile_doc(doc: &Document) -> Self {
+        let get_i64 = |key: &str| match doc.get(key) {
+            Some(Bson::Int32(v)) => Some(*v as i64),
+            Some(Bson::Int64(v)) => Some(*v),
+            Some(Bson::Double(v)) => Some(*v as i64),
+            _ => None,
+        };
+        Self {
+            millis: get_i64("millis"),
+            plan_summary: doc.get_str("planSummary").ok().map(|v| v.to_string()),
+            docs_examined: get_i64("docsExamined"),
+            keys_examined: get_i64("keysExamined"),
+            nreturned: get_i64("nreturned"),
+            response_length: get_i64("responseLength"),
+        }
+    }
+}
+
 impl OpRow {
+    /// 判断是否会修改数据，会同时检查命令名称和 aggregate 中的 `$out`/`$merge`
+    pub fn is_readonly(&self) -> bool {
+        if !self.op.is_readonly() && !matches!(self.op, Op::Command) {
+            return false;
+        }
+        if let Some(pipeline) = self.cmd.get("pipeline") {
+            if pipeline_writes(pipeline) {
+                return false;
+            }
+        }
+        if let Op::Command = self.op {
+            return cmd_readonly(&self.cmd);
+        }
+        true
+    }
+
     pub fn build_key(&self) -> String {
         let keys = match self.op {
             Op::Find => {
@@ -60,6 +116,43 @@ impl OpRow {
                     keys
                 }
             }
+            Op::Count => {
+                let mut keys = deep_build_key(self.cmd.get("query").unwrap_or(&Value::Null));
+                keys.sort();
+                keys
+            }
+            Op::Distinct => {
+                let mut keys = deep_build_key(self.cmd.get("query").unwrap_or(&Value::Null));
+                keys.sort();
+                keys.insert(
+                    0,
+                    self.cmd
+                        .get("key")
+                        .and_then(|k| k.as_str())
+                        .unwrap_or_default()
+                        .to_string(),
+                );
+                keys
+            }
+            Op::CountDocuments => {
+                let mut keys = deep_build_key(
+                    self.cmd
+                        .pointer("/pipeline/0/$match")
+                        .unwrap_or(&Value::Null),
+                );
+                keys.sort();
+                keys
+            }
+            Op::GeoNear | Op::Search => {
+                let mut keys = deep_build_key(
+                    self.cmd
+                        .get("pipeline")
+                        .or(self.cmd.get("query"))
+                        .unwrap_or(&Value::Null),
+                );
+                keys.sort();
+                keys
+            }
             _ => {
                 let mut keys = deep_build_key(&self.cmd);
                 keys.sort();
@@ -68,6 +161,121 @@ impl OpRow {
         };
         format!("{}:{:?}:{}", self.coll, self.op, to_sha3_8(&keys.join("")))
     }
+
+    /// 转换为 `.opb` 中的一条记录，cmd 中的 `$date`/`$oid`/`$numberLong` 等会被还原成原生的 BSON 类型
+    pub fn to_bson(&self) -> anyhow::Result<Document> {
+        let mut doc = bson::to_document(&OpRow {
+            cmd: Value::Null,
+            ..self.clone()
+        })?;
+        doc.insert("cmd", Bson::try_from(self.cmd.clone())?);
+        Ok(doc)
+    }
+
+    /// 从 `.opb` 的记录中恢复，cmd 直接作为执行时的 args，不需要再从 json 转换
+    pub fn from_bson(mut doc: Document) -> anyhow::Result<OpRow> {
+        let cmd = doc.insert("cmd", Bson::Null).unwrap_or(Bson::Null);
+        let mut row: OpRow = bson::from_document(doc)?;
+        if let Bson::Document(args) = &cmd {
+            row.args = args.clone();
+        }
+        // canonical 保留 Int64/Int32/Double 的区别，写操作从 cmd 还原时类型不变
+        row.cmd = cmd.into_canonical_extjson();
+        Ok(row)
+    }
+}
+
+/// json 中的数字，包括 canonical extjson 的 `$numberInt`/`$numberLong`/`$numberDouble`
+pub fn json_f64(v: &Value) -> Option<f64> {
+    match v {
+        Value::Number(n) => n.as_f64(),
+        Value::Object(map) if map.len() == 1 => {
+            let (k, v) = map.iter().next()?;
+            match k.as_str() {
+                "$numberInt" | "$numberLong" | "$numberDouble" => v.as_str()?.parse().ok(),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// json 中的整数，包括 canonical extjson 的 `$numberInt`/`$numberLong`
+pub fn json_i64(v: &Value) -> Option<i64> {
+    match v {
+        Value::Number(n) => n.as_i64(),
+        Value::Object(map) if map.len() == 1 => {
+            let (k, v) = map.iter().next()?;
+            match k.as_str() {
+                "$numberInt" | "$numberLong" => v.as_str()?.parse().ok(),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// 不会修改数据的命令，不在这里的命令 readonly 模式下都不会执行
+static READONLY_COMMANDS: once_cell::sync::Lazy<std::collections::HashSet<&'static str>> =
+    once_cell::sync::Lazy::new(|| {
+        std::collections::HashSet::from([
+            "find",
+            "count",
+            "distinct",
+            "aggregate",
+            "getMore",
+            "killCursors",
+            "explain",
+            "geoNear",
+            "listIndexes",
+            "listCollections",
+            "listDatabases",
+            "dbStats",
+            "collStats",
+            "dataSize",
+            "serverStatus",
+            "buildInfo",
+            "hostInfo",
+            "connectionStatus",
+            "ping",
+            "hello",
+            "isMaster",
+            "ismaster",
+            "getParameter",
+            "getCmdLineOpts",
+            "replSetGetStatus",
+            "currentOp",
+            "top",
+            "validate",
+        ])
+    });
+
+fn cmd_readonly(cmd: &Value) -> bool {
+    let name = match cmd.as_object().and_then(|o| o.keys().next()) {
+        Some(name) => name.as_str(),
+        None => return false,
+    };
+    match name {
+        // mapReduce 只有 inline 输出的时候才是只读的
+        "mapReduce" | "mapreduce" => cmd
+            .get("out")
+            .and_then(|o| o.get("inline"))
+            .is_some(),
+        "explain" => true,
+        "aggregate" => !cmd.get("pipeline").map(pipeline_writes).unwrap_or_default(),
+        _ => READONLY_COMMANDS.contains(name),
+    }
+}
+
+/// pipeline 中是否有写入的 stage（包括 `$facet`/`$lookup`/`$unionWith` 中的子 pipeline）
+fn pipeline_writes(pipeline: &Value) -> bool {
+    match pipeline {
+        Value::Object(o) => o.iter().any(|(k, v)| {
+            k == "$out" || k == "$merge" || pipeline_writes(v)
+        }),
+        Value::Array(a) => a.iter().any(pipeline_writes),
+        _ => false,
+    }
 }
 
 /// 将递归所有 object（包括子 object） 的 key 取出
@@ -112,6 +320,95 @@ pub(crate) enum Op {
     Aggregate,
     FindAndModify,
     GetMore,
+    Distinct,
+    /// 驱动的 countDocuments，是一个 `$match` + `$group: { _id: 1, n: { $sum: 1 } }` 的 aggregate
+    CountDocuments,
+    GeoNear,
+    /// Atlas Search：`$search`/`$searchMeta`/`$vectorSearch` 开头的 aggregate
+    Search,
+}
+
+impl Op {
+    /// 通过命令内容判断具体的操作，用于 profile 中 `op: "command"` 的记录
+    pub fn from_cmd(cmd: &Value) -> Op {
+        if cmd.get("count").is_some() {
+            Op::Count
+        } else if cmd.get("distinct").is_some() {
+            Op::Distinct
+        } else if cmd.get("geoNear").is_some() {
+            Op::GeoNear
+        } else if cmd.get("aggregate").is_some() {
+            match cmd.get("pipeline").and_then(|p| p.as_array()) {
+                Some(pipeline) => Op::from_pipeline(pipeline),
+                None => Op::Aggregate,
+            }
+        } else {
+            Op::Command
+        }
+    }
+
+    fn from_pipeline(pipeline: &[Value]) -> Op {
+        let stage_name = |stage: &Value| {
+            stage
+                .as_object()
+                .and_then(|o| o.keys().next())
+                .cloned()
+                .unwrap_or_default()
+        };
+
+        match pipeline.first().map(stage_name).as_deref() {
+            Some("$geoNear") => return Op::GeoNear,
+            Some("$search") | Some("$searchMeta") | Some("$vectorSearch") => return Op::Search,
+            _ => {}
+        }
+
+        // countDocuments: [$match, ($skip), ($limit), $group: { _id: 1, n: { $sum: 1 } }]
+        if let Some((last, rest)) = pipeline.split_last() {
+            let is_count_group = last
+                .get("$group")
+                .and_then(|g| g.as_object())
+                .map(|g| {
+                    g.len() == 2
+                        && g.get("_id").map(|id| id.is_number() || id.is_null()) == Some(true)
+                        && g.iter()
+                            .filter(|(k, _)| k.as_str() != "_id")
+                            .all(|(_, v)| v.get("$sum").map(|s| s.is_number()) == Some(true))
+                })
+                .unwrap_or_default();
+            let is_count_prefix = !rest.is_empty()
+                && rest.iter().all(|stage| {
+                    matches!(
+                        stage_name(stage).as_str(),
+                        "$match" | "$skip" | "$limit"
+                    )
+                });
+            if is_count_group && is_count_prefix {
+                return Op::CountDocuments;
+            }
+        }
+
+        Op::Aggregate
+    }
+
+    /// 只读的操作类型，Command 需要通过 OpRow::is_readonly 检查具体的命令
+    pub fn is_readonly(&self) -> bool {
+        match self {
+            Op::Find
+            | Op::Count
+            | Op::Aggregate
+            | Op::GetMore
+            | Op::Distinct
+            | Op::CountDocuments
+            | Op::GeoNear
+            | Op::Search => true,
+            Op::None
+            | Op::Insert
+            | Op::Update
+            | Op::Delete
+            | Op::Command
+            | Op::FindAndModify => false,
+        }
+    }
 }
 
 impl From<String> for Op {
@@ -125,7 +422,100 @@ impl From<String> for Op {
             "findAndModify" => Op::FindAndModify,
             "getMore" => Op::GetMore,
             "insert" => Op::Insert,
+            "distinct" => Op::Distinct,
+            "geoNear" => Op::GeoNear,
             _ => Op::None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_op_from_cmd() {
+        assert!(matches!(
+            Op::from_cmd(&json!({ "count": "users", "query": {} })),
+            Op::Count
+        ));
+        assert!(matches!(
+            Op::from_cmd(&json!({ "distinct": "users", "key": "name" })),
+            Op::Distinct
+        ));
+        assert!(matches!(
+            Op::from_cmd(&json!({ "aggregate": "users", "pipeline": [
+                { "$match": { "a": 1 } },
+                { "$group": { "_id": 1, "n": { "$sum": 1 } } }
+            ] })),
+            Op::CountDocuments
+        ));
+        assert!(matches!(
+            Op::from_cmd(&json!({ "aggregate": "users", "pipeline": [
+                { "$match": { "a": 1 } },
+                { "$group": { "_id": "$a", "n": { "$sum": 1 } } }
+            ] })),
+            Op::Aggregate
+        ));
+        assert!(matches!(
+            Op::from_cmd(&json!({ "aggregate": "users", "pipeline": [{ "$search": { "text": {} } }] })),
+            Op::Search
+        ));
+        assert!(matches!(
+            Op::from_cmd(&json!({ "aggregate": "users", "pipeline": [{ "$geoNear": {} }] })),
+            Op::GeoNear
+        ));
+        assert!(matches!(
+            Op::from_cmd(&json!({ "createIndexes": "users" })),
+            Op::Command
+        ));
+    }
+
+    #[test]
+    fn test_row_readonly() {
+        let row = |op: Op, cmd: Value| OpRow {
+            op,
+            cmd,
+            ..Default::default()
+        };
+        assert!(row(Op::Command, json!({ "listIndexes": "users" })).is_readonly());
+        assert!(!row(Op::Command, json!({ "drop": "users" })).is_readonly());
+        assert!(!row(Op::Command, json!({ "createIndexes": "users" })).is_readonly());
+        assert!(!row(Op::Command, json!({ "delete": "users", "deletes": [] })).is_readonly());
+        assert!(!row(
+            Op::Command,
+            json!({ "mapReduce": "users", "out": "result" })
+        )
+        .is_readonly());
+        assert!(row(Op::Find, json!({ "find": "users" })).is_readonly());
+        assert!(!row(
+            Op::Aggregate,
+            json!({ "aggregate": "users", "pipeline": [{ "$match": {} }, { "$merge": { "into": "b" } }] })
+        )
+        .is_readonly());
+        assert!(!row(
+            Op::Aggregate,
+            json!({ "aggregate": "users", "pipeline": [{ "$facet": { "a": [{ "$out": "b" }] } }] })
+        )
+        .is_readonly());
+        assert!(!row(Op::Update, json!({})).is_readonly());
+    }
+
+    #[test]
+    fn test_from_bson_keeps_types() {
+        let mut doc = bson::to_document(&OpRow {
+            op: Op::Insert,
+            ..Default::default()
+        })
+        .unwrap();
+        let documents = bson::doc! { "insert": "c", "documents": [{ "_id": 1_i64, "n": 1, "f": 1.0 }] };
+        doc.insert("cmd", documents.clone());
+        let row = OpRow::from_bson(doc).unwrap();
+        // 从 cmd 还原的文档类型不变
+        assert_eq!(Document::deserialize(&row.cmd).unwrap(), documents);
+        assert_eq!(row.cmd["documents"][0]["_id"], json!({ "$numberLong": "1" }));
+        assert_eq!(json_i64(&row.cmd["documents"][0]["_id"]), Some(1));
+        assert_eq!(json_f64(&row.cmd["documents"][0]["f"]), Some(1.0));
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::HashSet;
+
+use bson::{doc, Bson, Document};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use super::op_row::{Op, OpRow};
+
+/// 写操作可能修改的文档的查询条件，以及是否 upsert
+pub fn write_filters(row: &OpRow) -> Vec<(Document, bool)> {
+    let to_doc = |v: &Value| Document::deserialize(v).ok();
+    let upsert = |v: &Value| v.get("upsert").and_then(Value::as_bool).unwrap_or_default();
+    match row.op {
+        Op::Update | Op::Delete => {
+            let key = if let Op::Update = row.op {
+                "updates"
+            } else {
+                "deletes"
+            };
+            match row.cmd.get(key).and_then(Value::as_array) {
+                Some(items) => items
+                    .iter()
+                    .filter_map(|item| Some((to_doc(item.get("q")?)?, upsert(item))))
+                    .collect(),
+                None => row
+                    .cmd
+                    .get("q")
+                    .and_then(to_doc)
+                    .map(|q| vec![(q, upsert(&row.cmd))])
+                    .unwrap_or_default(),
+            }
+        }
+        Op::FindAndModify => row
+            .cmd
+            .get("query")
+            .and_then(to_doc)
+            .map(|q| vec![(q, upsert(&row.cmd))])
+            .unwrap_or_default(),
+        _ => vec![],
+    }
+}
+
+/// insert 的文档中的 _id，没有 _id 的（服务端生成）无法追踪
+pub fn insert_ids(row: &OpRow) -> Vec<Bson> {
+    row.cmd
+        .get("documents")
+        .and_then(Value::as_array)
+        .map(|documents| {
+            documents
+                .iter()
+                .filter_map(|doc| Bson::try_from(doc.get("_id")?.clone()).ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// upsert 的条件中直接指定的 _id，没找到文档时 upsert 会用这个 _id 创建新的文档
+pub fn upsert_id(q: &Document) -> Option<Bson> {
+    match q.get("_id")? {
+        Bson::Document(d) if d.keys().any(|k| k.starts_with('$')) => None,
+        id => Some(id.clone()),
+    }
+}
+
+/// 已经记录过快照的文档，按照 `ns` + `_id` 去重
+#[derive(Default)]
+pub struct Touched(HashSet<String>);
+
+impl Touched {
+    /// 第一次出现时返回 true
+    pub fn insert(&mut self, ns: &str, id: &Bson) -> bool {
+        self.0
+            .insert(format!("{}:{}", ns, id.clone().into_canonical_extjson()))
+    }
+
+    pub fn count(&self) -> usize {
+        self.0.len()
+    }
+}
+
+/// 还原一个文档的操作：压测前存在则整个替换（被删除的会重新插入），不存在则删除
+pub fn restore_row(row: &OpRow, id: &Bson, pre_image: Option<Document>) -> OpRow {
+    let id = id.clone().into_canonical_extjson();
+    let (op, cmd) = match pre_image {
+        Some(doc) => (
+            Op::Update,
+            json!({
+                "update": row.coll,
+                "updates": [
+                    {
+                        "q": { "_id": id },
+                        "u": Bson::Document(doc).into_canonical_extjson(),
+                        "multi": false,
+                        "upsert": true,
+                    }
+                ],
+            }),
+        ),
+        None => (
+            Op::Delete,
+            json!({
+                "delete": row.coll,
+                "deletes": [
+                    {
+                        "q": { "_id": id },
+                        "limit": 1
+                    }
+                ],
+            }),
+        ),
+    };
+    OpRow {
+        id: row.id.clone(),
+        op,
+        db: row.db.clone(),
+        coll: row.coll.clone(),
+        cmd,
+        ns: row.ns.clone(),
+        ts: row.ts,
+        profile: None,
+        args: doc! {},
+        key: String::new(),
+        hash: String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_rows() {
+        let row: OpRow = serde_json::from_value(json!({
+            "id": "1", "op": "Update", "db": "xgj", "coll": "c", "ns": "xgj.c", "ts": 0,
+            "cmd": { "update": "c", "updates": [
+                { "q": { "status": 1 }, "u": { "$set": { "status": 2 } }, "multi": true },
+                { "q": { "_id": { "$oid": "66a1b2c3d4e5f60718293a4b" } }, "u": { "$inc": { "n": 1 } }, "upsert": true }
+            ] }
+        }))
+        .unwrap();
+        let filters = write_filters(&row);
+        assert_eq!(filters.len(), 2);
+        assert_eq!(filters[0], (doc! { "status": 1 }, false));
+        let id = upsert_id(&filters[1].0).unwrap();
+        assert!(matches!(id, Bson::ObjectId(_)));
+        assert!(upsert_id(&doc! { "_id": { "$in": [1, 2] } }).is_none());
+
+        let mut touched = Touched::default();
+        assert!(touched.insert(&row.ns, &id));
+        assert!(!touched.insert(&row.ns, &id));
+
+        let pre_image = doc! { "_id": id.clone(), "n": 1, "l": 5_i64, "f": 1.0, "t": bson::DateTime::from_millis(1) };
+        let restore = restore_row(&row, &id, Some(pre_image.clone()));
+        let update = Document::deserialize(&restore.cmd["updates"][0]).unwrap();
+        // 类型需要和快照完全一致，否则 op-verify 的 hash 会不同
+        assert_eq!(update.get_document("u").unwrap(), &pre_image);
+        assert!(update.get_bool("upsert").unwrap());
+
+        let restore = restore_row(&row, &id, None);
+        assert!(matches!(restore.op, Op::Delete));
+        assert_eq!(
+            restore.cmd["deletes"][0]["q"]["_id"]["$oid"],
+            "66a1b2c3d4e5f60718293a4b"
+        );
+    }
+}
@@ -1,11 +1,71 @@
+use std::collections::BTreeSet;
+
 use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Debug, Deserialize, Serialize, Default)]
 pub(crate) struct OpState {
+    /// op_exec 的断点，已经连续执行完成的行数
     pub stress_index: i64,
     pub stress_start_ts: i64,
+    /// 最后一次保存断点的时间
     pub stress_end_ts: i64,
+    /// 断点对应的文件，`--continue` 时需要相同
+    #[serde(default)]
+    pub stress_file: String,
+    /// 断点时累计的指标
+    #[serde(default)]
+    pub stress_query_count: usize,
+    #[serde(default)]
+    pub stress_cost_ms: usize,
+    #[serde(default)]
+    pub stress_skip_count: usize,
 
     pub record_start_ts: i64,
     pub record_end_ts: i64,
+
+    /// op-export 已经连续完成的行数，中断后从这里继续
+    #[serde(default)]
+    pub export_index: usize,
+    /// op-export 的输出（data.op 或者 dump 目录），和本次的输出不同时重新开始
+    #[serde(default)]
+    pub export_target: String,
+}
+
+/// 并发处理时从头开始连续完成的行数，乱序完成的行先暂存
+#[derive(Debug, Default)]
+pub(crate) struct Watermark {
+    pub index: usize,
+    done: BTreeSet<usize>,
+}
+
+impl Watermark {
+    pub fn new(index: usize) -> Self {
+        Self {
+            index,
+            done: BTreeSet::new(),
+        }
+    }
+
+    /// 标记第 i 行完成，返回连续完成的行数
+    pub fn done(&mut self, i: usize) -> usize {
+        self.done.insert(i);
+        while self.done.remove(&self.index) {
+            self.index += 1;
+        }
+        self.index
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watermark() {
+        let mut watermark = Watermark::new(10);
+        assert_eq!(watermark.done(12), 10);
+        assert_eq!(watermark.done(11), 10);
+        assert_eq!(watermark.done(10), 13);
+        assert_eq!(watermark.done(14), 13);
+    }
 }
@@ -0,0 +1,169 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use bson::{Bson, Document};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::to_sha3;
+
+/// 压测前的基线，和 resume.op 同时生成，用于 op-verify 校验恢复后的状态
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Baseline {
+    pub created_at: i64,
+    /// ns => 集合的基线
+    pub namespaces: BTreeMap<String, NsBaseline>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct NsBaseline {
+    pub db: String,
+    pub coll: String,
+    /// 集合的文档数量
+    pub count: u64,
+    /// `_id`（canonical extjson）=> 文档的 hash，压测前不存在的为 None
+    pub docs: BTreeMap<String, Option<String>>,
+}
+
+impl Baseline {
+    /// 登记一个会被写入的集合，没有快照文档时也要校验它的文档数量
+    pub fn touch(&mut self, db: &str, coll: &str) -> &mut NsBaseline {
+        self.namespaces
+            .entry(format!("{}.{}", db, coll))
+            .or_insert_with(|| NsBaseline {
+                db: db.to_string(),
+                coll: coll.to_string(),
+                ..Default::default()
+            })
+    }
+
+    pub fn add(&mut self, db: &str, coll: &str, id: &Bson, pre_image: Option<&Document>) {
+        self.touch(db, coll)
+            .docs
+            .insert(id_key(id), pre_image.map(doc_hash));
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+}
+
+pub fn id_key(id: &Bson) -> String {
+    id.clone().into_canonical_extjson().to_string()
+}
+
+pub fn id_from_key(key: &str) -> anyhow::Result<Bson> {
+    Ok(Bson::try_from(serde_json::from_str::<serde_json::Value>(
+        key,
+    )?)?)
+}
+
+/// 文档内容的 hash，字段顺序不同也视为不同
+pub fn doc_hash(doc: &Document) -> String {
+    to_sha3(
+        &Bson::Document(doc.clone())
+            .into_canonical_extjson()
+            .to_string(),
+    )
+}
+
+/// 单个文档的差异
+#[derive(Debug, PartialEq)]
+pub enum DocDiff {
+    /// 压测前存在，现在不存在
+    Missing,
+    /// 压测前不存在，现在存在
+    Unexpected,
+    /// 内容不一致
+    Changed,
+}
+
+impl DocDiff {
+    /// 比较基线中的 hash 和当前文档
+    pub fn compare(expected: Option<&String>, live: Option<&Document>) -> Option<Self> {
+        match (expected, live) {
+            (None, None) => None,
+            (Some(_), None) => Some(DocDiff::Missing),
+            (None, Some(_)) => Some(DocDiff::Unexpected),
+            (Some(hash), Some(doc)) if *hash != doc_hash(doc) => Some(DocDiff::Changed),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DocDiff::Missing => "missing",
+            DocDiff::Unexpected => "unexpected",
+            DocDiff::Changed => "changed",
+        }
+    }
+}
+
+/// 一个集合的校验结果
+#[derive(Debug, Default)]
+pub struct NsReport {
+    pub ns: String,
+    pub expected_count: u64,
+    pub live_count: u64,
+    /// (_id, 差异)
+    pub diffs: Vec<(String, DocDiff)>,
+}
+
+impl NsReport {
+    pub fn is_ok(&self) -> bool {
+        self.expected_count == self.live_count && self.diffs.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::doc;
+
+    use super::*;
+
+    #[test]
+    fn test_baseline_diff() {
+        let a = doc! { "_id": 1, "n": 1 };
+        let mut baseline = Baseline::default();
+        baseline.add("xgj", "c", &Bson::Int32(1), Some(&a));
+        baseline.add("xgj", "c", &Bson::Int32(2), None);
+
+        let ns = &baseline.namespaces["xgj.c"];
+        let expected = ns.docs.get(&id_key(&Bson::Int32(1))).unwrap().as_ref();
+        assert_eq!(DocDiff::compare(expected, Some(&a)), None);
+        assert_eq!(
+            DocDiff::compare(expected, Some(&doc! { "_id": 1, "n": 2 })),
+            Some(DocDiff::Changed)
+        );
+        assert_eq!(DocDiff::compare(expected, None), Some(DocDiff::Missing));
+        let expected = ns.docs.get(&id_key(&Bson::Int32(2))).unwrap().as_ref();
+        assert_eq!(
+            DocDiff::compare(expected, Some(&doc! { "_id": 2 })),
+            Some(DocDiff::Unexpected)
+        );
+
+        assert_eq!(
+            id_from_key(&id_key(&Bson::Int32(2))).unwrap(),
+            Bson::Int32(2)
+        );
+    }
+
+    #[test]
+    fn test_baseline_touch() {
+        let mut baseline = Baseline::default();
+        baseline.touch("xgj", "empty");
+        baseline.add("xgj", "empty", &Bson::Int32(1), None);
+        baseline.touch("xgj", "empty");
+        baseline.touch("xgj", "upsert");
+
+        assert_eq!(baseline.namespaces.len(), 2);
+        assert_eq!(baseline.namespaces["xgj.empty"].docs.len(), 1);
+        assert!(baseline.namespaces["xgj.upsert"].docs.is_empty());
+        assert_eq!(baseline.namespaces["xgj.upsert"].coll, "upsert");
+    }
+}
@@ -1,4 +1,14 @@
 use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+
+use tokio::task::{JoinError, JoinHandle};
+use tokio::time::{Duration, Instant};
+
+/// 收到 SIGINT/SIGTERM 时设置的 code，ui 中停止/返回使用 1，结束使用 2
+pub static INTERRUPTED: usize = 3;
+
+/// 中断后等待正在执行的操作完成的最长时间
+pub static DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Debug, Default)]
 pub struct Signal {
@@ -20,3 +30,117 @@ impl Signal {
         self.code.load(std::sync::atomic::Ordering::Relaxed)
     }
 }
+
+impl Signal {
+    /// 等待 code 不为 0
+    pub async fn wait(&self) {
+        while self.get() == 0 {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+    }
+}
+
+/// 监听 SIGINT/SIGTERM：第一次设置 signal，等待正在执行的操作完成后正常结束（生成报告、恢复 profile），第二次强制退出
+pub fn listen(signal: Arc<Signal>) {
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        let mut term =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).unwrap();
+        loop {
+            #[cfg(unix)]
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = term.recv() => {}
+            }
+            #[cfg(not(unix))]
+            let _ = tokio::signal::ctrl_c().await;
+
+            if signal.get() == INTERRUPTED {
+                eprintln!("Signal [{}] force exit.", chrono::Local::now().timestamp());
+                std::process::exit(130);
+            }
+            signal.set(INTERRUPTED);
+            eprintln!(
+                "Signal [{}] interrupted, waiting for in-flight ops (up to {}s), press Ctrl-C again to force exit.",
+                chrono::Local::now().timestamp(),
+                DRAIN_TIMEOUT.as_secs()
+            );
+        }
+    });
+}
+
+/// 等待所有任务结束，收到中断信号后最多再等待 timeout，超时的任务会被取消，返回取消的数量
+///
+/// 第一次取消任务之前调用 before_abort（例如冻结断点，被取消的任务中没有执行完的行不计入）
+pub async fn join_all(
+    handles: Vec<JoinHandle<()>>,
+    signal: &Signal,
+    timeout: Duration,
+    before_abort: impl FnOnce(),
+) -> Result<usize, JoinError> {
+    let mut before_abort = Some(before_abort);
+    let mut deadline = None;
+    let mut aborted = 0;
+    for mut handle in handles {
+        loop {
+            tokio::select! {
+                res = &mut handle => {
+                    match res {
+                        Err(err) if err.is_cancelled() => {}
+                        res => res?,
+                    }
+                    break;
+                }
+                _ = tokio::time::sleep(Duration::from_millis(200)) => {
+                    if signal.get() != INTERRUPTED {
+                        continue;
+                    }
+                    let deadline = *deadline.get_or_insert_with(|| Instant::now() + timeout);
+                    if Instant::now() >= deadline {
+                        if let Some(before_abort) = before_abort.take() {
+                            before_abort();
+                        }
+                        handle.abort();
+                        aborted += 1;
+                        let _ = handle.await;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    Ok(aborted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_join_all_drain() {
+        let signal = Arc::new(Signal::new());
+        let handles = vec![
+            tokio::spawn(async {}),
+            tokio::spawn(async {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+            }),
+        ];
+        signal.set(INTERRUPTED);
+        signal.wait().await;
+        let aborted = join_all(handles, &signal, Duration::from_millis(300), || {})
+            .await
+            .unwrap();
+        assert_eq!(aborted, 1);
+
+        // ui 中停止时等待任务自己结束
+        let signal = Signal::new();
+        signal.set(1);
+        let handles = vec![tokio::spawn(async {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        })];
+        let aborted = join_all(handles, &signal, Duration::from_millis(100), || {})
+            .await
+            .unwrap();
+        assert_eq!(aborted, 0);
+    }
+}
@@ -1,6 +1,5 @@
 use once_cell::sync::Lazy;
 
-use rayon::prelude::*;
 use serde::Deserialize;
 use std::collections::{HashMap, HashSet};
 use std::fs::File;
@@ -8,30 +7,11 @@ use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Instant;
 
+use crate::commands::AuditFormat;
 use crate::indicator::Metric;
+use crate::tool::audit;
 use crate::utils::count_lines;
 
-#[derive(Debug, Deserialize)]
-pub struct Record {
-    pub __source__: String,
-    pub __time__: u64,
-    pub __topic__: String,
-    pub audit_type: String,
-    pub coll: String,
-    pub command: String,
-    pub db: String,
-    pub docs_examined: Option<u64>,
-    pub instanceid: String,
-    pub keys_examined: Option<u64>,
-    pub latency: u64,
-    pub optype: String,
-    pub return_num: Option<f64>,
-    pub thread_id: String,
-    pub time: u64,
-    pub user: String,
-    pub user_ip: String,
-}
-
 #[derive(Debug, Deserialize)]
 struct Stat {
     count: u64,
@@ -39,8 +19,9 @@ struct Stat {
     eg: Vec<String>,
 }
 
-pub fn analysis_alilog_csv(path: &str) -> Result<(), anyhow::Error> {
-    println!("analysis_alilog_csv: {}", path);
+pub fn analysis_audit_log(path: &str, format: Option<AuditFormat>) -> Result<(), anyhow::Error> {
+    println!("analysis_audit_log: {}", path);
+    let converter = audit::converter(path, format)?;
     let map = Arc::new(Mutex::new(HashMap::<String, Stat>::new()));
     let file: File = File::open(path)?;
 
@@ -50,7 +31,7 @@ pub fn analysis_alilog_csv(path: &str) -> Result<(), anyhow::Error> {
 
     let current = watch_progress("Analysis".to_string(), total_lines);
 
-    each_alilog_csv(file, |record| {
+    converter.each(file, &|record| {
         current.add(1);
         // let command: Value = serde_json::from_str(&record.command)?;
         let key = format!(
@@ -97,17 +78,6 @@ pub fn analysis_alilog_csv(path: &str) -> Result<(), anyhow::Error> {
     Ok(())
 }
 
-pub fn each_alilog_csv<CB: Fn(Record) + Sync + Send>(file: File, cb: CB) {
-    let reader = std::io::BufReader::new(file);
-    let mut rdr = csv::Reader::from_reader(reader);
-    rdr.records().par_bridge().for_each(|result| {
-        if let Ok(record) = result {
-            let record: Record = record.deserialize(None).unwrap();
-            cb(record);
-        }
-    });
-}
-
 pub fn watch_progress(name: String, total_lines: usize) -> Arc<Metric> {
     let current: Arc<Metric> = Arc::new(Metric::default());
 
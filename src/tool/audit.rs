@@ -0,0 +1,458 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use rayon::prelude::*;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::commands::AuditFormat;
+use crate::mongobar::op_row::Op;
+
+/// 各个云厂商审计日志统一后的记录
+#[derive(Debug, Clone, Default)]
+pub struct AuditRecord {
+    pub db: String,
+    pub coll: String,
+    pub optype: String,
+    /// 原始的命令文本，分析时用于提取 key 和作为示例
+    pub command: String,
+    /// 毫秒
+    pub latency: u64,
+    /// 毫秒时间戳
+    pub time: u64,
+}
+
+/// 审计日志转换器，每种云厂商的导出格式实现一个
+pub trait AuditConverter: Sync + Send {
+    fn name(&self) -> &'static str;
+
+    /// 通过文件首行判断是否为该格式
+    fn detect(&self, header: &str) -> bool;
+
+    fn each(&self, file: File, cb: &(dyn Fn(AuditRecord) + Sync + Send));
+
+    /// 从记录中取出可以直接执行的命令
+    fn to_cmd(&self, record: &AuditRecord) -> Option<Value> {
+        serde_json::from_str(&record.command).ok()
+    }
+}
+
+/// 指定了格式就直接使用，否则通过文件首行自动识别
+pub fn converter(
+    path: &str,
+    format: Option<AuditFormat>,
+) -> Result<Box<dyn AuditConverter>, anyhow::Error> {
+    if let Some(format) = format {
+        return Ok(from_format(format));
+    }
+
+    let mut header = String::new();
+    BufReader::new(File::open(path)?).read_line(&mut header)?;
+    let header = header.trim_start_matches('\u{feff}');
+
+    if let Some(converter) = detect(header) {
+        println!(
+            "Detected audit log format [{}] from {:?}",
+            converter.name(),
+            Path::new(path).file_name().unwrap_or_default()
+        );
+        return Ok(converter);
+    }
+
+    Err(anyhow::anyhow!(
+        "unknown audit log format of {}, please specify --format",
+        path
+    ))
+}
+
+/// 通过文件首行识别格式
+fn detect(header: &str) -> Option<Box<dyn AuditConverter>> {
+    [
+        AuditFormat::Aliyun,
+        // DocumentDB 的审计事件也有 atype，需要在 Atlas 之前判断
+        AuditFormat::Documentdb,
+        AuditFormat::Atlas,
+        AuditFormat::Tencent,
+        AuditFormat::Huawei,
+    ]
+    .into_iter()
+    .map(from_format)
+    .find(|converter| converter.detect(header))
+}
+
+fn from_format(format: AuditFormat) -> Box<dyn AuditConverter> {
+    match format {
+        AuditFormat::Aliyun => Box::new(AliyunCsv),
+        AuditFormat::Atlas => Box::new(JsonLines {
+            name: "atlas",
+            markers: &["\"atype\"", "\"attr\""],
+        }),
+        AuditFormat::Documentdb => Box::new(JsonLines {
+            name: "documentdb",
+            markers: &["\"timestamp_utc\"", "\"millis\""],
+        }),
+        AuditFormat::Tencent => Box::new(ColumnCsv {
+            name: "tencent",
+            db: &["DBName", "数据库名"],
+            coll: &["CollectionName", "TableName", "集合名"],
+            ns: &["Namespace"],
+            optype: &["SqlType", "操作类型"],
+            command: &["Sql", "SQL 命令", "命令"],
+            latency: &["ExecTime", "执行时间(ms)", "执行时间"],
+            time: &["Timestamp", "时间"],
+        }),
+        AuditFormat::Huawei => Box::new(ColumnCsv {
+            name: "huawei",
+            db: &["db", "database", "数据库"],
+            coll: &["collection", "coll", "集合"],
+            ns: &["ns", "namespace"],
+            optype: &["op", "operation", "type", "操作类型"],
+            command: &["command", "statement", "语句"],
+            latency: &["cost", "duration", "millis", "耗时(ms)"],
+            time: &["time", "timestamp", "时间"],
+        }),
+    }
+}
+
+/// 优先使用日志中的操作类型，不能识别的时候通过命令的第一个字段判断
+pub fn infer_op(optype: &str, cmd: &Value) -> Op {
+    match optype {
+        "query" => return Op::Find,
+        "remove" => return Op::Delete,
+        "getmore" => return Op::GetMore,
+        _ => {}
+    }
+    let op = Op::from(optype.to_string());
+    if !matches!(op, Op::None | Op::Aggregate) {
+        return op;
+    }
+    match cmd
+        .as_object()
+        .and_then(|o| o.keys().next())
+        .map(|k| Op::from(k.to_string()))
+        .unwrap_or_default()
+    {
+        Op::None | Op::Aggregate => Op::from_cmd(cmd),
+        op => op,
+    }
+}
+
+/// 阿里云审计日志 csv
+pub struct AliyunCsv;
+
+/// 只取需要的列，按照表头反序列化
+#[derive(Debug, Deserialize)]
+pub struct AliyunRecord {
+    pub coll: String,
+    pub command: String,
+    pub db: String,
+    pub latency: u64,
+    pub optype: String,
+    pub time: u64,
+}
+
+impl AuditConverter for AliyunCsv {
+    fn name(&self) -> &'static str {
+        "aliyun"
+    }
+
+    fn detect(&self, header: &str) -> bool {
+        header.contains("__source__") && header.contains("optype") && header.contains("command")
+    }
+
+    fn each(&self, file: File, cb: &(dyn Fn(AuditRecord) + Sync + Send)) {
+        let reader = BufReader::new(file);
+        let mut rdr = csv::Reader::from_reader(reader);
+        let headers = match rdr.headers() {
+            Ok(headers) => headers.clone(),
+            Err(_) => return,
+        };
+        rdr.records().par_bridge().for_each(|result| {
+            if let Ok(record) = result {
+                let record: AliyunRecord = match record.deserialize(Some(&headers)) {
+                    Ok(record) => record,
+                    Err(e) => {
+                        let line = record.position().map(|p| p.line()).unwrap_or_default();
+                        eprintln!("Skip bad audit record at line {}: {}", line, e);
+                        return;
+                    }
+                };
+                cb(AuditRecord {
+                    db: record.db,
+                    coll: record.coll,
+                    optype: record.optype,
+                    command: record.command,
+                    latency: record.latency,
+                    time: record.time,
+                });
+            }
+        });
+    }
+
+    fn to_cmd(&self, record: &AuditRecord) -> Option<Value> {
+        let cmd: Value = serde_json::from_str(&record.command).ok()?;
+        cmd.get("args").cloned()
+    }
+}
+
+/// 每行一个 json 的日志，支持三种结构：
+/// - 审计事件（Atlas / DocumentDB audit，DocumentDB 多一个 `timestamp_utc`）：`{ "atype": "authCheck", "param": { "ns", "args" } }`
+/// - mongod 结构化日志（Atlas database access / slow query）：`{ "t", "attr": { "ns", "command", "durationMillis" } }`
+/// - profiler 导出（DocumentDB profiler）：`{ "op", "ns", "command", "millis", "ts" }`
+pub struct JsonLines {
+    name: &'static str,
+    markers: &'static [&'static str],
+}
+
+impl AuditConverter for JsonLines {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn detect(&self, header: &str) -> bool {
+        header.trim_start().starts_with('{') && self.markers.iter().any(|m| header.contains(m))
+    }
+
+    fn each(&self, file: File, cb: &(dyn Fn(AuditRecord) + Sync + Send)) {
+        BufReader::new(file).lines().par_bridge().for_each(|line| {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => return,
+            };
+            if line.trim().is_empty() {
+                return;
+            }
+            if let Ok(v) = serde_json::from_str::<Value>(&line) {
+                if let Some(record) = parse_json_event(&v) {
+                    cb(record);
+                }
+            }
+        });
+    }
+}
+
+fn parse_json_event(v: &Value) -> Option<AuditRecord> {
+    let (ns, cmd, optype, latency, time) = if let Some(param) = v.get("param") {
+        if v.get("atype")?.as_str()? != "authCheck" {
+            return None;
+        }
+        (
+            param.get("ns")?.as_str()?,
+            param.get("args")?,
+            param.get("command").and_then(|c| c.as_str()).unwrap_or(""),
+            0,
+            v.get("ts"),
+        )
+    } else if let Some(attr) = v.get("attr") {
+        (
+            attr.get("ns")?.as_str()?,
+            attr.get("command")?,
+            "",
+            attr.get("durationMillis")
+                .and_then(|d| d.as_f64())
+                .unwrap_or(0.0) as u64,
+            v.get("t"),
+        )
+    } else {
+        (
+            v.get("ns")?.as_str()?,
+            v.get("command")?,
+            v.get("op").and_then(|o| o.as_str()).unwrap_or(""),
+            v.get("millis").and_then(|d| d.as_f64()).unwrap_or(0.0) as u64,
+            v.get("ts"),
+        )
+    };
+
+    let (db, coll) = split_ns(ns);
+    Some(AuditRecord {
+        db,
+        coll,
+        optype: optype.to_string(),
+        command: serde_json::to_string(cmd).ok()?,
+        latency,
+        time: time.map(parse_json_time).unwrap_or_default(),
+    })
+}
+
+/// 表头按别名匹配的 csv（腾讯云、华为云控制台导出）
+pub struct ColumnCsv {
+    name: &'static str,
+    db: &'static [&'static str],
+    coll: &'static [&'static str],
+    ns: &'static [&'static str],
+    optype: &'static [&'static str],
+    command: &'static [&'static str],
+    latency: &'static [&'static str],
+    time: &'static [&'static str],
+}
+
+impl ColumnCsv {
+    fn column(headers: &[String], aliases: &[&str]) -> Option<usize> {
+        aliases.iter().find_map(|alias| {
+            headers
+                .iter()
+                .position(|h| h.trim().eq_ignore_ascii_case(alias))
+        })
+    }
+}
+
+impl AuditConverter for ColumnCsv {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn detect(&self, header: &str) -> bool {
+        let headers: Vec<String> = header
+            .trim()
+            .split(',')
+            .map(|h| h.replace('"', ""))
+            .collect();
+        Self::column(&headers, self.command).is_some()
+            && (Self::column(&headers, self.db).is_some()
+                || Self::column(&headers, self.ns).is_some())
+    }
+
+    fn each(&self, file: File, cb: &(dyn Fn(AuditRecord) + Sync + Send)) {
+        let mut rdr = csv::Reader::from_reader(BufReader::new(file));
+        let headers: Vec<String> = match rdr.headers() {
+            Ok(headers) => headers.iter().map(|h| h.to_string()).collect(),
+            Err(_) => return,
+        };
+        let db = Self::column(&headers, self.db);
+        let coll = Self::column(&headers, self.coll);
+        let ns = Self::column(&headers, self.ns);
+        let optype = Self::column(&headers, self.optype);
+        let command = match Self::column(&headers, self.command) {
+            Some(command) => command,
+            None => return,
+        };
+        let latency = Self::column(&headers, self.latency);
+        let time = Self::column(&headers, self.time);
+
+        rdr.records().par_bridge().for_each(|result| {
+            let record = match result {
+                Ok(record) => record,
+                Err(_) => return,
+            };
+            let get = |i: Option<usize>| i.and_then(|i| record.get(i)).unwrap_or("").to_string();
+
+            let (mut r_db, mut r_coll) = (get(db), get(coll));
+            if r_db.is_empty() || r_coll.is_empty() {
+                let (n_db, n_coll) = split_ns(&get(ns));
+                if r_db.is_empty() {
+                    r_db = n_db;
+                }
+                if r_coll.is_empty() {
+                    r_coll = n_coll;
+                }
+            }
+
+            cb(AuditRecord {
+                db: r_db,
+                coll: r_coll,
+                optype: get(optype),
+                command: get(Some(command)),
+                latency: get(latency).parse::<f64>().unwrap_or(0.0) as u64,
+                time: parse_time(&get(time)),
+            });
+        });
+    }
+}
+
+fn split_ns(ns: &str) -> (String, String) {
+    match ns.split_once('.') {
+        Some((db, coll)) => (db.to_string(), coll.to_string()),
+        None => (ns.to_string(), String::new()),
+    }
+}
+
+fn parse_json_time(v: &Value) -> u64 {
+    match v {
+        Value::Object(o) => o.get("$date").map(parse_json_time).unwrap_or_default(),
+        Value::String(s) => parse_time(s),
+        Value::Number(n) => parse_time(&n.to_string()),
+        _ => 0,
+    }
+}
+
+/// 统一转换为毫秒时间戳，支持秒/毫秒数字和常见的日期字符串
+fn parse_time(s: &str) -> u64 {
+    let s = s.trim();
+    if let Ok(n) = s.parse::<f64>() {
+        return if n > 1e12 {
+            n as u64
+        } else {
+            (n * 1000.0) as u64
+        };
+    }
+    if let Ok(dt) = chrono::DateTime::parse_from_rfc3339(s) {
+        return dt.timestamp_millis() as u64;
+    }
+    for fmt in [
+        "%Y-%m-%d %H:%M:%S%.f",
+        "%Y-%m-%d %H:%M:%S",
+        "%Y/%m/%d %H:%M:%S",
+    ] {
+        if let Ok(dt) = chrono::NaiveDateTime::parse_from_str(s, fmt) {
+            if let Some(dt) = dt.and_local_timezone(chrono::Local).single() {
+                return dt.timestamp_millis() as u64;
+            }
+        }
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn converter_of(header: &str) -> Option<&'static str> {
+        detect(header).map(|c| c.name())
+    }
+
+    #[test]
+    fn test_detect_and_parse() {
+        let aliyun = "__source__,__time__,__topic__,audit_type,coll,command,db,docs_examined,instanceid,keys_examined,latency,optype,return_num,thread_id,time,user,user_ip";
+        assert!(from_format(AuditFormat::Aliyun).detect(aliyun));
+        assert!(!from_format(AuditFormat::Tencent).detect(aliyun));
+        assert!(from_format(AuditFormat::Tencent)
+            .detect("Timestamp,ClientIp,DBName,TableName,SqlType,Sql,ExecTime"));
+
+        let atlas = json!({
+            "atype": "authCheck",
+            "ts": { "$date": "2024-07-08T00:00:00.000Z" },
+            "param": { "command": "find", "ns": "xgj.users", "args": { "find": "users", "filter": { "a": 1 } } }
+        });
+        assert_eq!(converter_of(&atlas.to_string()), Some("atlas"),);
+        let documentdb = json!({
+            "atype": "authCheck", "ts": 1720396800000u64, "timestamp_utc": "2024-07-08 00:00:00.000",
+            "param": { "command": "find", "ns": "xgj.users", "args": { "find": "users" } }
+        });
+        assert_eq!(converter_of(&documentdb.to_string()), Some("documentdb"));
+        let record = parse_json_event(&atlas).unwrap();
+        assert_eq!(record.db, "xgj");
+        assert_eq!(record.coll, "users");
+        assert_eq!(record.time, 1720396800000);
+        assert!(matches!(
+            infer_op(
+                &record.optype,
+                &serde_json::from_str(&record.command).unwrap()
+            ),
+            Op::Find
+        ));
+
+        let profiler = json!({ "op": "command", "ns": "xgj.users", "command": { "aggregate": "users", "pipeline": [] }, "millis": 12, "ts": 1720396800 });
+        assert_eq!(converter_of(&profiler.to_string()), Some("documentdb"));
+        let record = parse_json_event(&profiler).unwrap();
+        assert_eq!(record.latency, 12);
+        assert!(matches!(
+            infer_op(
+                &record.optype,
+                &serde_json::from_str(&record.command).unwrap()
+            ),
+            Op::Aggregate
+        ));
+    }
+}
@@ -8,14 +8,21 @@ use std::{
 use bson::doc;
 use serde_json::Value;
 
-use crate::tool::analyze::{each_alilog_csv, watch_progress};
+use crate::commands::AuditFormat;
+use crate::tool::analyze::watch_progress;
+use crate::tool::audit::{self, infer_op};
 use crate::{
-    mongobar::op_row::{Op, OpRow},
+    mongobar::{op_header, op_row::OpRow},
     utils::{count_lines, match_date_replace, to_sha3},
 };
 
-pub fn convert_alilog_csv(csv_path: &str, filter_db: String) -> Result<PathBuf, anyhow::Error> {
-    println!("convert_alilog_csv: {}", csv_path);
+pub fn convert_audit_log(
+    csv_path: &str,
+    filter_db: String,
+    format: Option<AuditFormat>,
+) -> Result<PathBuf, anyhow::Error> {
+    println!("convert_audit_log: {}", csv_path);
+    let converter = audit::converter(csv_path, format)?;
     let csv_path = PathBuf::from(csv_path);
     let file: File = File::open(csv_path.clone())?;
     let current = watch_progress(
@@ -29,20 +36,24 @@ pub fn convert_alilog_csv(csv_path: &str, filter_db: String) -> Result<PathBuf,
     let w_file = File::create(out_path.clone())?;
     let writer = Arc::new(Mutex::new(std::io::BufWriter::new(w_file)));
 
-    each_alilog_csv(file, |record| {
+    converter.each(file, &|record| {
         current.add(1);
         if filter_db.len() > 0 && record.db != filter_db {
             return;
         }
-        let cmd: Value = serde_json::from_str(&record.command).unwrap();
+        let cmd: Value = match converter.to_cmd(&record) {
+            Some(cmd) => cmd,
+            None => return,
+        };
         let op_row = OpRow {
             id: to_sha3(record.command.as_str()),
-            op: Op::from(record.optype.clone()),
+            op: infer_op(&record.optype, &cmd),
             ns: format!("{}.{}", record.db, record.coll),
             db: record.db,
             coll: record.coll,
-            cmd: cmd.get("args").unwrap().to_owned(),
+            cmd,
             ts: record.time as i64,
+            profile: None,
             args: doc! {},
             key: String::new(),
             hash: String::new(),
@@ -61,5 +72,9 @@ pub fn convert_alilog_csv(csv_path: &str, filter_db: String) -> Result<PathBuf,
             )
             .unwrap();
     });
+    writer.lock().unwrap().flush()?;
+
+    let header = op_header::OpHeader::new(&format!("audit:{}", converter.name()), &filter_db, "");
+    op_header::stamp(&out_path, header)?;
     Ok(out_path)
 }
@@ -1,30 +1,46 @@
 use std::io::BufRead;
 
+use crate::mongobar::op_file::{OpReader, OpRecord};
+use crate::mongobar::op_filter::OpFilter;
 use crate::mongobar::op_row::OpRow;
 
-pub fn reg_filter_line(target: &str, filter: &str) -> usize {
+/// 通过过滤表达式（`re:` 开头时按正则匹配原始的行）筛选记录，输出为 json 行
+pub fn reg_filter_line(target: &str, filter: &str) -> anyhow::Result<usize> {
     let mut line_number = 0;
-    let re = regex::Regex::new(filter).unwrap();
-    let file = std::fs::File::open(target).unwrap();
-    let reader = std::io::BufReader::new(file);
-    for line in reader.lines() {
-        let line = line.unwrap();
-        if re.is_match(&line) {
-            println!("{}", line);
-            line_number += 1;
+    let filter = OpFilter::parse(filter)?;
+    let mut reader = OpReader::open(std::path::Path::new(target)).unwrap();
+    while let Some(record) = reader.read_record().unwrap() {
+        if !filter.matches_record(&record) {
+            continue;
+        }
+        if let OpFilter::Expr(_) = filter {
+            if !record.is_row() {
+                continue;
+            }
+            let row = match &record {
+                OpRecord::Line(line) => serde_json::from_str::<OpRow>(line).unwrap(),
+                OpRecord::Bson(doc) => OpRow::from_bson(doc.clone()).unwrap(),
+            };
+            if !filter.matches_row(&row) {
+                continue;
+            }
         }
+        match record {
+            OpRecord::Line(line) => println!("{}", line),
+            OpRecord::Bson(doc) => println!("{}", bson::Bson::Document(doc).into_relaxed_extjson()),
+        }
+        line_number += 1;
     }
 
-    line_number
+    Ok(line_number)
 }
 
 pub fn mode_filter_line(target: &str, mode: &str) -> usize {
     let mut line_number = 0;
-    let file = std::fs::File::open(target).unwrap();
-    let reader = std::io::BufReader::new(file);
+    let reader = OpReader::open(std::path::Path::new(target)).unwrap();
     for line in reader.lines() {
         let line = line.unwrap();
-        if line.trim().is_empty() {
+        if line.trim().is_empty() || line.starts_with('#') {
             continue;
         }
         let row = serde_json::from_str::<OpRow>(&line).unwrap();
@@ -0,0 +1,304 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use bson::Document;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::mongobar::op_file::{self, OpFormat, OpReader, OpRecord};
+use crate::mongobar::op_header::{self, OpHeader};
+use crate::mongobar::op_row::{Op, OpRow};
+use crate::utils::get_db_coll;
+
+#[derive(Debug, Default)]
+pub struct LintReport {
+    pub rows: usize,
+    pub bad: usize,
+    pub fixed: usize,
+    pub dropped: usize,
+}
+
+/// 检查 op 文件中的每一条记录，`fix` 为 true 时修复能修复的记录并丢弃其他的错误记录（注释行也会被丢弃）
+///
+/// 修复时边读边写入同目录下的临时文件，完成后替换原文件
+pub fn lint(target: &str, fix: bool) -> anyhow::Result<LintReport> {
+    let path = Path::new(target);
+    let format = OpFormat::from_path(path);
+    let mut report = LintReport::default();
+    let mut header: Option<OpHeader> = None;
+    let tmp = op_file::sibling_tmp(path);
+    let mut writer = match fix {
+        true => Some(op_file::create(&tmp)?),
+        false => None,
+    };
+
+    let mut reader = OpReader::open(path)?;
+    let mut line = 0;
+    while let Some(record) = reader.read_record()? {
+        line += 1;
+        if let Some(h) = OpHeader::from_record(&record) {
+            header = Some(h);
+            continue;
+        }
+        if !record.is_row() {
+            continue;
+        }
+        report.rows += 1;
+
+        let row = match record {
+            OpRecord::Line(line) => serde_json::from_str::<OpRow>(&line).map_err(|e| e.to_string()),
+            OpRecord::Bson(doc) => OpRow::from_bson(doc).map_err(|e| e.to_string()),
+        };
+        let mut row = match row {
+            Ok(row) => row,
+            Err(e) => {
+                report.bad += 1;
+                println!("line {}: invalid row: {}", line, e);
+                if fix {
+                    report.dropped += 1;
+                }
+                continue;
+            }
+        };
+
+        let issues = lint_row(&row);
+        if issues.is_empty() {
+            if let Some(writer) = &mut writer {
+                op_file::write_row(writer, format, &row)?;
+            }
+            continue;
+        }
+        report.bad += 1;
+        println!("line {}: [{}] {}", line, row.id, issues.join("; "));
+        let Some(writer) = &mut writer else {
+            continue;
+        };
+        if repair_row(&mut row) && lint_row(&row).is_empty() {
+            report.fixed += 1;
+            op_file::write_row(writer, format, &row)?;
+        } else {
+            report.dropped += 1;
+        }
+    }
+
+    if let Some(header) = &header {
+        if header.count != report.rows {
+            println!(
+                "header: count {} does not match {} rows",
+                header.count, report.rows
+            );
+        }
+    }
+
+    if let Some(mut writer) = writer {
+        writer.flush()?;
+        drop(writer);
+        if report.bad > 0 || header.as_ref().is_some_and(|h| h.count != report.rows) {
+            fs::rename(&tmp, path)?;
+            if let Some(header) = header {
+                op_header::stamp(path, header)?;
+            }
+        } else {
+            fs::remove_file(&tmp)?;
+        }
+    }
+
+    Ok(report)
+}
+
+/// 必须能转换为 Document，op_exec 中直接 unwrap
+fn check_doc(issues: &mut Vec<String>, name: String, v: &Value) {
+    if !v.is_object() {
+        issues.push(format!("{} must be an object", name));
+    } else if let Err(e) = Document::deserialize(v) {
+        issues.push(format!("{} is not a valid document: {}", name, e));
+    }
+}
+
+/// 返回记录中的问题，空则表示可以正常执行
+///
+/// 检查 op_exec/trans_value_to_doc 中 unwrap 的字段和解析：cmd、documents、updates（包括数组形式的 u）、deletes、query、pipeline
+pub fn lint_row(row: &OpRow) -> Vec<String> {
+    let mut issues = vec![];
+    if let Op::None = row.op {
+        return issues;
+    }
+    if row.db.is_empty() || row.coll.is_empty() {
+        issues.push("missing db/coll".to_string());
+    }
+    if !row.cmd.is_object() {
+        issues.push("cmd is not an object".to_string());
+        return issues;
+    }
+
+    let cmd = &row.cmd;
+    match row.op {
+        Op::Find | Op::Count | Op::Command | Op::Distinct | Op::GeoNear => {
+            if let Err(e) = Document::deserialize(cmd) {
+                issues.push(format!("cmd is not a valid document: {}", e));
+            }
+            if let Op::Distinct = row.op {
+                if !cmd.get("key").is_some_and(|v| v.is_string()) {
+                    issues.push("distinct requires key".to_string());
+                }
+            }
+        }
+        Op::Insert => match cmd.get("documents").and_then(|v| v.as_array()) {
+            Some(documents) => {
+                for (i, doc) in documents.iter().enumerate() {
+                    check_doc(&mut issues, format!("documents[{}]", i), doc);
+                }
+            }
+            None => issues.push("insert requires documents array".to_string()),
+        },
+        Op::Update => match cmd.get("updates") {
+            Some(Value::Array(updates)) => {
+                for (i, update) in updates.iter().enumerate() {
+                    check_doc(&mut issues, format!("updates[{}]", i), update);
+                    if !update.get("q").is_some_and(Value::is_object) {
+                        issues.push(format!("updates[{}] requires q", i));
+                    }
+                    match update.get("u") {
+                        Some(u @ Value::Object(_)) => {
+                            check_doc(&mut issues, format!("updates[{}].u", i), u)
+                        }
+                        // 聚合管道形式的更新
+                        Some(Value::Array(stages)) => {
+                            for (j, stage) in stages.iter().enumerate() {
+                                check_doc(&mut issues, format!("updates[{}].u[{}]", i, j), stage);
+                            }
+                        }
+                        _ => issues.push(format!("updates[{}] requires u", i)),
+                    }
+                }
+            }
+            Some(_) => issues.push("updates must be an array".to_string()),
+            None => {
+                check_doc(&mut issues, "cmd".to_string(), cmd);
+                if !cmd.get("q").is_some_and(Value::is_object) {
+                    issues.push("update requires updates or q".to_string());
+                }
+                if !cmd.get("u").is_some_and(Value::is_object) {
+                    issues.push("update requires u".to_string());
+                }
+            }
+        },
+        Op::Delete => match cmd.get("deletes") {
+            Some(Value::Array(deletes)) => {
+                for (i, delete) in deletes.iter().enumerate() {
+                    check_doc(&mut issues, format!("deletes[{}]", i), delete);
+                    if !delete.get("q").is_some_and(Value::is_object) {
+                        issues.push(format!("deletes[{}] requires q", i));
+                    }
+                }
+            }
+            Some(_) => issues.push("deletes must be an array".to_string()),
+            None => {
+                check_doc(&mut issues, "cmd".to_string(), cmd);
+                if !cmd.get("q").is_some_and(Value::is_object) {
+                    issues.push("delete requires deletes or q".to_string());
+                }
+            }
+        },
+        Op::FindAndModify => match cmd.get("query") {
+            Some(query) => check_doc(&mut issues, "query".to_string(), query),
+            None => issues.push("findAndModify requires query".to_string()),
+        },
+        Op::Aggregate | Op::CountDocuments | Op::Search => {
+            match cmd.get("pipeline").and_then(|v| v.as_array()) {
+                Some(pipeline) => {
+                    if let Some(i) = pipeline
+                        .iter()
+                        .position(|v| Document::deserialize(v).is_err())
+                    {
+                        issues.push(format!("pipeline[{}] is not a valid stage", i));
+                    }
+                }
+                None => issues.push("aggregate requires pipeline array".to_string()),
+            }
+        }
+        Op::GetMore | Op::None => {}
+    }
+    issues
+}
+
+/// 修复能修复的问题：补全 db/coll/ns，单个的 documents/updates/deletes 包装成数组
+pub fn repair_row(row: &mut OpRow) -> bool {
+    let mut repaired = false;
+    if (row.db.is_empty() || row.coll.is_empty()) && row.ns.contains('.') {
+        let (db, coll) = get_db_coll(&row.ns);
+        row.db = db;
+        row.coll = coll;
+        repaired = true;
+    }
+    if row.ns.is_empty() && !row.db.is_empty() && !row.coll.is_empty() {
+        row.ns = format!("{}.{}", row.db, row.coll);
+        repaired = true;
+    }
+    let key = match row.op {
+        Op::Insert => "documents",
+        Op::Update => "updates",
+        Op::Delete => "deletes",
+        _ => return repaired,
+    };
+    if let Some(v) = row.cmd.get_mut(key) {
+        if v.is_object() {
+            *v = Value::Array(vec![v.take()]);
+            repaired = true;
+        }
+    }
+    repaired
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(v: Value) -> OpRow {
+        serde_json::from_value(v).unwrap()
+    }
+
+    #[test]
+    fn test_lint_and_repair_row() {
+        let ok = row(serde_json::json!({
+            "id": "1", "op": "Update", "db": "xgj", "coll": "c", "ns": "xgj.c", "ts": 0,
+            "cmd": { "update": "c", "updates": [{ "q": { "a": 1 }, "u": { "$set": { "b": 1 } } }] }
+        }));
+        assert!(lint_row(&ok).is_empty());
+
+        let mut bad = row(serde_json::json!({
+            "id": "2", "op": "Insert", "db": "", "coll": "", "ns": "xgj.c", "ts": 0,
+            "cmd": { "insert": "c", "documents": { "a": 1 } }
+        }));
+        assert_eq!(lint_row(&bad).len(), 2);
+        assert!(repair_row(&mut bad));
+        assert!(lint_row(&bad).is_empty());
+        assert_eq!(bad.coll, "c");
+
+        let mut broken = row(serde_json::json!({
+            "id": "3", "op": "Aggregate", "db": "xgj", "coll": "c", "ns": "xgj.c", "ts": 0,
+            "cmd": { "aggregate": "c" }
+        }));
+        assert!(!lint_row(&broken).is_empty());
+        assert!(!repair_row(&mut broken));
+
+        // op_exec 中 unwrap 的解析：超出 i64 的数字不能转换为 Document
+        let pipeline = row(serde_json::json!({
+            "id": "4", "op": "Update", "db": "xgj", "coll": "c", "ns": "xgj.c", "ts": 0,
+            "cmd": { "update": "c", "updates": [
+                { "q": { "a": 1 }, "u": [{ "$set": { "b": 1 } }] },
+                { "q": { "a": 1 }, "u": [{ "$set": { "b": u64::MAX } }] }
+            ] }
+        }));
+        let issues = lint_row(&pipeline);
+        assert_eq!(issues.len(), 2);
+        assert!(issues[0].starts_with("updates[1] is not a valid document"));
+        assert!(issues[1].starts_with("updates[1].u[0] is not a valid document"));
+        let insert = row(serde_json::json!({
+            "id": "5", "op": "Insert", "db": "xgj", "coll": "c", "ns": "xgj.c", "ts": 0,
+            "cmd": { "insert": "c", "documents": [{ "a": u64::MAX }, 1] }
+        }));
+        assert_eq!(lint_row(&insert).len(), 2);
+    }
+}
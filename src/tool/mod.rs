@@ -1,3 +1,8 @@
 pub mod analyze;
+pub mod audit;
 pub mod convert;
 pub mod filter;
+pub mod lint;
+pub mod pack;
+pub mod templatize;
+pub mod transform;
@@ -0,0 +1,51 @@
+use std::path::{Path, PathBuf};
+
+use crate::mongobar::op_file::{self, OpCompression, OpFormat};
+
+/// .op => .opb，压缩格式保持不变，qxg.op.zst => qxg.opb.zst
+pub fn pack(target: &str, out: Option<String>) -> anyhow::Result<(PathBuf, usize)> {
+    convert_to(target, out, OpFormat::Bson)
+}
+
+/// .opb => .op
+pub fn unpack(target: &str, out: Option<String>) -> anyhow::Result<(PathBuf, usize)> {
+    convert_to(target, out, OpFormat::Json)
+}
+
+fn convert_to(
+    target: &str,
+    out: Option<String>,
+    format: OpFormat,
+) -> anyhow::Result<(PathBuf, usize)> {
+    let path = Path::new(target);
+    if !op_file::is_op_file(path) {
+        return Err(anyhow::anyhow!("{} is not an op file", target));
+    }
+    if OpFormat::from_path(path) == format {
+        return Err(anyhow::anyhow!(
+            "{} is already in {} format",
+            target,
+            format.ext()
+        ));
+    }
+
+    let outfile = match out {
+        Some(out) => PathBuf::from(out),
+        None => path.with_file_name(format!(
+            "{}{}{}",
+            op_file::op_name(path),
+            format.ext(),
+            OpCompression::from_path(path).suffix()
+        )),
+    };
+    if OpFormat::from_path(&outfile) != format {
+        return Err(anyhow::anyhow!(
+            "output {} must end with {}",
+            outfile.display(),
+            format.ext()
+        ));
+    }
+
+    let n = op_file::convert(path, &outfile)?;
+    Ok((outfile, n))
+}
@@ -0,0 +1,259 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+
+use crate::mongobar::op_file::{self, OpFormat, OpReader};
+use crate::mongobar::op_gen::GEN_KEY;
+use crate::mongobar::op_header;
+use crate::mongobar::op_row::{self, OpRow};
+
+use super::transform::default_out;
+
+/// cmd 中会被参数化的部分，其他的（排序、投影、limit 等）保持录制时的值
+static PARAM_FIELDS: [&str; 7] = [
+    "filter",
+    "query",
+    "q",
+    "updates",
+    "deletes",
+    "pipeline",
+    "documents",
+];
+
+/// extjson 中表示一个值的对象，当作叶子节点
+static EXTJSON_KEYS: [&str; 10] = [
+    "$oid",
+    "$date",
+    "$numberLong",
+    "$numberInt",
+    "$numberDouble",
+    "$numberDecimal",
+    "$binary",
+    "$uuid",
+    "$timestamp",
+    "$regularExpression",
+];
+
+/// 一个叶子节点上出现过的值和次数，超过 max_values 后不再记录新的值
+#[derive(Default)]
+struct LeafValues {
+    values: Vec<(Value, usize)>,
+    index: HashMap<String, usize>,
+    overflow: bool,
+    int_range: Option<(i64, i64)>,
+    /// 出现过非整数的值
+    not_int: bool,
+}
+
+impl LeafValues {
+    fn push(&mut self, v: &Value, max_values: usize) {
+        match op_row::json_i64(v) {
+            Some(n) => {
+                let (min, max) = self.int_range.unwrap_or((n, n));
+                self.int_range = Some((min.min(n), max.max(n)));
+            }
+            None => self.not_int = true,
+        }
+        let k = v.to_string();
+        match self.index.get(&k) {
+            Some(i) => self.values[*i].1 += 1,
+            None if self.values.len() < max_values => {
+                self.index.insert(k, self.values.len());
+                self.values.push((v.clone(), 1));
+            }
+            None => self.overflow = true,
+        }
+    }
+
+    /// 只有一个值时保持原样，否则替换为 `$gen`
+    fn to_gen(&self) -> Option<Value> {
+        if self.values.len() <= 1 && !self.overflow {
+            return None;
+        }
+        if let (true, false, Some((min, max))) = (self.overflow, self.not_int, self.int_range) {
+            return Some(json!({ GEN_KEY: "int", "min": min, "max": max }));
+        }
+        let mut values = self.values.clone();
+        values.sort_by_key(|v| Reverse(v.1));
+        let (values, weights): (Vec<Value>, Vec<usize>) = values.into_iter().unzip();
+        Some(json!({ GEN_KEY: "choice", "values": values, "weights": weights }))
+    }
+}
+
+/// 同一个查询形状（OpRow::key）的记录
+struct Template {
+    row: OpRow,
+    count: usize,
+    leaves: HashMap<String, LeafValues>,
+}
+
+#[derive(Debug, Default)]
+pub struct TemplatizeReport {
+    pub rows: usize,
+    pub templates: usize,
+    pub written: usize,
+}
+
+/// 按照 OpRow::key 分组，统计每个叶子节点上出现过的值，生成带 `$gen` 占位符的模板文件
+///
+/// `rows` 大于 0 时每个模板按照录制时的占比重复写入，总数约为 rows，否则每个模板写入一次
+pub fn templatize(
+    target: &str,
+    out: Option<String>,
+    max_values: usize,
+    rows: usize,
+) -> anyhow::Result<(PathBuf, TemplatizeReport)> {
+    let path = Path::new(target);
+    let mut report = TemplatizeReport::default();
+    let mut templates: Vec<Template> = vec![];
+    let mut index: HashMap<String, usize> = HashMap::new();
+
+    let mut reader = OpReader::open(path)?;
+    while let Some(row) = reader.read_row()? {
+        report.rows += 1;
+        let key = format!("{}:{}", row.db, row.build_key());
+        let i = *index.entry(key).or_insert_with(|| {
+            templates.push(Template {
+                row: row.clone(),
+                count: 0,
+                leaves: HashMap::new(),
+            });
+            templates.len() - 1
+        });
+        let template = &mut templates[i];
+        template.count += 1;
+        let mut leaves = vec![];
+        for field in PARAM_FIELDS.iter() {
+            if let Some(v) = row.cmd.get(field) {
+                collect_leaves(v, format!("/{}", field), &mut leaves);
+            }
+        }
+        for (pointer, v) in leaves {
+            template
+                .leaves
+                .entry(pointer)
+                .or_default()
+                .push(v, max_values);
+        }
+    }
+    report.templates = templates.len();
+    templates.sort_by_key(|t| Reverse(t.count));
+
+    let out = out
+        .map(PathBuf::from)
+        .unwrap_or_else(|| default_out(target, "tpl"));
+    let format = OpFormat::from_path(&out);
+    {
+        let mut writer = op_file::create(&out)?;
+        for (i, template) in templates.iter().enumerate() {
+            let mut row = template.row.clone();
+            row.id = format!("tpl-{}", i);
+            for (pointer, leaf) in template.leaves.iter() {
+                if let (Some(gen), Some(v)) = (leaf.to_gen(), row.cmd.pointer_mut(pointer)) {
+                    *v = gen;
+                }
+            }
+            let repeat = match rows {
+                0 => 1,
+                _ => (template.count * rows).div_ceil(report.rows),
+            };
+            for _ in 0..repeat {
+                op_file::write_row(&mut writer, format, &row)?;
+                report.written += 1;
+            }
+        }
+        writer.flush()?;
+    }
+
+    let mut header = op_header::read(path).unwrap_or_default();
+    header.source = format!("templatize:{}", header.source);
+    op_header::stamp(&out, header.with_time_range(0, 0))?;
+
+    Ok((out, report))
+}
+
+fn is_leaf(v: &Value) -> bool {
+    match v {
+        Value::Object(map) => {
+            map.len() == 1 && map.keys().all(|k| EXTJSON_KEYS.contains(&k.as_str()))
+        }
+        // `$in: [1, 2, 3]` 这样的数组作为一个整体
+        Value::Array(arr) => arr
+            .iter()
+            .all(|v| !v.is_array() && (!v.is_object() || is_leaf(v))),
+        _ => true,
+    }
+}
+
+fn collect_leaves<'a>(v: &'a Value, pointer: String, leaves: &mut Vec<(String, &'a Value)>) {
+    if is_leaf(v) {
+        leaves.push((pointer, v));
+        return;
+    }
+    match v {
+        Value::Object(map) => {
+            for (k, v) in map.iter() {
+                let k = k.replace('~', "~0").replace('/', "~1");
+                collect_leaves(v, format!("{}/{}", pointer, k), leaves);
+            }
+        }
+        Value::Array(arr) => {
+            for (i, v) in arr.iter().enumerate() {
+                collect_leaves(v, format!("{}/{}", pointer, i), leaves);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mongobar::op_fixture::{row, write_rows, TempDir};
+
+    /// 三条同一模板的查询和一条单独的查询，max_values 为 2
+    fn templatized(dir: &TempDir) -> (PathBuf, TemplatizeReport) {
+        let path = dir.join("a.op");
+        let mut rows: Vec<OpRow> = [(1, "new"), (2, "new"), (3, "done")]
+            .into_iter()
+            .map(|(i, status)| {
+                row(json!({
+                    "id": i.to_string(), "ts": i,
+                    "cmd": { "find": "c", "filter": { "uid": i, "status": status, "type": 1 }, "limit": 10 }
+                }))
+            })
+            .collect();
+        rows.push(row(json!({
+            "id": "4", "ts": 4, "cmd": { "find": "c", "filter": { "name": "a" } }
+        })));
+        write_rows(&path, &rows);
+        templatize(path.to_str().unwrap(), None, 2, 0).unwrap()
+    }
+
+    #[test]
+    fn test_templatize_report() {
+        let dir = TempDir::new("templatize");
+        let (out, report) = templatized(&dir);
+        assert_eq!((report.rows, report.templates, report.written), (4, 2, 2));
+        assert_eq!(op_header::read(&out).unwrap().count, 2);
+    }
+
+    #[test]
+    fn test_templatize_values() {
+        let dir = TempDir::new("templatize");
+        let (out, _) = templatized(&dir);
+        let row = OpReader::open(&out).unwrap().read_row().unwrap().unwrap();
+        let filter = &row.cmd["filter"];
+        // 超过 max_values 的整数按照范围生成
+        assert_eq!(filter["uid"], json!({ "$gen": "int", "min": 1, "max": 3 }));
+        // 没有超过的按照出现次数加权
+        assert_eq!(filter["status"]["values"], json!(["new", "done"]));
+        assert_eq!(filter["status"]["weights"], json!([2, 1]));
+        // 只出现过一个值的保持不变
+        assert_eq!(filter["type"], 1);
+        assert_eq!(row.cmd["limit"], 10);
+    }
+}
@@ -0,0 +1,497 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::commands::{DedupBy, SplitBy};
+use crate::mongobar::op_file::{self, OpFormat, OpReader};
+use crate::mongobar::op_header::{self, OpHeader};
+use crate::mongobar::op_row::OpRow;
+
+/// split 时同时打开的文件数量上限，超过后关闭最久没有写入的文件，再次写入时追加
+static MAX_OPEN_WRITERS: usize = 64;
+
+/// 写入 op 文件，结束时如果有头信息则重新统计后写入
+struct RowWriter {
+    path: PathBuf,
+    format: OpFormat,
+    writer: Box<dyn Write + Send>,
+    count: usize,
+}
+
+impl RowWriter {
+    fn create(path: PathBuf) -> anyhow::Result<Self> {
+        Ok(Self {
+            format: OpFormat::from_path(&path),
+            writer: op_file::create(&path)?,
+            path,
+            count: 0,
+        })
+    }
+
+    /// 追加到已有的文件，压缩文件会追加一个新的 gzip member / zstd frame
+    fn append(path: PathBuf) -> anyhow::Result<Self> {
+        Ok(Self {
+            format: OpFormat::from_path(&path),
+            writer: op_file::append(&path)?,
+            path,
+            count: 0,
+        })
+    }
+
+    fn write(&mut self, row: &OpRow) -> anyhow::Result<()> {
+        self.count += 1;
+        op_file::write_row(&mut self.writer, self.format, row)
+    }
+
+    /// 关闭文件，返回本次写入的条数
+    fn close(mut self) -> anyhow::Result<(PathBuf, usize)> {
+        self.writer.flush()?;
+        drop(self.writer);
+        Ok((self.path, self.count))
+    }
+
+    fn finish(self, header: Option<OpHeader>) -> anyhow::Result<(PathBuf, usize)> {
+        let (path, count) = self.close()?;
+        stamp(&path, header)?;
+        Ok((path, count))
+    }
+}
+
+fn stamp(path: &Path, header: Option<OpHeader>) -> anyhow::Result<()> {
+    if let Some(header) = header {
+        // 时间范围按照新文件重新统计
+        op_header::stamp(path, header.with_time_range(0, 0))?;
+    }
+    Ok(())
+}
+
+/// 默认的输出文件：`a/qxg.op.zst` => `a/qxg.{tag}.op.zst`
+pub fn default_out(target: &str, tag: &str) -> PathBuf {
+    let path = Path::new(target);
+    path.with_file_name(format!(
+        "{}.{}{}",
+        op_file::op_name(path),
+        tag,
+        op_file::op_suffix(path)
+    ))
+}
+
+fn out_path(target: &str, out: Option<String>, tag: &str) -> PathBuf {
+    out.map(PathBuf::from)
+        .unwrap_or_else(|| default_out(target, tag))
+}
+
+/// 按比例或者固定条数（蓄水池）抽样，相同的 seed 结果相同
+pub fn sample(
+    target: &str,
+    out: Option<String>,
+    ratio: Option<f64>,
+    n: Option<usize>,
+    seed: u64,
+) -> anyhow::Result<(PathBuf, usize)> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut reader = OpReader::open(Path::new(target))?;
+    let mut writer = RowWriter::create(out_path(target, out, "sample"))?;
+
+    match (ratio, n) {
+        (Some(ratio), None) => {
+            while let Some(row) = reader.read_row()? {
+                if rng.gen::<f64>() < ratio {
+                    writer.write(&row)?;
+                }
+            }
+        }
+        (None, Some(n)) => {
+            let mut reservoir: Vec<(usize, OpRow)> = Vec::with_capacity(n);
+            let mut i = 0;
+            while let Some(row) = reader.read_row()? {
+                if reservoir.len() < n {
+                    reservoir.push((i, row));
+                } else {
+                    let j = rng.gen_range(0..=i);
+                    if j < n {
+                        reservoir[j] = (i, row);
+                    }
+                }
+                i += 1;
+            }
+            // 保持原来的顺序
+            reservoir.sort_by_key(|(i, _)| *i);
+            for (_, row) in reservoir.iter() {
+                writer.write(row)?;
+            }
+        }
+        _ => return Err(anyhow::anyhow!("one of --ratio or --n is required")),
+    }
+
+    writer.finish(op_header::read(Path::new(target)))
+}
+
+/// 按时间窗口、集合或者操作类型拆分为多个文件，返回生成的文件和条数
+pub fn split(
+    target: &str,
+    outdir: Option<String>,
+    by: SplitBy,
+    window: Option<String>,
+) -> anyhow::Result<Vec<(PathBuf, usize)>> {
+    split_with_limit(target, outdir, by, window, MAX_OPEN_WRITERS)
+}
+
+/// 最多同时打开 max_open 个文件
+fn split_with_limit(
+    target: &str,
+    outdir: Option<String>,
+    by: SplitBy,
+    window: Option<String>,
+    max_open: usize,
+) -> anyhow::Result<Vec<(PathBuf, usize)>> {
+    let path = Path::new(target);
+    let window = match by {
+        SplitBy::Time => {
+            let window = window.as_deref().unwrap_or("1h");
+            match parse_duration(window)? {
+                ms if ms <= 0 => {
+                    return Err(anyhow::anyhow!(
+                        "invalid window {}, must be at least 1ms",
+                        window
+                    ))
+                }
+                ms => ms,
+            }
+        }
+        _ => 0,
+    };
+    let outdir = outdir
+        .map(PathBuf::from)
+        .unwrap_or_else(|| path.parent().unwrap_or(Path::new(".")).to_path_buf());
+    std::fs::create_dir_all(&outdir)?;
+
+    let name = op_file::op_name(path);
+    let suffix = op_file::op_suffix(path);
+    // 每个文件的路径和总条数，打开的文件和最后一次写入的序号
+    let mut parts: HashMap<String, (PathBuf, usize)> = HashMap::new();
+    let mut writers: HashMap<String, (RowWriter, usize)> = HashMap::new();
+    let mut reader = OpReader::open(path)?;
+    let mut i = 0;
+    while let Some(row) = reader.read_row()? {
+        i += 1;
+        let part = match by {
+            SplitBy::Time => format!("{}", row.ts - row.ts.rem_euclid(window)),
+            SplitBy::Coll => row.coll.clone(),
+            SplitBy::Op => format!("{:?}", row.op),
+        };
+        if !writers.contains_key(&part) {
+            if writers.len() >= max_open.max(1) {
+                let oldest = writers
+                    .iter()
+                    .min_by_key(|(_, (_, last))| *last)
+                    .map(|(k, _)| k.clone())
+                    .unwrap();
+                writers.remove(&oldest).unwrap().0.close()?;
+            }
+            let writer = match parts.get(&part) {
+                Some((out, _)) => RowWriter::append(out.clone())?,
+                None => {
+                    let out = outdir.join(format!("{}.{}{}", name, part, suffix));
+                    parts.insert(part.clone(), (out.clone(), 0));
+                    RowWriter::create(out)?
+                }
+            };
+            writers.insert(part.clone(), (writer, i));
+        }
+        let (writer, last) = writers.get_mut(&part).unwrap();
+        writer.write(&row)?;
+        *last = i;
+        parts.get_mut(&part).unwrap().1 += 1;
+    }
+    for (writer, _) in writers.into_values() {
+        writer.close()?;
+    }
+
+    let header = op_header::read(path);
+    let mut outs = parts.into_values().collect::<Vec<_>>();
+    for (out, _) in outs.iter() {
+        stamp(out, header.clone())?;
+    }
+    outs.sort();
+    Ok(outs)
+}
+
+/// 按照 ts 合并多个文件，每个文件内部需要是按 ts 排序的
+pub fn merge(targets: &[String], out: &str) -> anyhow::Result<(PathBuf, usize)> {
+    let mut readers = targets
+        .iter()
+        .map(|t| OpReader::open(Path::new(t)))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let mut heads: Vec<Option<OpRow>> = vec![None; readers.len()];
+    let mut heap = BinaryHeap::new();
+    for (i, reader) in readers.iter_mut().enumerate() {
+        if let Some(row) = reader.read_row()? {
+            heap.push(Reverse((row.ts, i)));
+            heads[i] = Some(row);
+        }
+    }
+
+    let mut writer = RowWriter::create(PathBuf::from(out))?;
+    while let Some(Reverse((_, i))) = heap.pop() {
+        let row = heads[i].take().unwrap();
+        writer.write(&row)?;
+        if let Some(next) = readers[i].read_row()? {
+            heap.push(Reverse((next.ts, i)));
+            heads[i] = Some(next);
+        }
+    }
+
+    let header = targets
+        .iter()
+        .find_map(|t| op_header::read(Path::new(t)))
+        .map(|mut h| {
+            h.source = format!("merge:{}", h.source);
+            h
+        });
+    writer.finish(header)
+}
+
+/// 平移 ts，`to` 为第一条记录的新时间，或者直接指定偏移的毫秒数
+pub fn shift_time(
+    target: &str,
+    out: Option<String>,
+    to: Option<i64>,
+    offset: Option<i64>,
+) -> anyhow::Result<(PathBuf, usize)> {
+    let mut reader = OpReader::open(Path::new(target))?;
+    let mut writer = RowWriter::create(out_path(target, out, "shift"))?;
+    let mut delta: Option<i64> = offset;
+    while let Some(mut row) = reader.read_row()? {
+        let d = *delta.get_or_insert_with(|| to.unwrap_or(row.ts) - row.ts);
+        row.ts += d;
+        writer.write(&row)?;
+    }
+    writer.finish(op_header::read(Path::new(target)))
+}
+
+/// 按照 OpRow::id 或者 key 去重，保留第一条
+pub fn dedup(target: &str, out: Option<String>, by: DedupBy) -> anyhow::Result<(PathBuf, usize)> {
+    let mut reader = OpReader::open(Path::new(target))?;
+    let mut writer = RowWriter::create(out_path(target, out, "dedup"))?;
+    let mut seen: HashSet<String> = HashSet::new();
+    while let Some(row) = reader.read_row()? {
+        let k = match by {
+            DedupBy::Id => row.id.clone(),
+            DedupBy::Key => row.build_key(),
+        };
+        if seen.insert(k) {
+            writer.write(&row)?;
+        }
+    }
+    writer.finish(op_header::read(Path::new(target)))
+}
+
+/// 取开头（tail 为结尾）一段时间内的记录
+pub fn head_tail(
+    target: &str,
+    out: Option<String>,
+    duration: &str,
+    tail: bool,
+) -> anyhow::Result<(PathBuf, usize)> {
+    let path = Path::new(target);
+    let duration = parse_duration(duration)?;
+    let header = op_header::read(path);
+
+    let range = if tail {
+        let end = match header.as_ref().filter(|h| h.end_ts > 0) {
+            Some(h) => h.end_ts,
+            None => {
+                let mut end = i64::MIN;
+                let mut reader = OpReader::open(path)?;
+                while let Some(row) = reader.read_row()? {
+                    end = end.max(row.ts);
+                }
+                end
+            }
+        };
+        (end.saturating_sub(duration), i64::MAX)
+    } else {
+        (i64::MIN, i64::MAX)
+    };
+
+    let mut reader = OpReader::open(path)?;
+    let mut writer = RowWriter::create(out_path(target, out, if tail { "tail" } else { "head" }))?;
+    let mut start: Option<i64> = None;
+    while let Some(row) = reader.read_row()? {
+        if tail {
+            if row.ts >= range.0 {
+                writer.write(&row)?;
+            }
+            continue;
+        }
+        let start = *start.get_or_insert(row.ts);
+        if row.ts > start + duration {
+            break;
+        }
+        writer.write(&row)?;
+    }
+    writer.finish(header)
+}
+
+/// `500ms`/`30s`/`10m`/`2h`/`1d` => 毫秒，不带单位为秒
+pub fn parse_duration(s: &str) -> anyhow::Result<i64> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let num: f64 = num
+        .parse()
+        .map_err(|_| anyhow::anyhow!("invalid duration {}", s))?;
+    let ms = match unit {
+        "ms" => 1.0,
+        "" | "s" => 1000.0,
+        "m" => 60_000.0,
+        "h" => 3_600_000.0,
+        "d" => 86_400_000.0,
+        _ => return Err(anyhow::anyhow!("invalid duration unit {}", unit)),
+    };
+    Ok((num * ms) as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mongobar::op_fixture::{self, TempDir};
+
+    fn write_rows(path: &Path, rows: &[(&str, &str, i64)]) {
+        let rows: Vec<OpRow> = rows
+            .iter()
+            .map(|(id, coll, ts)| {
+                op_fixture::row(serde_json::json!({
+                    "id": id, "coll": coll, "ts": ts, "cmd": { "find": coll, "filter": { "a": 1 } }
+                }))
+            })
+            .collect();
+        op_fixture::write_rows(path, &rows);
+    }
+
+    fn read_ids(path: &Path) -> Vec<String> {
+        let mut reader = OpReader::open(path).unwrap();
+        let mut ids = vec![];
+        while let Some(row) = reader.read_row().unwrap() {
+            ids.push(row.id);
+        }
+        ids
+    }
+
+    /// 两个集合，id 2 重复，最后一条和前面间隔一分钟
+    fn write_a(dir: &TempDir) -> String {
+        let a = dir.join("a.op");
+        write_rows(&a, &[("1", "x", 1000), ("2", "y", 3000), ("2", "y", 61000)]);
+        a.to_str().unwrap().to_string()
+    }
+
+    fn out(dir: &TempDir, name: &str) -> Option<String> {
+        Some(dir.join(name).to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn test_merge() {
+        let dir = TempDir::new("transform");
+        let a = write_a(&dir);
+        let b = dir.join("b.op");
+        write_rows(&b, &[("3", "x", 2000), ("4", "x", 4000)]);
+
+        let merged = dir.join("m.op");
+        merge(
+            &[a, b.to_str().unwrap().to_string()],
+            merged.to_str().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(read_ids(&merged), vec!["1", "3", "2", "4", "2"]);
+    }
+
+    #[test]
+    fn test_dedup() {
+        let dir = TempDir::new("transform");
+        let (out, n) = dedup(&write_a(&dir), None, DedupBy::Id).unwrap();
+        assert_eq!(
+            (read_ids(&out), n),
+            (vec!["1".to_string(), "2".to_string()], 2)
+        );
+    }
+
+    #[test]
+    fn test_head_tail() {
+        let dir = TempDir::new("transform");
+        let a = write_a(&dir);
+        let (out, _) = head_tail(&a, None, "2s", false).unwrap();
+        assert_eq!(read_ids(&out), vec!["1", "2"]);
+        let (out, _) = head_tail(&a, None, "59s", true).unwrap();
+        assert_eq!(read_ids(&out), vec!["2", "2"]);
+    }
+
+    #[test]
+    fn test_shift_time() {
+        let dir = TempDir::new("transform");
+        let (out, _) = shift_time(&write_a(&dir), None, Some(0), None).unwrap();
+        let mut reader = OpReader::open(&out).unwrap();
+        assert_eq!(reader.read_row().unwrap().unwrap().ts, 0);
+        assert_eq!(reader.read_row().unwrap().unwrap().ts, 2000);
+    }
+
+    #[test]
+    fn test_split_by_coll() {
+        let dir = TempDir::new("transform");
+        let parts = split(&write_a(&dir), out(&dir, "split"), SplitBy::Coll, None).unwrap();
+        assert_eq!(
+            parts.iter().map(|(_, n)| *n).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+    }
+
+    #[test]
+    fn test_split_over_open_limit() {
+        let dir = TempDir::new("transform");
+        // 超过打开文件的上限时关闭后追加写入
+        let c = dir.join("c.op.gz");
+        write_rows(&c, &[("1", "x", 1000), ("2", "y", 2000), ("3", "x", 3000)]);
+        let parts = split_with_limit(
+            c.to_str().unwrap(),
+            out(&dir, "split-c"),
+            SplitBy::Coll,
+            None,
+            1,
+        )
+        .unwrap();
+        assert_eq!(parts[0].1, 2);
+        assert_eq!(read_ids(&parts[0].0), vec!["1", "3"]);
+        assert_eq!(read_ids(&parts[1].0), vec!["2"]);
+    }
+
+    #[test]
+    fn test_sample_with_seed() {
+        let dir = TempDir::new("transform");
+        let a = write_a(&dir);
+        let (s1, _) = sample(&a, out(&dir, "s1.op"), None, Some(2), 7).unwrap();
+        let (s2, _) = sample(&a, out(&dir, "s2.op"), None, Some(2), 7).unwrap();
+        assert_eq!(read_ids(&s1), read_ids(&s2));
+        assert_eq!(read_ids(&s1).len(), 2);
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("10m").unwrap(), 600_000);
+        assert_eq!(parse_duration("500ms").unwrap(), 500);
+    }
+
+    #[test]
+    fn test_split_rejects_empty_window() {
+        let dir = TempDir::new("transform");
+        let a = write_a(&dir);
+        for window in ["0s", "0.0001s", "0ms"] {
+            assert!(split(&a, None, SplitBy::Time, Some(window.to_string())).is_err());
+        }
+    }
+}
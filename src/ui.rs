@@ -30,14 +30,20 @@ use crate::{
     commands::UI,
     exec_tokio, ind_keys,
     indicator::{self, Metric},
-    mongobar::{op_logs, Mongobar},
+    mongobar::{op_filter, op_logs, Mongobar},
 };
 
 use crate::mongobar::op_row;
 
+static OPLOG_PAGE_SIZE: usize = 100;
+
 struct App {
     oplog_scroll: (u16, u16),
     oplogs: Vec<op_row::OpRow>,
+    oplog_page: usize,
+    oplog_total: usize,
+    /// 翻页时复用，进入 OpLog 页面时打开；有过滤条件时为满足条件的行号，按照过滤后的结果分页
+    oplog_reader: Option<(op_logs::OpLogs, Option<Vec<usize>>)>,
 
     router: Router,
 
@@ -67,15 +73,21 @@ struct App {
     popup_title: String,
     popup_tip: String,
 
+    /// `--i-know-this-is-prod`
+    allow_prod: bool,
+
     v: f64,
 }
 
 impl App {
-    fn new(ui: UI) -> Self {
+    fn new(ui: UI, allow_prod: bool) -> Self {
         let indic = indicator::Indicator::new().init(ind_keys(), ui.target.clone());
         Self {
             oplog_scroll: (0, 0),
             oplogs: vec![],
+            oplog_page: 0,
+            oplog_total: 0,
+            oplog_reader: None,
 
             router: Router::new(vec![
                 Route::new(RouteType::Push, "Stress", "Stress"),
@@ -109,6 +121,8 @@ impl App {
             popup_title: "Popup Input".to_string(),
             popup_tip: "Press Enter to confirm.".to_string(),
 
+            allow_prod,
+
             v: 0.0,
         }
     }
@@ -124,6 +138,47 @@ impl App {
         self.start_at.set(chrono::Local::now().timestamp() as usize);
     }
 
+    /// 打开 oplogs 并计算满足过滤条件的行，过滤条件错误时显示在日志中
+    fn open_oplogs(&mut self) {
+        let r = Mongobar::new(&self.ui.target).init();
+        let oplogs = op_logs::OpLogs::new(
+            r.op_file_oplogs.clone(),
+            op_logs::OpReadMode::FullLine(None),
+            Vec::new(),
+        );
+        let matched = match &self.ui.filter {
+            Some(filter) => match oplogs.matched_rows(filter) {
+                Ok(rows) => Some(rows),
+                Err(e) => {
+                    self.indicator
+                        .take("logs")
+                        .unwrap()
+                        .push(format!("OpLog filter error: {}", e));
+                    Some(vec![])
+                }
+            },
+            None => None,
+        };
+        self.oplog_total = matched.as_ref().map_or(oplogs.len(), |rows| rows.len());
+        self.oplog_reader = Some((oplogs, matched));
+        self.oplog_page = 0;
+    }
+
+    /// 通过 .idx 索引直接读取当前页，不需要从头扫描文件
+    fn load_oplog_page(&mut self) {
+        let start = self.oplog_page * OPLOG_PAGE_SIZE;
+        if let Some((oplogs, matched)) = &self.oplog_reader {
+            self.oplogs = match matched {
+                Some(rows) => {
+                    let end = (start + OPLOG_PAGE_SIZE).min(rows.len());
+                    oplogs.rows_at(rows.get(start..end).unwrap_or_default())
+                }
+                None => oplogs.limit(start, OPLOG_PAGE_SIZE),
+            };
+        }
+        self.oplog_scroll.0 = 0;
+    }
+
     fn reset(&mut self) {
         self.query_chart_data.clear();
         self.query_count_max = f64::MIN;
@@ -214,7 +269,12 @@ impl App {
     }
 }
 
-pub fn boot(ui: UI) -> Result<(), Box<dyn Error>> {
+pub fn boot(ui: UI, allow_prod: bool) -> Result<(), Box<dyn Error>> {
+    // 进入界面之前检查过滤条件
+    if let Some(filter) = &ui.filter {
+        op_filter::OpFilter::parse(filter)?;
+    }
+
     // setup terminal
     enable_raw_mode()?;
     let mut stdout = io::stdout();
@@ -224,7 +284,7 @@ pub fn boot(ui: UI) -> Result<(), Box<dyn Error>> {
 
     // create app and run it
     let tick_rate = Duration::from_millis(100);
-    let app = App::new(ui);
+    let app = App::new(ui, allow_prod);
 
     let res = run_app(&mut terminal, app, tick_rate);
 
@@ -291,21 +351,24 @@ fn run_app<B: Backend>(
                                     Route::new(RouteType::Push, "ScrollDown", "ScrollDown"),
                                     Route::new(RouteType::Push, "ScrollLeft", "ScrollLeft"),
                                     Route::new(RouteType::Push, "ScrollRight", "ScrollRight"),
+                                    Route::new(RouteType::Push, "PrevPage", "PrevPage"),
+                                    Route::new(RouteType::Push, "NextPage", "NextPage"),
                                     Route::new(RouteType::Pop, "Back", "Back"),
                                 ],
                                 0,
                             );
-                            let target = app.ui.target.clone();
-
-                            let r = Mongobar::new(&target).init();
-
-                            app.oplogs = op_logs::OpLogs::new(
-                                r.op_file_oplogs.clone(),
-                                op_logs::OpReadMode::FullLine(app.ui.filter.clone()),
-                                Vec::new(),
-                            )
-                            .limit(0, 100)
-                            .to_vec();
+                            app.open_oplogs();
+                            app.load_oplog_page();
+                        }
+                        "/Stress/OpLog/PrevPage" if app.oplog_page > 0 => {
+                            app.oplog_page -= 1;
+                            app.load_oplog_page();
+                        }
+                        "/Stress/OpLog/NextPage"
+                            if (app.oplog_page + 1) * OPLOG_PAGE_SIZE < app.oplog_total =>
+                        {
+                            app.oplog_page += 1;
+                            app.load_oplog_page();
                         }
                         "/Stress/OpLog/ScrollUP" => {
                             if app.oplog_scroll.0 > 0 {
@@ -357,6 +420,7 @@ fn run_app<B: Backend>(
                             let inner_indicator = app.indicator.clone();
                             let signal = app.signal.clone();
                             let ui = app.ui.clone();
+                            let allow_prod = app.allow_prod;
 
                             inner_indicator.reset();
 
@@ -370,6 +434,7 @@ fn run_app<B: Backend>(
                                         .set_signal(signal)
                                         .set_indicator(indicator)
                                         .set_ignore_field(ui.ignore_field.clone())
+                                        .set_allow_prod(allow_prod)
                                         .merge_config_loop_count(ui.loop_count.clone())
                                         .merge_config_thread_count(ui.thread_count.clone())
                                         .merge_config_rebuild(ui.rebuild.clone())
@@ -478,6 +543,7 @@ fn run_app<B: Backend>(
                             let inner_indicator = app.indicator.clone();
                             let signal = app.signal.clone();
                             let ui = app.ui.clone();
+                            let allow_prod = app.allow_prod;
 
                             inner_indicator.reset();
 
@@ -489,6 +555,7 @@ fn run_app<B: Backend>(
                                         .set_signal(signal)
                                         .set_indicator(indicator)
                                         .set_ignore_field(ui.ignore_field.clone())
+                                        .set_allow_prod(allow_prod)
                                         .merge_config_loop_count(ui.loop_count.clone())
                                         .merge_config_thread_count(ui.thread_count.clone())
                                         .merge_config_rebuild(ui.rebuild.clone())
@@ -594,6 +661,7 @@ fn run_app<B: Backend>(
                             let inner_indicator = app.indicator.clone();
                             let signal = app.signal.clone();
                             let ui = app.ui.clone();
+                            let allow_prod = app.allow_prod;
 
                             inner_indicator.reset();
 
@@ -605,6 +673,7 @@ fn run_app<B: Backend>(
                                         .set_signal(signal)
                                         .set_indicator(indicator)
                                         .set_ignore_field(ui.ignore_field.clone())
+                                        .set_allow_prod(allow_prod)
                                         .merge_config_loop_count(ui.loop_count.clone())
                                         .merge_config_thread_count(ui.thread_count.clone())
                                         .merge_config_rebuild(ui.rebuild.clone())
@@ -642,6 +711,7 @@ fn run_app<B: Backend>(
                             let inner_indicator = app.indicator.clone();
                             let signal = app.signal.clone();
                             let ui = app.ui.clone();
+                            let allow_prod = app.allow_prod;
 
                             inner_indicator.reset();
 
@@ -653,6 +723,7 @@ fn run_app<B: Backend>(
                                         .set_signal(signal)
                                         .set_indicator(indicator)
                                         .set_ignore_field(ui.ignore_field.clone())
+                                        .set_allow_prod(allow_prod)
                                         .merge_config_loop_count(ui.loop_count.clone())
                                         .merge_config_thread_count(ui.thread_count.clone())
                                         .merge_config_rebuild(ui.rebuild.clone())
@@ -752,9 +823,13 @@ fn render_oplog_view(frame: &mut Frame, area: Rect, app: &App) {
 
 fn render_oplogs(frame: &mut Frame, area: Rect, app: &App) {
     let logs = &app.oplogs;
-    let block = Block::new()
-        .borders(Borders::ALL)
-        .title(format!("OpLogs: {}", logs.len()));
+    let block = Block::new().borders(Borders::ALL).title(format!(
+        "OpLogs: {} Page: {}/{} Total: {}",
+        logs.len(),
+        app.oplog_page + 1,
+        app.oplog_total.div_ceil(OPLOG_PAGE_SIZE).max(1),
+        app.oplog_total
+    ));
     let paragraph = Paragraph::new(
         logs.iter()
             .map(|v| {
@@ -920,6 +995,7 @@ fn render_log(f: &mut Frame, area: Rect, app: &App) {
     let boot_worker = app.indicator.take("boot_worker").unwrap().get();
     let dyn_threads = app.indicator.take("dyn_threads").unwrap().get();
     let dyn_cc_limit = app.indicator.take("dyn_cc_limit").unwrap().get();
+    let skip_count = app.indicator.take("skip_count").unwrap().get();
     let start_at = app.start_at.get();
     let current_at = app.current_at.get();
     // let query_qps = app.indicator.take("query_qps").unwrap().get();
@@ -936,9 +1012,10 @@ fn render_log(f: &mut Frame, area: Rect, app: &App) {
             current_at - start_at
         )),
         Line::from(format!(
-            "> Query : avg_qps({:.2}/s) qps({}/s)",
+            "> Query : avg_qps({:.2}/s) qps({}/s) skip({})",
             (query_count as f64) / (app.current_at.get() - app.start_at.get()) as f64,
             app.diff_query_count,
+            skip_count,
         )),
         Line::from(format!(
             "> Cost  : avg_dur({:.2}ms) dur({:.2}ms)",